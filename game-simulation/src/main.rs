@@ -1,11 +1,45 @@
 use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
 use rand::Rng;
+use serde::Deserialize;
+use sha2::Sha256;
 use std::collections::HashMap;
+use std::io::Read;
 use std::process;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
 use tokio::select;
 use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+// mirrors LaunchDocument in src/launch.rs
+#[derive(Deserialize)]
+struct LaunchDocument {
+    match_id: u32,
+    port: u32,
+    players: Vec<LaunchPlayer>,
+    result_url: String,
+    result_secret: String,
+    #[serde(default)]
+    result_file: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LaunchPlayer {
+    username: String,
+    token: String,
+}
+
+struct Launch {
+    match_id: Option<u32>,
+    mode: &'static str,
+    port: String,
+    players: Vec<(String, String)>,
+    /// Where to post the signed result and the key to sign it with
+    result: Option<(String, String)>,
+    /// Where to write the result
+    result_file: Option<String>,
+}
 
 fn parse_args() -> HashMap<String, String> {
     let args: Vec<String> = std::env::args().collect();
@@ -24,61 +58,160 @@ fn parse_args() -> HashMap<String, String> {
     map
 }
 
-#[tokio::main]
-async fn main() {
-    println!("Running");
-    let exit_codes = [1000, 1001, 1002];
-    let args = parse_args();
+fn parse_launch_document(contents: &str, mode: &'static str) -> Launch {
+    let doc: LaunchDocument = serde_json::from_str(contents).unwrap_or_else(|e| {
+        eprintln!("Invalid launch document: {}", e);
+        process::exit(1);
+    });
+    Launch {
+        match_id: Some(doc.match_id),
+        mode,
+        port: doc.port.to_string(),
+        players: doc.players.into_iter().map(|p| (p.username, p.token)).collect(),
+        result: Some((doc.result_url, doc.result_secret)),
+        result_file: doc.result_file,
+    }
+}
 
+fn launch_from_args(args: &HashMap<String, String>) -> Launch {
+    if args.contains_key("-launchstdin") {
+        let mut contents = String::new();
+        std::io::stdin().read_to_string(&mut contents).unwrap_or_else(|_| {
+            eprintln!("Failed to read launch document from stdin");
+            process::exit(1);
+        });
+        return parse_launch_document(&contents, "stdin");
+    }
+    if let Some(path) = args.get("-launchfile") {
+        let contents = std::fs::read_to_string(path).unwrap_or_else(|_| {
+            eprintln!("Failed to read launch file {}", path);
+            process::exit(1);
+        });
+        // the secrets are in memory now, nobody else needs the file
+        let _ = std::fs::remove_file(path);
+        return parse_launch_document(&contents, "file");
+    }
     let port = args.get("-port").unwrap_or_else(|| {
         eprintln!("Missing -port");
         process::exit(1);
     });
 
-    let username1 = args.get("-username1").unwrap_or_else(|| {
+    // -username1, -username2 and so on, as many as there are players, with their tokens in MATCH_PLAYER<N>_TOKEN
+    let mut players = Vec::new();
+    while let Some(username) = args.get(&format!("-username{}", players.len() + 1)) {
+        let token = std::env::var(format!("MATCH_PLAYER{}_TOKEN", players.len() + 1)).unwrap_or_else(|_| {
+            eprintln!("Missing MATCH_PLAYER{}_TOKEN", players.len() + 1);
+            process::exit(1);
+        });
+        players.push((username.clone(), token));
+    }
+    if players.is_empty() {
         eprintln!("Missing -username1");
         process::exit(1);
-    });
+    }
 
-    let username2 = args.get("-username2").unwrap_or_else(|| {
-        eprintln!("Missing -username2");
-        process::exit(1);
-    });
+    Launch {
+        match_id: args.get("-matchid").and_then(|id| id.parse().ok()),
+        mode: "argv",
+        port: port.clone(),
+        players,
+        result: args.get("-resulturl").cloned().zip(std::env::var("MATCH_RESULT_SECRET").ok()),
+        result_file: args.get("-resultfile").cloned(),
+    }
+}
 
-    let token1 = args.get("-player1token").unwrap_or_else(|| {
-        eprintln!("Missing -player1token");
-        process::exit(1);
-    });
+/// Result report matching `code`, see `GameResultReport` in src/outcome.rs
+fn result_report(launch: &Launch, match_id: u32, code: i32, duration_secs: u64) -> String {
+    let winner = match code {
+        1001 => launch.players.first().map(|p| &p.0),
+        1002 => launch.players.get(1).map(|p| &p.0),
+        _ => None,
+    };
+    serde_json::json!({
+        "match_id": match_id,
+        "winner": winner,
+        "scores": launch.players.iter().map(|(u, _)| (u.clone(), if Some(u) == winner { 1 } else { 0 })).collect::<HashMap<String, i64>>(),
+        "duration_secs": duration_secs,
+        "reason": match code {
+            1001 | 1002 => "completed",
+            1003 => "draw",
+            _ => "error",
+        },
+    })
+    .to_string()
+}
 
-    let token2 = args.get("-player2token").unwrap_or_else(|| {
-        eprintln!("Missing -player2token");
-        process::exit(1);
-    });
+/// Hex HMAC-SHA256 of the report keyed by the match's result secret, every channel must carry it
+fn sign(secret: &str, report: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(report.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Posts the report to the matchmaking server, signed with the match's result secret
+async fn post_result(launch: &Launch, report: &str) {
+    let Some((url, secret)) = &launch.result else {
+        return;
+    };
+    let signature = sign(secret, report);
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Signature", signature)
+        .body(report.to_string())
+        .send()
+        .await;
+    match response {
+        Ok(response) => println!("Reported result: {}", response.status()),
+        Err(e) => eprintln!("Failed to report result: {}", e),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    println!("Running");
+    let exit_codes = [1000, 1001, 1002, 1003];
+    let args = parse_args();
+    let launch = launch_from_args(&args);
 
-    println!("Port: {}", port);
-    println!("Username1: {}, Token1: {}", username1, token1);
-    println!("Username2: {}, Token2: {}", username2, token2);
+    println!("Port: {} ({} launch)", launch.port, launch.mode);
+    for (i, (username, _)) in launch.players.iter().enumerate() {
+        println!("Player {}: {}", i + 1, username);
+    }
+    // sent to every client on connect so tests can check which launch path was used
+    let hello = serde_json::json!({ "match_id": launch.match_id, "launch": launch.mode }).to_string();
 
-    let addr = format!("127.0.0.1:{}", port);
+    let addr = format!("127.0.0.1:{}", launch.port);
     let listener = TcpListener::bind(&addr).await.unwrap_or_else(|_| {
         eprintln!("Failed to bind to {}", addr);
         process::exit(1);
     });
 
     println!("WebSocket server listening on ws://{}", addr);
+    // for games using the stdout ready probe
+    println!("READY");
 
+    let started = Instant::now();
     let timeout = tokio::time::sleep(Duration::from_secs(60));
 
     let server = async {
         loop {
             let (stream, _) = listener.accept().await.expect("Failed to accept connection");
+            let hello = hello.clone();
 
             tokio::spawn(async move {
-                let ws_stream = accept_async(stream).await.expect("WebSocket handshake failed");
+                // the matchmaker's ready probe connects without a handshake
+                let Ok(ws_stream) = accept_async(stream).await else {
+                    return;
+                };
 
                 println!("New WebSocket connection");
 
                 let (mut write, mut read) = ws_stream.split();
+                if let Err(e) = write.send(Message::text(hello)).await {
+                    eprintln!("Send error: {}", e);
+                    return;
+                }
                 while let Some(Ok(msg)) = read.next().await {
                     if msg.is_text() || msg.is_binary() {
                         if let Err(e) = write.send(msg).await {
@@ -98,6 +231,25 @@ async fn main() {
         _ = timeout => {
             let code = exit_codes[rand::rng().random_range(0..exit_codes.len())];
             println!("Exiting after 60 seconds with code: {}", code);
+            if let Some(match_id) = launch.match_id {
+                let report = result_report(&launch, match_id, code, started.elapsed().as_secs());
+                // SIMULATION_RESULT picks one channel to test the matchmaker's fallbacks, every channel is used by default
+                let channel = std::env::var("SIMULATION_RESULT").unwrap_or_default();
+                let uses = |name: &str| channel.is_empty() || channel == name;
+                if uses("callback") {
+                    post_result(&launch, &report).await;
+                }
+                // the result file and the last line of stdout hold the signature, a space and the report
+                let signed = format!("{} {}", sign(launch.result.as_ref().map_or("", |(_, secret)| secret), &report), report);
+                if let Some(path) = launch.result_file.as_ref().filter(|_| uses("file")) {
+                    if let Err(e) = std::fs::write(path, &signed) {
+                        eprintln!("Failed to write result file {}: {}", path, e);
+                    }
+                }
+                if uses("stdout") {
+                    println!("{}", signed);
+                }
+            }
             process::exit(code);
         }
     }
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::{env, fs};
+
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::auth::AuthProvider;
+use crate::error::UnauthorizedError;
+use crate::user::User;
+use crate::utils::{now_secs, random_token};
+
+const DEFAULT_SESSION_TTL_SECS: u64 = 60 * 60 * 24 * 7;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub id: u64,
+    pub username: String,
+    pub display_name: String,
+    pub password_hash: String,
+    pub created_at: u64,
+}
+
+struct Session {
+    user_id: u64,
+    expires_at: u64,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    UsernameTaken,
+    InvalidCredentials,
+    Hashing,
+}
+
+/// Registered accounts and their login sessions. Accounts are persisted to `path` when set, sessions only live in memory
+pub struct UserStore {
+    accounts: HashMap<u64, Account>,
+    ids_by_username: HashMap<String, u64>,
+    sessions: HashMap<String, Session>,
+    next_id: u64,
+    session_ttl: u64,
+    path: Option<String>,
+}
+pub type SharedUserStore = Arc<RwLock<UserStore>>;
+
+impl UserStore {
+    pub fn new(path: Option<String>, session_ttl: u64) -> Self {
+        let accounts: Vec<Account> = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|contents| serde_json::from_str(&contents).expect("Invalid user store file"))
+            .unwrap_or_default();
+        let next_id = accounts.iter().map(|a| a.id).max().unwrap_or(0) + 1;
+        Self {
+            ids_by_username: accounts.iter().map(|a| (a.username.to_lowercase(), a.id)).collect(),
+            accounts: accounts.into_iter().map(|a| (a.id, a)).collect(),
+            sessions: HashMap::new(),
+            next_id,
+            session_ttl,
+            path,
+        }
+    }
+    pub fn from_env() -> Self {
+        let path = env::var("USER_STORE_PATH").ok().filter(|p| !p.is_empty());
+        let session_ttl = env::var("SESSION_TTL_SECS")
+            .map(|v| v.parse().expect("Invalid SESSION_TTL_SECS"))
+            .unwrap_or(DEFAULT_SESSION_TTL_SECS);
+        Self::new(path, session_ttl)
+    }
+    pub fn username_taken(&self, username: &str) -> bool {
+        self.ids_by_username.contains_key(&username.to_lowercase())
+    }
+    /// Adds an account with a password hashed by `hash_password`
+    pub fn register(&mut self, username: &str, display_name: &str, password_hash: String) -> Result<Account, AccountError> {
+        // checked again, the name may have been taken while the password was hashed
+        if self.username_taken(username) {
+            return Err(AccountError::UsernameTaken);
+        }
+        let account = Account {
+            id: self.next_id,
+            username: username.to_string(),
+            display_name: display_name.to_string(),
+            password_hash,
+            created_at: now_secs(),
+        };
+        self.next_id += 1;
+        self.ids_by_username.insert(username.to_lowercase(), account.id);
+        self.accounts.insert(account.id, account.clone());
+        self.save();
+        Ok(account)
+    }
+    /// Id and password hash of the account with this username, for `verify_password`
+    pub fn credentials(&self, username: &str) -> Option<(u64, String)> {
+        let account = self.ids_by_username.get(&username.to_lowercase()).and_then(|id| self.accounts.get(id))?;
+        Some((account.id, account.password_hash.clone()))
+    }
+    /// Opens a new session for an account whose password was checked, returning its token and expiry
+    pub fn open_session(&mut self, user_id: u64) -> Result<(String, u64, Account), AccountError> {
+        let account = self.accounts.get(&user_id).ok_or(AccountError::InvalidCredentials)?.clone();
+        let token = random_token();
+        let expires_at = now_secs() + self.session_ttl;
+        self.sessions.retain(|_, s| s.expires_at > now_secs());
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                user_id: account.id,
+                expires_at,
+            },
+        );
+        Ok((token, expires_at, account))
+    }
+    pub fn logout(&mut self, token: &str) {
+        self.sessions.remove(token);
+    }
+    pub fn get(&self, id: u64) -> Option<&Account> {
+        self.accounts.get(&id)
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            let accounts: Vec<&Account> = self.accounts.values().collect();
+            match serde_json::to_string_pretty(&accounts) {
+                Ok(json) => {
+                    if let Err(e) = fs::write(path, json) {
+                        println!("Failed to save user store to {}: {}", path, e);
+                    }
+                }
+                Err(e) => println!("Failed to serialize user store: {}", e),
+            }
+        }
+    }
+}
+
+/// Hashes a password with argon2 on the blocking pool, it takes long enough to stall the runtime
+pub async fn hash_password(password: String) -> Result<String, AccountError> {
+    task::spawn_blocking(move || hash_password_blocking(&password))
+        .await
+        .map_err(|_| AccountError::Hashing)?
+}
+
+/// Checks a password against its argon2 hash on the blocking pool
+pub async fn verify_password(password: String, hash: String) -> bool {
+    task::spawn_blocking(move || verify_password_blocking(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
+
+fn hash_password_blocking(password: &str) -> Result<String, AccountError> {
+    let mut salt = [0u8; 16];
+    getrandom::fill(&mut salt).map_err(|_| AccountError::Hashing)?;
+    let salt = SaltString::encode_b64(&salt).map_err(|_| AccountError::Hashing)?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|_| AccountError::Hashing)
+}
+
+fn verify_password_blocking(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Resolves session tokens issued by `/login`
+pub struct SessionAuthProvider {
+    store: SharedUserStore,
+}
+
+impl SessionAuthProvider {
+    pub fn new(store: SharedUserStore) -> Self {
+        Self { store }
+    }
+}
+
+impl AuthProvider for SessionAuthProvider {
+    fn authenticate(&self, token: &str) -> Result<User, UnauthorizedError> {
+        let store = self.store.read().unwrap();
+        let session = store.sessions.get(token).ok_or_else(|| UnauthorizedError::new("unknown session"))?;
+        if session.expires_at <= now_secs() {
+            return Err(UnauthorizedError::new("session expired"));
+        }
+        let account = store
+            .get(session.user_id)
+            .ok_or_else(|| UnauthorizedError::new("account no longer exists"))?;
+        Ok(User {
+            id: account.id,
+            username: account.username.clone(),
+            display_name: account.display_name.clone(),
+            auth_token: token.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(session_ttl: u64) -> SharedUserStore {
+        Arc::new(RwLock::new(UserStore::new(None, session_ttl)))
+    }
+
+    fn reason(result: Result<User, UnauthorizedError>) -> String {
+        result.err().expect("session should be rejected").reason
+    }
+
+    #[tokio::test]
+    async fn registered_accounts_log_in_with_their_password() {
+        let hash = hash_password(String::from("correct horse")).await.unwrap();
+        let store = store(60);
+        let account = store.write().unwrap().register("Alice", "Alice A", hash).unwrap();
+        let (id, hash) = store.read().unwrap().credentials("alice").unwrap();
+        assert_eq!(id, account.id);
+        assert!(verify_password(String::from("correct horse"), hash.clone()).await);
+        assert!(!verify_password(String::from("wrong horse"), hash).await);
+        assert!(!verify_password(String::from("correct horse"), String::from("not a hash")).await);
+        assert!(store.read().unwrap().credentials("bob").is_none());
+    }
+
+    #[test]
+    fn usernames_are_unique_regardless_of_case() {
+        let store = store(60);
+        let first = store.write().unwrap().register("alice", "alice", String::new()).unwrap();
+        assert!(matches!(
+            store.write().unwrap().register("ALICE", "alice", String::new()),
+            Err(AccountError::UsernameTaken)
+        ));
+        let second = store.write().unwrap().register("bob", "bob", String::new()).unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+    }
+
+    #[test]
+    fn sessions_resolve_until_they_expire_or_log_out() {
+        let store = store(60);
+        let id = store.write().unwrap().register("alice", "Alice", String::new()).unwrap().id;
+        let (token, expires_at, _) = store.write().unwrap().open_session(id).unwrap();
+        assert!(expires_at >= now_secs() + 59);
+        let provider = SessionAuthProvider::new(store.clone());
+        let user = provider.authenticate(&token).unwrap();
+        assert_eq!((user.id, user.username.as_str(), user.display_name.as_str()), (id, "alice", "Alice"));
+        store.write().unwrap().logout(&token);
+        assert_eq!(reason(provider.authenticate(&token)), "unknown session");
+        assert!(store.write().unwrap().open_session(id + 1).is_err());
+
+        let expiring = self::store(0);
+        let id = expiring.write().unwrap().register("alice", "alice", String::new()).unwrap().id;
+        let (token, _, _) = expiring.write().unwrap().open_session(id).unwrap();
+        assert_eq!(reason(SessionAuthProvider::new(expiring).authenticate(&token)), "session expired");
+    }
+
+    #[test]
+    fn accounts_are_saved_but_sessions_are_not() {
+        let path = env::temp_dir().join(format!("user-store-test-{}.json", random_token()));
+        let path = path.to_string_lossy().into_owned();
+        let mut store = UserStore::new(Some(path.clone()), 60);
+        let id = store.register("alice", "alice", String::from("hash")).unwrap().id;
+        let (token, _, _) = store.open_session(id).unwrap();
+
+        let reloaded = Arc::new(RwLock::new(UserStore::new(Some(path.clone()), 60)));
+        assert_eq!(reloaded.read().unwrap().credentials("alice"), Some((id, String::from("hash"))));
+        assert!(reloaded.read().unwrap().username_taken("Alice"));
+        assert_eq!(reloaded.write().unwrap().register("bob", "bob", String::new()).unwrap().id, id + 1);
+        assert!(SessionAuthProvider::new(reloaded).authenticate(&token).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
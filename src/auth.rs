@@ -0,0 +1,195 @@
+use std::{collections::HashMap, env, fs, sync::Arc};
+
+use jsonwebtoken::{decode, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::accounts::{SessionAuthProvider, SharedUserStore};
+use crate::error::UnauthorizedError;
+use crate::user::User;
+use crate::utils::now_secs;
+
+pub trait AuthProvider: Send + Sync {
+    /// Resolves a bearer token (without the "Bearer " prefix) into a user
+    fn authenticate(&self, token: &str) -> Result<User, UnauthorizedError>;
+}
+pub type SharedAuthProvider = Arc<dyn AuthProvider>;
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    username: String,
+    name: Option<String>,
+}
+
+/// Verifies JWTs signed by the account service, either with a shared HS256 secret or an RS256 public key
+pub struct JwtAuthProvider {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuthProvider {
+    pub fn new(key: DecodingKey, algorithm: Algorithm, issuer: Option<String>) -> Self {
+        let mut validation = Validation::new(algorithm);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        Self { key, validation }
+    }
+    pub fn from_env() -> Result<Self, String> {
+        let issuer = env::var("AUTH_JWT_ISSUER").ok().filter(|i| !i.is_empty());
+        match env::var("AUTH_JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string()).as_str() {
+            "HS256" => {
+                let secret = env::var("AUTH_JWT_SECRET").map_err(|_| "AUTH_JWT_SECRET must be set for HS256")?;
+                Ok(Self::new(DecodingKey::from_secret(secret.as_bytes()), Algorithm::HS256, issuer))
+            }
+            "RS256" => {
+                let path = env::var("AUTH_JWT_PUBLIC_KEY").map_err(|_| "AUTH_JWT_PUBLIC_KEY must be set for RS256")?;
+                let pem = fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                let key = DecodingKey::from_rsa_pem(&pem).map_err(|e| format!("Invalid RSA public key {}: {}", path, e))?;
+                Ok(Self::new(key, Algorithm::RS256, issuer))
+            }
+            other => Err(format!("Unsupported AUTH_JWT_ALGORITHM {}", other)),
+        }
+    }
+}
+
+impl AuthProvider for JwtAuthProvider {
+    fn authenticate(&self, token: &str) -> Result<User, UnauthorizedError> {
+        let data = decode::<Claims>(token, &self.key, &self.validation).map_err(|e| {
+            UnauthorizedError::new(match e.kind() {
+                ErrorKind::ExpiredSignature => "token expired",
+                ErrorKind::ImmatureSignature => "token not yet valid",
+                ErrorKind::InvalidSignature => "invalid token signature",
+                ErrorKind::InvalidIssuer => "invalid token issuer",
+                ErrorKind::InvalidAlgorithm => "invalid token algorithm",
+                ErrorKind::MissingRequiredClaim(_) => "token is missing required claims",
+                _ => "malformed token",
+            })
+        })?;
+        let id = data.claims.sub.parse().map_err(|_| UnauthorizedError::new("malformed token subject"))?;
+        Ok(User {
+            id,
+            display_name: data.claims.name.unwrap_or_else(|| data.claims.username.clone()),
+            username: data.claims.username,
+            auth_token: token.to_string(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenEntry {
+    token: String,
+    id: u64,
+    username: String,
+    display_name: Option<String>,
+    expires_at: Option<u64>,
+}
+
+/// Static tokens loaded from a JSON file, meant for local testing
+pub struct TokenFileAuthProvider {
+    tokens: HashMap<String, TokenEntry>,
+}
+
+impl TokenFileAuthProvider {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let entries: Vec<TokenEntry> = serde_json::from_str(&contents).map_err(|e| format!("Invalid token file {}: {}", path, e))?;
+        Ok(Self {
+            tokens: entries.into_iter().map(|e| (e.token.clone(), e)).collect(),
+        })
+    }
+}
+
+impl AuthProvider for TokenFileAuthProvider {
+    fn authenticate(&self, token: &str) -> Result<User, UnauthorizedError> {
+        let entry = self.tokens.get(token).ok_or_else(|| UnauthorizedError::new("unknown token"))?;
+        if let Some(expires_at) = entry.expires_at {
+            if now_secs() >= expires_at {
+                return Err(UnauthorizedError::new("token expired"));
+            }
+        }
+        Ok(User {
+            id: entry.id,
+            username: entry.username.clone(),
+            display_name: entry.display_name.clone().unwrap_or_else(|| entry.username.clone()),
+            auth_token: entry.token.clone(),
+        })
+    }
+}
+
+pub fn auth_provider_from_env(users: SharedUserStore) -> SharedAuthProvider {
+    match env::var("AUTH_PROVIDER").unwrap_or_else(|_| "session".to_string()).as_str() {
+        "session" => Arc::new(SessionAuthProvider::new(users)),
+        "jwt" => Arc::new(JwtAuthProvider::from_env().expect("Invalid JWT auth config")),
+        "token_file" => {
+            let path = env::var("AUTH_TOKEN_FILE").expect("AUTH_TOKEN_FILE must be set for the token_file provider");
+            Arc::new(TokenFileAuthProvider::from_file(&path).expect("Invalid token file"))
+        }
+        other => panic!("Unknown AUTH_PROVIDER {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    fn jwt(claims: serde_json::Value, secret: &str) -> String {
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    fn provider() -> JwtAuthProvider {
+        JwtAuthProvider::new(DecodingKey::from_secret(b"secret"), Algorithm::HS256, Some(String::from("accounts")))
+    }
+
+    fn reason(result: Result<User, UnauthorizedError>) -> String {
+        result.err().expect("token should be rejected").reason
+    }
+
+    #[test]
+    fn valid_jwt_resolves_to_its_user() {
+        let token = jwt(
+            json!({ "sub": "7", "username": "alice", "exp": now_secs() + 60, "iss": "accounts" }),
+            "secret",
+        );
+        let user = provider().authenticate(&token).unwrap();
+        assert_eq!((user.id, user.username.as_str(), user.display_name.as_str()), (7, "alice", "alice"));
+        assert_eq!(user.auth_token, token);
+    }
+
+    #[test]
+    fn invalid_jwts_are_rejected() {
+        let claims = |exp: u64, iss: &str| json!({ "sub": "7", "username": "alice", "name": "Alice", "exp": exp, "iss": iss });
+        // past the default leeway of 60 seconds
+        let expired = jwt(claims(now_secs() - 120, "accounts"), "secret");
+        assert_eq!(reason(provider().authenticate(&expired)), "token expired");
+        let wrong_issuer = jwt(claims(now_secs() + 60, "elsewhere"), "secret");
+        assert_eq!(reason(provider().authenticate(&wrong_issuer)), "invalid token issuer");
+        let wrong_key = jwt(claims(now_secs() + 60, "accounts"), "other secret");
+        assert_eq!(reason(provider().authenticate(&wrong_key)), "invalid token signature");
+        let no_expiry = jwt(json!({ "sub": "7", "username": "alice", "iss": "accounts" }), "secret");
+        assert_eq!(reason(provider().authenticate(&no_expiry)), "token is missing required claims");
+        let bad_subject = jwt(
+            json!({ "sub": "alice", "username": "alice", "exp": now_secs() + 60, "iss": "accounts" }),
+            "secret",
+        );
+        assert_eq!(reason(provider().authenticate(&bad_subject)), "malformed token subject");
+        assert_eq!(reason(provider().authenticate("not a jwt")), "malformed token");
+    }
+
+    #[test]
+    fn token_file_resolves_known_unexpired_tokens() {
+        let provider = TokenFileAuthProvider::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/test/tokens.json")).unwrap();
+        let user = provider.authenticate("test-token-player-two").unwrap();
+        assert_eq!(
+            (user.id, user.username.as_str(), user.display_name.as_str()),
+            (2, "player_two", "player_two")
+        );
+        assert_eq!(reason(provider.authenticate("test-token-expired")), "token expired");
+        assert_eq!(reason(provider.authenticate("not-a-token")), "unknown token");
+        assert!(TokenFileAuthProvider::from_file("does-not-exist.json").is_err());
+    }
+}
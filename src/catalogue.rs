@@ -0,0 +1,616 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::limits::GameLimits;
+use crate::outcome::Outcome;
+use crate::ports::{parse_port_range, port_range, PortRequirement};
+use crate::sandbox::{Sandbox, SandboxConfig};
+use crate::state::MatchState;
+use crate::supervisor::ReadyProbe;
+use crate::supervisor::SharedSupervisor;
+use crate::utils::now_secs;
+
+const DEFAULT_CATALOGUE_PATH: &str = "games.json";
+const DEFAULT_CATALOGUE_POLL_SECS: u64 = 5;
+const DEFAULT_OPEN_TIMEOUT_SECS: u64 = 60 * 20;
+const DEFAULT_READY_CHECK_SECS: u64 = 30;
+const DEFAULT_QUEUE_SECS: u64 = 60 * 10;
+const DEFAULT_PLAYING_TIMEOUT_SECS: u64 = 60 * 60;
+const DEFAULT_STARTUP_SECS: u64 = 30;
+const DEFAULT_MAX_RUNTIME_SECS: u64 = 60 * 60;
+const DEFAULT_KILL_GRACE_SECS: u64 = 10;
+/// Placeholders `player_args` may use on top of the ones of `args`. Game tokens are not one of them, they are handed
+/// over in the environment rather than the command line, see `launch::player_token_var`
+const PLAYER_PLACEHOLDERS: [&str; 4] = ["number", "username", "user_id", "team"];
+
+/// Argv launch arguments of each player when a game sets no `player_args`
+fn default_player_args() -> Vec<String> {
+    ["-username{player.number}", "{player.username}"].map(String::from).to_vec()
+}
+fn default_true() -> bool {
+    true
+}
+
+/// How long a match may stay in each state, see `reaper`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GameTimeouts {
+    #[serde(default = "GameTimeouts::default_open")]
+    pub open_secs: u64,
+    /// How long players of a full match get to ready up
+    #[serde(default = "GameTimeouts::default_ready_check")]
+    pub ready_check_secs: u64,
+    /// How long a ready match may wait in the launch queue, see `queue`
+    #[serde(default = "GameTimeouts::default_queue")]
+    pub queue_secs: u64,
+    #[serde(default = "GameTimeouts::default_playing")]
+    pub playing_secs: u64,
+    /// How long a game may take to accept connections, the match is aborted otherwise
+    #[serde(default = "GameTimeouts::default_startup")]
+    pub startup_secs: u64,
+    /// How long a game process may run, see `supervisor`
+    #[serde(default = "GameTimeouts::default_max_runtime")]
+    pub max_runtime_secs: u64,
+    /// How long a game gets to exit after SIGTERM before it is killed
+    #[serde(default = "GameTimeouts::default_kill_grace")]
+    pub kill_grace_secs: u64,
+}
+
+impl GameTimeouts {
+    fn default_open() -> u64 {
+        DEFAULT_OPEN_TIMEOUT_SECS
+    }
+    fn default_ready_check() -> u64 {
+        DEFAULT_READY_CHECK_SECS
+    }
+    fn default_queue() -> u64 {
+        DEFAULT_QUEUE_SECS
+    }
+    fn default_playing() -> u64 {
+        DEFAULT_PLAYING_TIMEOUT_SECS
+    }
+    fn default_startup() -> u64 {
+        DEFAULT_STARTUP_SECS
+    }
+    fn default_max_runtime() -> u64 {
+        DEFAULT_MAX_RUNTIME_SECS
+    }
+    fn default_kill_grace() -> u64 {
+        DEFAULT_KILL_GRACE_SECS
+    }
+    pub fn for_state(&self, state: MatchState) -> u64 {
+        match state {
+            MatchState::OPEN => self.open_secs,
+            MatchState::READYING => self.ready_check_secs,
+            MatchState::QUEUED => self.queue_secs,
+            MatchState::STARTING => self.startup_secs,
+            MatchState::PLAYING => self.playing_secs,
+            // terminal, the match is retired
+            MatchState::FINISHED | MatchState::CANCELLED | MatchState::EXPIRED | MatchState::ABORTED => 0,
+        }
+    }
+    /// Expiry time of a match entering `state` now
+    pub fn expiry_for(&self, state: MatchState) -> u64 {
+        now_secs() + self.for_state(state)
+    }
+}
+
+impl Default for GameTimeouts {
+    fn default() -> Self {
+        Self {
+            open_secs: DEFAULT_OPEN_TIMEOUT_SECS,
+            ready_check_secs: DEFAULT_READY_CHECK_SECS,
+            queue_secs: DEFAULT_QUEUE_SECS,
+            playing_secs: DEFAULT_PLAYING_TIMEOUT_SECS,
+            startup_secs: DEFAULT_STARTUP_SECS,
+            max_runtime_secs: DEFAULT_MAX_RUNTIME_SECS,
+            kill_grace_secs: DEFAULT_KILL_GRACE_SECS,
+        }
+    }
+}
+
+/// How the pot of a drawn match is settled
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawPolicy {
+    /// Every stake goes back, as if the game had failed
+    #[default]
+    Refund,
+    /// The pot is captured and paid out in equal shares
+    Split,
+}
+
+/// Everything the server knows about one game type, as written in the catalogue file
+#[derive(Deserialize, Debug, Clone)]
+pub struct GameDefinitionFile {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub min_players: usize,
+    pub max_players: usize,
+    /// Path of the game server build, relative to the working directory
+    pub executable: Option<String>,
+    /// Extra launch arguments, see `launch::expand_args`
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Argv launch arguments repeated for every player, see `launch::expand_player_args`
+    #[serde(default = "default_player_args")]
+    pub player_args: Vec<String>,
+    /// How many teams players are split into, every player is on their own team when unset
+    pub teams: Option<usize>,
+    pub prizes: Vec<u32>,
+    /// What each exit code of the game means, any other code refunds the match
+    #[serde(default)]
+    pub exit_codes: HashMap<i32, Outcome>,
+    #[serde(default)]
+    pub draw_policy: DrawPolicy,
+    /// Named ports as `start-end` (inclusive), a single `game` port from `PORT_START` to `PORT_END` (inclusive)
+    /// when empty
+    #[serde(default)]
+    pub ports: BTreeMap<String, String>,
+    #[serde(default)]
+    pub timeouts: GameTimeouts,
+    #[serde(default)]
+    pub ready_probe: ReadyProbe,
+    #[serde(default)]
+    pub limits: GameLimits,
+    /// How many games of this type may run at once, only `MAX_GAME_PROCESSES` applies when unset
+    pub max_running: Option<usize>,
+    /// Runs the game sandboxed when set, see `sandbox::Sandbox`
+    pub sandbox: Option<SandboxConfig>,
+}
+
+/// A validated catalogue entry. Matches hold the one they were created with
+#[derive(Debug, Clone)]
+pub struct GameDefinition {
+    pub game_type: String,
+    pub enabled: bool,
+    pub min_players: usize,
+    pub max_players: usize,
+    pub executable: Option<String>,
+    pub args: Vec<String>,
+    pub player_args: Vec<String>,
+    /// Players join the team with the fewest players, `max_players / teams` each once full
+    pub teams: usize,
+    pub prizes: Vec<u32>,
+    pub exit_codes: HashMap<i32, Outcome>,
+    pub draw_policy: DrawPolicy,
+    pub ports: Vec<PortRequirement>,
+    pub timeouts: GameTimeouts,
+    pub ready_probe: ReadyProbe,
+    pub limits: GameLimits,
+    pub max_running: Option<usize>,
+    pub sandbox: Option<SandboxConfig>,
+}
+
+impl GameDefinition {
+    pub fn outcome_for_exit_code(&self, exit_code: i32) -> Outcome {
+        self.exit_codes.get(&exit_code).cloned().unwrap_or(Outcome::Refunded)
+    }
+}
+
+#[cfg(test)]
+impl GameDefinition {
+    /// A two player game named `test` running `/bin/true` for a prize of 5, with `fields` added to or replacing the
+    /// ones of its catalogue entry
+    pub fn for_tests(fields: &str) -> Arc<Self> {
+        let catalogue = serde_json::json!({ "games": { "test": tests::entry(fields) } }).to_string();
+        GameCatalogue::parse(&catalogue, &(0..0)).unwrap().get("test").unwrap().clone()
+    }
+}
+
+#[derive(Deserialize)]
+struct CatalogueFile {
+    #[serde(deserialize_with = "unique_games")]
+    games: BTreeMap<String, GameDefinitionFile>,
+}
+
+/// Rejects a game type listed twice, a map would silently keep the last one
+fn unique_games<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, GameDefinitionFile>, D::Error> {
+    struct GamesVisitor;
+    impl<'de> Visitor<'de> for GamesVisitor {
+        type Value = BTreeMap<String, GameDefinitionFile>;
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map of game types")
+        }
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut games = BTreeMap::new();
+            while let Some((game_type, definition)) = map.next_entry::<String, GameDefinitionFile>()? {
+                if games.contains_key(&game_type) {
+                    return Err(de::Error::custom(format!("game {} is listed twice", game_type)));
+                }
+                games.insert(game_type, definition);
+            }
+            Ok(games)
+        }
+    }
+    deserializer.deserialize_map(GamesVisitor)
+}
+
+/// The game types matches can be created for, loaded from `GAME_CATALOGUE_PATH`.
+/// Replaced as a whole on reload, matches keep the `Arc<GameDefinition>` they were created with
+#[derive(Debug, Default)]
+pub struct GameCatalogue {
+    games: BTreeMap<String, Arc<GameDefinition>>,
+}
+pub type SharedGameCatalogue = Arc<RwLock<GameCatalogue>>;
+
+/// Names of the placeholders `{<prefix><name>}` used by a launch argument
+fn placeholders<'a>(arg: &'a str, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+    arg.match_indices(prefix).filter_map(move |(start, _)| {
+        let rest = &arg[start + prefix.len()..];
+        rest.find('}').map(|end| &rest[..end])
+    })
+}
+
+fn validate(game_type: &str, file: GameDefinitionFile, default_ports: &std::ops::Range<u32>) -> Result<GameDefinition, String> {
+    let invalid = |reason: String| format!("game {}: {}", game_type, reason);
+    if file.min_players == 0 || file.min_players > file.max_players {
+        return Err(invalid(format!(
+            "min_players {} and max_players {} are not a valid range",
+            file.min_players, file.max_players
+        )));
+    }
+    if file.prizes.is_empty() {
+        return Err(invalid(String::from("no prizes")));
+    }
+    if file.enabled && file.executable.as_deref().is_none_or(str::is_empty) {
+        return Err(invalid(String::from("enabled without an executable")));
+    }
+    if let Some(executable) = &file.executable {
+        if !Path::new(executable).exists() {
+            println!("Executable {} of game {} does not exist", executable, game_type);
+        }
+    }
+    let teams = file.teams.unwrap_or(file.max_players);
+    if teams == 0 || !file.max_players.is_multiple_of(teams) {
+        return Err(invalid(format!("{} players can't be split into {} teams", file.max_players, teams)));
+    }
+    if teams < file.max_players && file.min_players < teams {
+        return Err(invalid(format!(
+            "min_players {} would start a game with empty teams out of {}",
+            file.min_players, teams
+        )));
+    }
+    for (code, outcome) in &file.exit_codes {
+        match outcome {
+            Outcome::Winner { player } if *player >= file.max_players => {
+                return Err(invalid(format!("exit code {} names player {} of {}", code, player, file.max_players)));
+            }
+            Outcome::TeamWinner { team } if *team >= teams => {
+                return Err(invalid(format!("exit code {} names team {} of {}", code, team, teams)));
+            }
+            _ => {}
+        }
+    }
+    let timeouts = file.timeouts;
+    if [
+        timeouts.open_secs,
+        timeouts.ready_check_secs,
+        timeouts.queue_secs,
+        timeouts.playing_secs,
+        timeouts.startup_secs,
+        timeouts.max_runtime_secs,
+    ]
+    .contains(&0)
+    {
+        return Err(invalid(String::from("timeouts must be at least a second")));
+    }
+    file.limits.validate().map_err(invalid)?;
+    if let Some(sandbox) = &file.sandbox {
+        sandbox.validate().map_err(invalid)?;
+    }
+    if file.max_running == Some(0) {
+        return Err(invalid(String::from("max_running must be at least 1")));
+    }
+    let ports = if file.ports.is_empty() {
+        vec![PortRequirement {
+            name: String::from("game"),
+            range: default_ports.clone(),
+        }]
+    } else {
+        file.ports
+            .iter()
+            .map(|(name, range)| {
+                Ok(PortRequirement {
+                    name: name.clone(),
+                    range: parse_port_range(range).map_err(|e| invalid(format!("port {}: {}", name, e)))?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?
+    };
+    for arg in file.args.iter().chain(&file.player_args) {
+        for name in placeholders(arg, "{ports.") {
+            if !ports.iter().any(|p| p.name == name) {
+                return Err(invalid(format!("argument {} uses undeclared port {}", arg, name)));
+            }
+        }
+    }
+    for arg in &file.args {
+        if placeholders(arg, "{player.").next().is_some() {
+            return Err(invalid(format!("argument {} uses a player placeholder outside player_args", arg)));
+        }
+    }
+    for arg in &file.player_args {
+        if arg.contains("{player.token}") {
+            return Err(invalid(format!(
+                "argument {} would show game tokens to anyone on the host, games get them as MATCH_PLAYER<N>_TOKEN",
+                arg
+            )));
+        }
+        if let Some(name) = placeholders(arg, "{player.").find(|name| !PLAYER_PLACEHOLDERS.contains(name)) {
+            return Err(invalid(format!("argument {} uses unknown placeholder player.{}", arg, name)));
+        }
+    }
+    Ok(GameDefinition {
+        game_type: game_type.to_string(),
+        enabled: file.enabled,
+        min_players: file.min_players,
+        max_players: file.max_players,
+        executable: file.executable,
+        args: file.args,
+        player_args: file.player_args,
+        teams,
+        prizes: file.prizes,
+        exit_codes: file.exit_codes,
+        draw_policy: file.draw_policy,
+        ports,
+        timeouts,
+        ready_probe: file.ready_probe,
+        limits: file.limits,
+        max_running: file.max_running,
+        sandbox: file.sandbox,
+    })
+}
+
+impl GameCatalogue {
+    /// Parses and validates a catalogue, every error is reported with the game it belongs to
+    pub fn parse(contents: &str, default_ports: &std::ops::Range<u32>) -> Result<Self, String> {
+        let file: CatalogueFile = serde_json::from_str(contents).map_err(|e| format!("invalid catalogue: {}", e))?;
+        if file.games.is_empty() {
+            return Err(String::from("catalogue has no games"));
+        }
+        let games = file
+            .games
+            .into_iter()
+            .map(|(game_type, definition)| Ok((game_type.clone(), Arc::new(validate(&game_type, definition, default_ports)?))))
+            .collect::<Result<_, String>>()?;
+        Ok(Self { games })
+    }
+    /// Loads and validates the catalogue at `path`, preparing `sandbox` if one of its games is sandboxed
+    pub fn load(path: &str, sandbox: &Sandbox) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let low_port: u32 = env::var("PORT_START")
+            .unwrap_or_else(|_| "30000".to_string())
+            .parse()
+            .expect("Invalid low port");
+        let high_port: u32 = env::var("PORT_END")
+            .unwrap_or_else(|_| "31000".to_string())
+            .parse()
+            .expect("Invalid high port");
+        let default_ports = port_range(low_port, high_port).map_err(|e| format!("PORT_START and PORT_END: {}", e))?;
+        let catalogue = Self::parse(&contents, &default_ports)?;
+        if catalogue.games().any(|game| game.sandbox.is_some()) {
+            sandbox.prepare().map_err(|e| format!("cannot sandbox games: {}", e))?;
+        }
+        Ok(catalogue)
+    }
+    pub fn path_from_env() -> String {
+        env::var("GAME_CATALOGUE_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| DEFAULT_CATALOGUE_PATH.to_string())
+    }
+    pub fn from_env(sandbox: &Sandbox) -> Self {
+        let path = Self::path_from_env();
+        Self::load(&path, sandbox).unwrap_or_else(|e| panic!("Invalid game catalogue {}: {}", path, e))
+    }
+    pub fn get(&self, game_type: &str) -> Option<&Arc<GameDefinition>> {
+        self.games.get(game_type)
+    }
+    pub fn games(&self) -> impl Iterator<Item = &Arc<GameDefinition>> {
+        self.games.values()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the catalogue on SIGHUP and when the file's modification time changes, checked every
+/// `GAME_CATALOGUE_POLL_SECS` (0 only reloads on SIGHUP). A catalogue that fails validation is logged and the
+/// current one is kept
+pub async fn run_catalogue_reloader(catalogue: SharedGameCatalogue, supervisor: SharedSupervisor) {
+    let poll_secs: u64 = env::var("GAME_CATALOGUE_POLL_SECS")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().expect("Invalid GAME_CATALOGUE_POLL_SECS"))
+        .unwrap_or(DEFAULT_CATALOGUE_POLL_SECS);
+    reload_catalogue(GameCatalogue::path_from_env(), poll_secs, catalogue, supervisor).await;
+}
+
+/// Replaces `catalogue` with the one at `path` whenever it changes, see `run_catalogue_reloader`
+async fn reload_catalogue(path: String, poll_secs: u64, catalogue: SharedGameCatalogue, supervisor: SharedSupervisor) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    let mut interval = tokio::time::interval(Duration::from_secs(poll_secs.max(1)));
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => println!("SIGHUP received, reloading game catalogue {}", path),
+            _ = interval.tick(), if poll_secs > 0 => {
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                println!("Game catalogue {} changed, reloading", path);
+            }
+        }
+        last_modified = modified(&path);
+        // preparing the sandbox for a first sandboxed game starts and waits on a probe process
+        let (load_path, load_supervisor) = (path.clone(), supervisor.clone());
+        let loaded = tokio::task::spawn_blocking(move || GameCatalogue::load(&load_path, load_supervisor.sandbox()))
+            .await
+            .unwrap_or_else(|e| Err(format!("loading failed: {}", e)));
+        match loaded {
+            Ok(loaded) => {
+                let game_types: Vec<&str> = loaded.games.keys().map(String::as_str).collect();
+                println!("Loaded game catalogue with {}", game_types.join(", "));
+                *catalogue.write().unwrap() = loaded;
+            }
+            Err(e) => println!("Rejected game catalogue {}, keeping the current one: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT_PORTS: std::ops::Range<u32> = 30000..30011;
+
+    /// The entry of a two player game with `fields` added to or replacing its own
+    pub(super) fn entry(fields: &str) -> serde_json::Value {
+        let mut game = serde_json::json!({ "min_players": 2, "max_players": 2, "executable": "/bin/true", "prizes": [5] });
+        let fields: serde_json::Value = serde_json::from_str(&format!("{{{}}}", fields)).unwrap();
+        game.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        game
+    }
+
+    fn parse(fields: &str) -> Result<Arc<GameDefinition>, String> {
+        let catalogue = serde_json::json!({ "games": { "test": entry(fields) } }).to_string();
+        GameCatalogue::parse(&catalogue, &DEFAULT_PORTS).map(|catalogue| catalogue.get("test").unwrap().clone())
+    }
+
+    fn rejected(fields: &str, reason: &str) {
+        let e = parse(fields).unwrap_err();
+        assert!(e.contains(reason), "{} was rejected with {}", fields, e);
+    }
+
+    #[test]
+    fn definitions_get_defaults() {
+        let game = parse("").unwrap();
+        assert_eq!((game.teams, game.enabled, game.draw_policy), (2, true, DrawPolicy::Refund));
+        assert_eq!(game.timeouts, GameTimeouts::default());
+        assert_eq!(
+            game.ports,
+            vec![PortRequirement {
+                name: String::from("game"),
+                range: DEFAULT_PORTS
+            }]
+        );
+        assert_eq!(game.outcome_for_exit_code(1001), Outcome::Refunded);
+    }
+
+    #[test]
+    fn game_types_are_listed_once() {
+        let game = entry("").to_string();
+        let catalogue = format!(r#"{{ "games": {{ "test": {}, "other": {}, "test": {} }} }}"#, game, game, game);
+        let e = GameCatalogue::parse(&catalogue, &DEFAULT_PORTS).unwrap_err();
+        assert!(e.contains("game test is listed twice"), "{}", e);
+        assert!(GameCatalogue::parse(r#"{ "games": {} }"#, &DEFAULT_PORTS).is_err());
+    }
+
+    #[test]
+    fn player_counts_must_be_a_range() {
+        rejected(r#""min_players": 3"#, "not a valid range");
+        rejected(r#""min_players": 0"#, "not a valid range");
+        assert!(parse(r#""max_players": 8"#).is_ok());
+        rejected(r#""prizes": []"#, "no prizes");
+        rejected(r#""executable": null"#, "without an executable");
+        assert!(parse(r#""executable": null, "enabled": false"#).is_ok());
+    }
+
+    #[test]
+    fn players_must_split_evenly_into_teams() {
+        rejected(r#""max_players": 4, "teams": 3"#, "can't be split into 3 teams");
+        rejected(r#""teams": 0"#, "can't be split into 0 teams");
+        rejected(r#""min_players": 2, "max_players": 6, "teams": 3"#, "empty teams");
+        let game = parse(r#""min_players": 3, "max_players": 6, "teams": 3"#).unwrap();
+        assert_eq!(game.teams, 3);
+    }
+
+    #[test]
+    fn port_ranges_are_inclusive_and_may_overlap() {
+        let game = parse(r#""ports": { "game": "31000-31010", "query": "31005-31005" }"#).unwrap();
+        let ranges: Vec<_> = game.ports.iter().map(|p| (p.name.as_str(), p.range.clone())).collect();
+        assert_eq!(ranges, vec![("game", 31000..31011), ("query", 31005..31006)]);
+        rejected(r#""ports": { "game": "31010-31000" }"#, "port game: invalid port range");
+        rejected(r#""ports": { "game": "31000-70000" }"#, "port game: invalid port range");
+        rejected(r#""ports": { "game": "31000" }"#, "port game: invalid port range");
+        rejected(r#""args": ["-query", "{ports.query}"]"#, "undeclared port query");
+    }
+
+    #[test]
+    fn exit_codes_must_name_outcomes_the_game_has() {
+        let game = parse(r#""exit_codes": { "1001": { "outcome": "winner", "player": 1 }, "1003": { "outcome": "draw" } }"#).unwrap();
+        assert_eq!(game.outcome_for_exit_code(1001), Outcome::Winner { player: 1 });
+        assert_eq!(game.outcome_for_exit_code(1003), Outcome::Draw);
+        rejected(r#""exit_codes": { "1001": { "outcome": "winner", "player": 2 } }"#, "names player 2 of 2");
+        rejected(
+            r#""max_players": 4, "teams": 2, "exit_codes": { "1": { "outcome": "team_winner", "team": 2 } }"#,
+            "names team 2 of 2",
+        );
+        rejected(r#""exit_codes": { "1001": { "outcome": "surrender" } }"#, "invalid catalogue");
+        rejected(r#""exit_codes": { "win": { "outcome": "draw" } }"#, "invalid catalogue");
+    }
+
+    #[test]
+    fn timeouts_default_separately_and_must_be_set() {
+        let game = parse(r#""timeouts": { "playing_secs": 120 }"#).unwrap();
+        assert_eq!(game.timeouts.playing_secs, 120);
+        assert_eq!(game.timeouts.startup_secs, DEFAULT_STARTUP_SECS);
+        rejected(r#""timeouts": { "ready_check_secs": 0 }"#, "at least a second");
+        rejected(r#""timeouts": { "max_runtime_secs": 0 }"#, "at least a second");
+        // a game may be killed right after SIGTERM
+        assert!(parse(r#""timeouts": { "kill_grace_secs": 0 }"#).is_ok());
+        rejected(r#""max_running": 0"#, "max_running must be at least 1");
+    }
+
+    /// Writes a catalogue with a `test` game for `prize` to `path`, or garbage without one
+    fn write_catalogue(path: &Path, prize: Option<u32>) {
+        let contents = match prize {
+            Some(prize) => serde_json::json!({ "games": { "test": entry(&format!(r#""prizes": [{}]"#, prize)) } }).to_string(),
+            None => String::from("{ \"games\": "),
+        };
+        fs::write(path, contents).unwrap();
+    }
+
+    fn prizes(catalogue: &SharedGameCatalogue) -> Vec<u32> {
+        catalogue.read().unwrap().get("test").unwrap().prizes.clone()
+    }
+
+    #[tokio::test]
+    async fn changed_catalogues_replace_the_current_one_unless_invalid() {
+        let path = env::temp_dir().join(format!("catalogue-{}.json", crate::utils::random_token()));
+        write_catalogue(&path, Some(5));
+        let supervisor = crate::supervisor::Supervisor::for_tests();
+        let loaded = GameCatalogue::load(path.to_str().unwrap(), supervisor.sandbox()).unwrap();
+        let catalogue: SharedGameCatalogue = Arc::new(RwLock::new(loaded));
+        // a match created now keeps its definition
+        let created_with = catalogue.read().unwrap().get("test").unwrap().clone();
+        let reloader = tokio::spawn(reload_catalogue(path.to_string_lossy().into_owned(), 1, catalogue.clone(), supervisor));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        write_catalogue(&path, Some(10));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(prizes(&catalogue), vec![10]);
+        assert_eq!(created_with.prizes, vec![5]);
+
+        write_catalogue(&path, None);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(prizes(&catalogue), vec![10]);
+        write_catalogue(&path, Some(0));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(prizes(&catalogue), vec![0]);
+
+        reloader.abort();
+        fs::remove_file(&path).unwrap();
+    }
+}
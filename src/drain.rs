@@ -0,0 +1,226 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::history::SharedMatchHistory;
+use crate::ledger::SharedLedger;
+use crate::outcome::{MatchResult, Outcome, OutcomeSource};
+use crate::queue::SharedLaunchQueue;
+use crate::state::{transition, MatchState};
+use crate::utils::now_secs;
+use crate::{retire_match, Matches};
+
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 300;
+/// How often a drain checks whether the running games are done
+const DRAIN_POLL_SECS: u64 = 1;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum DrainState {
+    /// Matches are created and joined as usual
+    Serving,
+    /// New matches are refused, running games get until `deadline` before they are stopped
+    Draining { deadline: u64 },
+    /// Every match has ended
+    Drained,
+}
+
+/// Whether the server is winding down, started by `SIGTERM` or `POST /admin/drain`. Subscribers of
+/// `/updates` are told through `subscribe`, see `run_drain` for what draining does
+pub struct Drain {
+    state: watch::Sender<DrainState>,
+    /// How long running games get to finish once draining starts
+    timeout: u64,
+}
+pub type SharedDrain = Arc<Drain>;
+
+impl Drain {
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            state: watch::channel(DrainState::Serving).0,
+            timeout,
+        }
+    }
+    pub fn from_env() -> Self {
+        Self::new(
+            env::var("DRAIN_TIMEOUT_SECS")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse().expect("Invalid DRAIN_TIMEOUT_SECS"))
+                .unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS),
+        )
+    }
+    pub fn state(&self) -> DrainState {
+        *self.state.borrow()
+    }
+    pub fn is_draining(&self) -> bool {
+        self.state() != DrainState::Serving
+    }
+    pub fn subscribe(&self) -> watch::Receiver<DrainState> {
+        self.state.subscribe()
+    }
+    /// Starts draining unless it already started, returns whether this call started it
+    pub fn start(&self) -> bool {
+        let deadline = now_secs() + self.timeout;
+        self.state.send_if_modified(|state| {
+            if *state != DrainState::Serving {
+                return false;
+            }
+            println!("Draining, running games have until {}", deadline);
+            *state = DrainState::Draining { deadline };
+            true
+        })
+    }
+    /// Resolves once every match has ended
+    pub async fn drained(&self) {
+        let _ = self.subscribe().wait_for(|s| *s == DrainState::Drained).await;
+    }
+}
+
+/// The result of a match whose game was stopped at the drain deadline without reporting one
+pub fn drained_result() -> MatchResult {
+    MatchResult {
+        outcome: Outcome::Refunded,
+        source: OutcomeSource::Drained,
+        report: None,
+    }
+}
+
+/// Waits for a drain to start, then cancels every `OPEN`, `READYING` and `QUEUED` match and refunds it. `STARTING`
+/// and `PLAYING` games are waited on until the deadline, then stopped and settled from whatever they reported or
+/// refunded with `drained_result`, their exit code says nothing once they were stopped
+pub async fn run_drain(drain: SharedDrain, matches: Matches, ledger: SharedLedger, history: SharedMatchHistory, queue: SharedLaunchQueue) {
+    let deadline = {
+        let mut state = drain.subscribe();
+        match state.wait_for(|s| *s != DrainState::Serving).await.map(|s| *s) {
+            Ok(DrainState::Draining { deadline }) => deadline,
+            _ => return,
+        }
+    };
+    // `/create` and `/join` check the drain under the locks taken here, nothing new shows up after this
+    let mut cancelled = Vec::new();
+    for game in matches.read().await.values() {
+        let mut game = game.write().await;
+        if matches!(game.state, MatchState::OPEN | MatchState::READYING | MatchState::QUEUED) && transition(&mut game, MatchState::CANCELLED).is_ok()
+        {
+            queue.remove(game.id);
+            cancelled.push(game.id);
+        }
+    }
+    for id in cancelled {
+        ledger.refund(id).await;
+        retire_match(&matches, &history, id).await;
+    }
+    // the games retire their matches as they exit, a stopped game is settled by its supervisor
+    let mut stopped = false;
+    let mut interval = tokio::time::interval(Duration::from_secs(DRAIN_POLL_SECS));
+    loop {
+        interval.tick().await;
+        let remaining: Vec<_> = matches.read().await.values().cloned().collect();
+        if remaining.is_empty() {
+            break;
+        }
+        if !stopped && now_secs() >= deadline {
+            println!("Drain deadline reached, stopping {} games", remaining.len());
+            for game in remaining {
+                let mut game = game.write().await;
+                game.drained = true;
+                game.kill.notify_one();
+            }
+            stopped = true;
+        }
+    }
+    println!("Drained");
+    drain.state.send_replace(DrainState::Drained);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalogue::GameDefinition;
+    use crate::history::MatchHistory;
+    use crate::ledger::{Ledger, TransactionKind};
+    use crate::queue::LaunchQueue;
+    use crate::wallet::InMemoryWallet;
+    use crate::{settle_match, Match};
+    use std::collections::HashMap;
+    use tokio::sync::RwLock;
+
+    #[test]
+    fn drain_starts_once_with_its_deadline() {
+        let drain = Drain::new(60);
+        assert!(!drain.is_draining());
+        let mut subscriber = drain.subscribe();
+        assert!(drain.start());
+        assert!(subscriber.has_changed().unwrap());
+        let DrainState::Draining { deadline } = *subscriber.borrow_and_update() else {
+            panic!("drain did not start");
+        };
+        assert!(deadline >= now_secs() + 59);
+        assert!(drain.is_draining());
+        assert!(!drain.start());
+        assert!(!subscriber.has_changed().unwrap());
+        assert_eq!(drain.state(), DrainState::Draining { deadline });
+    }
+
+    #[tokio::test]
+    async fn draining_cancels_unlaunched_matches_and_stops_games_at_the_deadline() {
+        let ledger: SharedLedger = Arc::new(Ledger::new(Arc::new(InMemoryWallet::new(100)), None, None));
+        let matches: Matches = Arc::new(RwLock::new(HashMap::new()));
+        for (id, state) in [
+            (1, MatchState::OPEN),
+            (2, MatchState::READYING),
+            (3, MatchState::QUEUED),
+            (4, MatchState::PLAYING),
+        ] {
+            let mut game = Match::for_tests(id, GameDefinition::for_tests(""), &["alice"]);
+            game.player_ids = vec![id as u64];
+            game.state = state;
+            ledger.escrow(id, id as u64, 10).await.unwrap();
+            matches.write().await.insert(id, Arc::new(RwLock::new(game)));
+        }
+        let playing = matches.read().await[&4].clone();
+        let history: SharedMatchHistory = Arc::new(std::sync::RwLock::new(MatchHistory::new(10)));
+        let drain = Arc::new(Drain::new(2));
+        let draining = tokio::spawn(run_drain(
+            drain.clone(),
+            matches.clone(),
+            ledger.clone(),
+            history.clone(),
+            Arc::new(LaunchQueue::new(None)),
+        ));
+        drain.start();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        for id in [1, 2, 3] {
+            assert_eq!(history.read().unwrap().get(id).unwrap().state, MatchState::CANCELLED);
+            let kinds: Vec<_> = ledger.transactions_for(id as u64).iter().map(|t| t.kind).collect();
+            assert_eq!(kinds, vec![TransactionKind::ESCROW, TransactionKind::REFUND]);
+        }
+        assert_eq!(matches.read().await.keys().collect::<Vec<_>>(), vec![&4]);
+        assert!(!playing.read().await.drained);
+
+        // the game is stopped at the deadline, the drain waits for it to be settled
+        let kill = playing.read().await.kill.clone();
+        tokio::time::timeout(Duration::from_secs(4), kill.notified()).await.unwrap();
+        assert!(playing.read().await.drained);
+        assert_eq!(playing.read().await.state, MatchState::PLAYING);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(matches!(drain.state(), DrainState::Draining { .. }));
+        assert!(!draining.is_finished());
+
+        settle_match(&playing, &ledger, drained_result()).await;
+        retire_match(&matches, &history, 4).await;
+        tokio::time::timeout(Duration::from_secs(3), drain.drained()).await.unwrap();
+        draining.await.unwrap();
+        let retired = history.read().unwrap().get(4).cloned().unwrap();
+        assert_eq!(
+            (retired.state, retired.result.unwrap().source),
+            (MatchState::ABORTED, OutcomeSource::Drained)
+        );
+        assert_eq!(ledger.wallet().balance(4).await.unwrap(), 100);
+    }
+}
@@ -6,31 +6,66 @@ pub struct NotFoundError;
 impl Reject for NotFoundError {}
 
 #[derive(Debug)]
+pub struct UnauthorizedError {
+    pub reason: String,
+}
 
-pub struct UnauthorizedError;
+impl UnauthorizedError {
+    pub fn new(reason: &str) -> Self {
+        Self { reason: reason.to_string() }
+    }
+}
 
 impl Reject for UnauthorizedError {}
 
+#[derive(Debug)]
+pub struct UsernameTakenError;
+
+impl Reject for UsernameTakenError {}
+
 #[derive(Debug)]
 pub struct InvalidInputError;
 
 impl Reject for InvalidInputError {}
 
+#[derive(Debug)]
+pub struct InsufficientFundsError;
+
+impl Reject for InsufficientFundsError {}
+
+#[derive(Debug)]
+pub struct WalletUnavailableError;
+
+impl Reject for WalletUnavailableError {}
+
 #[derive(Debug)]
 pub struct CannotJoinMatchError;
 
 impl Reject for CannotJoinMatchError {}
 
 #[derive(Debug)]
-pub struct IdGenerationError;
-impl Reject for IdGenerationError {}
+pub struct PasswordHashingError;
+impl Reject for PasswordHashingError {}
 
 #[derive(Debug)]
-pub struct CannotBroadcastError;
+pub struct DuplicateResultError;
+impl Reject for DuplicateResultError {}
 
-impl Reject for CannotBroadcastError {}
+#[derive(Debug)]
+pub struct IdGenerationError;
+impl Reject for IdGenerationError {}
 
 #[derive(Debug)]
 pub struct NoAvailablePorts;
 
 impl Reject for NoAvailablePorts {}
+
+#[derive(Debug)]
+pub struct InvalidMatchStateError;
+
+impl Reject for InvalidMatchStateError {}
+
+#[derive(Debug)]
+pub struct DrainingError;
+
+impl Reject for DrainingError {}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::utils::{now_secs, random_token};
+
+const DEFAULT_GAME_TOKEN_GRACE_SECS: u64 = 60 * 5;
+
+/// Token a player presents to the game server of a single match, minted when the match launches
+#[derive(Serialize, Clone)]
+pub struct GameToken {
+    #[serde(skip)]
+    pub token: String,
+    pub match_id: u32,
+    pub user_id: u64,
+    pub username: String,
+    pub expires_at: u64,
+}
+
+pub struct GameTokens {
+    tokens: HashMap<String, GameToken>,
+    /// How long tokens stay valid after their match would have timed out
+    grace: u64,
+}
+pub type SharedGameTokens = Arc<Mutex<GameTokens>>;
+
+impl GameTokens {
+    pub fn new(grace: u64) -> Self {
+        Self {
+            tokens: HashMap::new(),
+            grace,
+        }
+    }
+    pub fn from_env() -> Self {
+        let grace = env::var("GAME_TOKEN_GRACE_SECS")
+            .map(|v| v.parse().expect("Invalid GAME_TOKEN_GRACE_SECS"))
+            .unwrap_or(DEFAULT_GAME_TOKEN_GRACE_SECS);
+        Self::new(grace)
+    }
+    /// Mints a token for a player of a match whose game times out at `game_ends_at`, it stays valid until then
+    pub fn mint(&mut self, match_id: u32, user_id: u64, username: &str, game_ends_at: u64) -> String {
+        let now = now_secs();
+        self.tokens.retain(|_, t| t.expires_at > now);
+        let token = random_token();
+        self.tokens.insert(
+            token.clone(),
+            GameToken {
+                token: token.clone(),
+                match_id,
+                user_id,
+                username: username.to_string(),
+                expires_at: game_ends_at + self.grace,
+            },
+        );
+        token
+    }
+    /// Accepts a token minted by a previous run of the server again, for a game that outlived it
+    pub fn restore(&mut self, token: &str, match_id: u32, user_id: u64, username: &str, game_ends_at: u64) {
+        self.tokens.insert(
+            token.to_string(),
+            GameToken {
+                token: token.to_string(),
+                match_id,
+                user_id,
+                username: username.to_string(),
+                expires_at: game_ends_at + self.grace,
+            },
+        );
+    }
+    /// Returns the token's owner if it was minted for `match_id` and has not expired
+    pub fn verify(&self, token: &str, match_id: u32) -> Option<&GameToken> {
+        self.tokens.get(token).filter(|t| t.match_id == match_id && t.expires_at > now_secs())
+    }
+    pub fn revoke_match(&mut self, match_id: u32) {
+        self.tokens.retain(|_, t| t.match_id != match_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_verify_for_their_match_only() {
+        let mut tokens = GameTokens::new(60);
+        let token = tokens.mint(1, 7, "alice", now_secs() + 3600);
+        let verified = tokens.verify(&token, 1).unwrap();
+        assert_eq!((verified.user_id, verified.username.as_str()), (7, "alice"));
+        assert_eq!(verified.expires_at, now_secs() + 3660);
+        assert!(tokens.verify(&token, 2).is_none());
+        assert!(tokens.verify("not-a-token", 1).is_none());
+    }
+
+    #[test]
+    fn tokens_expire_after_the_grace_period() {
+        let mut tokens = GameTokens::new(0);
+        let expired = tokens.mint(1, 7, "alice", now_secs());
+        assert!(tokens.verify(&expired, 1).is_none());
+        let mut tokens = GameTokens::new(60);
+        let in_grace = tokens.mint(1, 7, "alice", now_secs() - 30);
+        assert!(tokens.verify(&in_grace, 1).is_some());
+        // expired tokens are dropped when the next ones are minted
+        let mut tokens = GameTokens::new(0);
+        tokens.mint(1, 7, "alice", now_secs() - 1);
+        tokens.mint(2, 8, "bob", now_secs() + 60);
+        assert_eq!(tokens.tokens.len(), 1);
+    }
+
+    #[test]
+    fn revoking_a_match_keeps_other_matches_tokens() {
+        let mut tokens = GameTokens::new(60);
+        let alice = tokens.mint(1, 7, "alice", now_secs() + 60);
+        let bob = tokens.mint(1, 8, "bob", now_secs() + 60);
+        let carol = tokens.mint(2, 9, "carol", now_secs() + 60);
+        tokens.revoke_match(1);
+        assert!(tokens.verify(&alice, 1).is_none() && tokens.verify(&bob, 1).is_none());
+        assert!(tokens.verify(&carol, 2).is_some());
+        tokens.restore(&alice, 1, 7, "alice", now_secs() + 60);
+        assert_eq!(tokens.verify(&alice, 1).unwrap().username, "alice");
+    }
+}
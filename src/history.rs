@@ -0,0 +1,88 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Arc, RwLock};
+
+use crate::Match;
+
+const DEFAULT_MATCH_HISTORY_SIZE: usize = 1000;
+
+/// Matches that reached a terminal state, kept so players can still look up the result.
+/// The oldest match is dropped once `capacity` is reached
+pub struct MatchHistory {
+    matches: HashMap<u32, Match>,
+    order: VecDeque<u32>,
+    capacity: usize,
+}
+pub type SharedMatchHistory = Arc<RwLock<MatchHistory>>;
+
+impl MatchHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            matches: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+    pub fn from_env() -> Self {
+        let capacity = env::var("MATCH_HISTORY_SIZE")
+            .map(|v| v.parse().expect("Invalid MATCH_HISTORY_SIZE"))
+            .unwrap_or(DEFAULT_MATCH_HISTORY_SIZE);
+        Self::new(capacity)
+    }
+    pub fn push(&mut self, game: Match) {
+        if self.capacity == 0 {
+            return;
+        }
+        // ids are only unique among live matches, a new one may reuse the id of a match still kept here
+        if self.matches.contains_key(&game.id) {
+            self.order.retain(|id| *id != game.id);
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.matches.remove(&oldest);
+            }
+        }
+        self.order.push_back(game.id);
+        self.matches.insert(game.id, game);
+    }
+    pub fn get(&self, id: u32) -> Option<&Match> {
+        self.matches.get(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalogue::GameDefinition;
+    use crate::state::MatchState;
+
+    fn history(capacity: usize, ids: &[u32]) -> MatchHistory {
+        let mut history = MatchHistory::new(capacity);
+        for id in ids {
+            history.push(Match::for_tests(*id, GameDefinition::for_tests(""), &["alice"]));
+        }
+        history
+    }
+
+    #[test]
+    fn oldest_matches_are_dropped_at_capacity() {
+        let history = history(2, &[1, 2, 3]);
+        assert!(history.get(1).is_none());
+        assert!(history.get(2).is_some() && history.get(3).is_some());
+        assert!(self::history(0, &[1]).get(1).is_none());
+    }
+
+    #[test]
+    fn pushing_a_match_again_replaces_it() {
+        let mut history = history(2, &[1, 2]);
+        let mut again = Match::for_tests(1, GameDefinition::for_tests(""), &["alice"]);
+        again.state = MatchState::CANCELLED;
+        history.push(again);
+        assert_eq!(history.order, VecDeque::from([2, 1]));
+        assert_eq!(history.get(1).unwrap().state, MatchState::CANCELLED);
+        // 2 is now the oldest
+        history.push(Match::for_tests(3, GameDefinition::for_tests(""), &["alice"]));
+        assert!(history.get(2).is_none());
+        assert!(history.get(1).is_some() && history.get(3).is_some());
+    }
+}
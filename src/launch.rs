@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::catalogue::GameDefinition;
+use crate::outcome::{verify_signature, GameResultReport, OutcomeSource};
+use crate::utils::random_token;
+
+const DEFAULT_RUNTIME_DIR: &str = "runtime";
+
+/// Environment variable holding the result secret in the `LaunchMode::Argv` mode
+pub const RESULT_SECRET_VAR: &str = "MATCH_RESULT_SECRET";
+
+/// Environment variable holding the game token of the `number`th player (from 1) in the `LaunchMode::Argv` mode
+pub fn player_token_var(number: usize) -> String {
+    format!("MATCH_PLAYER{}_TOKEN", number)
+}
+
+/// How launch parameters reach the game process
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LaunchMode {
+    /// `-port … -username1 …` on the command line, visible to anyone running `ps`. The result secret and game
+    /// tokens are in the environment instead, see `RESULT_SECRET_VAR` and `player_token_var`
+    Argv,
+    /// Launch document written to the child's stdin, announced with `-launchstdin true`
+    Stdin,
+    /// Launch document written to a 0600 file in the runtime dir passed with `-launchfile <path>`
+    File,
+}
+
+impl LaunchMode {
+    fn from_env() -> Self {
+        match env::var("LAUNCH_MODE").unwrap_or_else(|_| "argv".to_string()).as_str() {
+            "argv" | "" => LaunchMode::Argv,
+            "stdin" => LaunchMode::Stdin,
+            "file" => LaunchMode::File,
+            other => panic!("Unknown LAUNCH_MODE {}", other),
+        }
+    }
+}
+
+pub struct LaunchConfig {
+    pub mode: LaunchMode,
+    /// Where game servers post their signed result, see `end_match_handler`
+    pub result_url: String,
+    /// Where games without a scratch dir get their launch and result files, only the server's user can enter it
+    pub runtime_dir: PathBuf,
+}
+pub type SharedLaunchConfig = Arc<LaunchConfig>;
+
+impl LaunchConfig {
+    pub fn from_env(host: &str, port: u16) -> Self {
+        Self {
+            mode: LaunchMode::from_env(),
+            result_url: env::var("RESULT_CALLBACK_URL")
+                .ok()
+                .filter(|u| !u.is_empty())
+                .unwrap_or_else(|| format!("http://{}:{}/end_match", host, port)),
+            runtime_dir: runtime_dir_from_env(),
+        }
+    }
+}
+
+/// Creates `GAME_RUNTIME_DIR` if needed and makes it private to the server's user
+fn runtime_dir_from_env() -> PathBuf {
+    let dir = env::var("GAME_RUNTIME_DIR")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| DEFAULT_RUNTIME_DIR.to_string());
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .expect("Failed to create GAME_RUNTIME_DIR");
+    // the mode only applies to a dir created just now
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).expect("Failed to restrict GAME_RUNTIME_DIR");
+    fs::canonicalize(&dir).expect("Invalid GAME_RUNTIME_DIR")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LaunchPlayer {
+    pub user_id: u64,
+    pub username: String,
+    pub token: String,
+    pub team: usize,
+}
+
+/// Everything a game process needs to host a match
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LaunchDocument {
+    pub match_id: u32,
+    pub game_type: String,
+    pub port: u32,
+    pub players: Vec<LaunchPlayer>,
+    /// How many teams the players are split into, see `LaunchPlayer.team`
+    pub teams: usize,
+    /// Every port leased for the match by name, `port` is the `game` one
+    pub ports: BTreeMap<String, u32>,
+    pub settings: serde_json::Value,
+    pub result_url: String,
+    /// HMAC-SHA256 key the result posted to `result_url` must be signed with
+    pub result_secret: String,
+    /// Where the game may write its result instead, see `read_report`
+    pub result_file: String,
+    /// Directory the game may write to when it is sandboxed, see `sandbox::Sandbox`
+    pub scratch_dir: Option<String>,
+}
+
+/// A path in `dir`, the game's scratch dir or the runtime dir, for the game of `match_id` to write its result to.
+/// The file does not exist yet
+pub fn result_file_path(match_id: u32, dir: &Path) -> String {
+    dir.join(format!("result-{}-{}.json", match_id, &random_token()[..8]))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Parses a result report written as the hex HMAC-SHA256 signature of the report, keyed by the result secret like
+/// the ones posted to `result_url`, a space and the report. Ignores an unsigned one or one for another match
+fn parse_report(contents: &str, doc: &LaunchDocument) -> Option<GameResultReport> {
+    let contents = contents.trim();
+    let (signature, body) = contents.split_once(' ').unwrap_or(("", contents));
+    let report = serde_json::from_str::<GameResultReport>(body).ok()?;
+    if !verify_signature(&doc.result_secret, body.as_bytes(), signature) {
+        println!("Ignoring unsigned result reported by the game of match {}", doc.match_id);
+        return None;
+    }
+    if report.match_id != doc.match_id {
+        println!(
+            "Ignoring result for match {} reported by the game of match {}",
+            report.match_id, doc.match_id
+        );
+        return None;
+    }
+    Some(report)
+}
+
+/// Reads and removes the result file of a game that exited. It is not followed if it is a symlink and ignored
+/// unless the server's user, which the game runs as, owns it
+fn take_result_file(doc: &LaunchDocument) -> Option<GameResultReport> {
+    let opened = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(&doc.result_file);
+    match fs::remove_file(&doc.result_file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => println!("Failed to remove result file {}: {}", doc.result_file, e),
+        _ => {}
+    }
+    let mut file = opened.ok()?;
+    let metadata = file.metadata().ok()?;
+    // SAFETY: geteuid can't fail
+    if !metadata.is_file() || metadata.uid() != unsafe { libc::geteuid() } {
+        println!("Ignoring result file for match {} not written by its game", doc.match_id);
+        return None;
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    let report = parse_report(&contents, doc);
+    if report.is_none() {
+        println!("Invalid result file for match {}", doc.match_id);
+    }
+    report
+}
+
+/// The game simulation stands in for every game outside production
+fn executable_path(game: &GameDefinition) -> Result<String, std::io::Error> {
+    match env::var("ENVIRONMENT").ok() {
+        Some(_) => Ok(String::from("./target/release/game-simulation")),
+        None => game
+            .executable
+            .clone()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("game {} has no executable", game.game_type))),
+    }
+}
+
+/// Substitutes `{match_id}`, `{port}`, `{player_count}`, `{teams}` and `{ports.<name>}` in launch arguments.
+/// A port the match didn't lease is an error rather than being passed on as is
+pub fn expand_args(args: &[String], doc: &LaunchDocument) -> Result<Vec<String>, std::io::Error> {
+    args.iter()
+        .map(|arg| {
+            let mut expanded = arg
+                .replace("{match_id}", &doc.match_id.to_string())
+                .replace("{port}", &doc.port.to_string())
+                .replace("{player_count}", &doc.players.len().to_string())
+                .replace("{teams}", &doc.teams.to_string());
+            while let Some(start) = expanded.find("{ports.") {
+                let end = expanded[start..]
+                    .find('}')
+                    .map(|i| start + i)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unterminated placeholder in {}", arg)))?;
+                let name = &expanded[start + "{ports.".len()..end];
+                let port = doc
+                    .ports
+                    .get(name)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("match has no {} port for {}", name, arg)))?;
+                expanded.replace_range(start..=end, &port.to_string());
+            }
+            Ok(expanded)
+        })
+        .collect()
+}
+
+/// Renders the argv arguments of every player from the game's `player_args`, substituting `{player.number}` (from 1),
+/// `{player.username}`, `{player.user_id}` and `{player.team}` (from 0) before `expand_args`
+pub fn expand_player_args(template: &[String], doc: &LaunchDocument) -> Result<Vec<String>, std::io::Error> {
+    let mut args = Vec::new();
+    for (i, player) in doc.players.iter().enumerate() {
+        let rendered: Vec<String> = template
+            .iter()
+            .map(|arg| {
+                arg.replace("{player.number}", &(i + 1).to_string())
+                    .replace("{player.username}", &player.username)
+                    .replace("{player.user_id}", &player.user_id.to_string())
+                    .replace("{player.team}", &player.team.to_string())
+            })
+            .collect();
+        args.extend(expand_args(&rendered, doc)?);
+    }
+    Ok(args)
+}
+
+/// Writes the launch document to a file only the server's user can read, in the game's scratch dir if it has one
+/// and the runtime dir otherwise
+fn write_launch_file(doc: &LaunchDocument, runtime_dir: &Path) -> Result<PathBuf, std::io::Error> {
+    let dir = doc.scratch_dir.as_ref().map(PathBuf::from).unwrap_or_else(|| runtime_dir.to_path_buf());
+    let path = dir.join(format!("match-{}-{}.json", doc.match_id, &random_token()[..8]));
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+    file.write_all(serde_json::to_string(doc)?.as_bytes())?;
+    Ok(path)
+}
+
+/// Builds the command starting the game for `doc`, and the launch file it is handed if any.
+/// The stdin of a `LaunchMode::Stdin` launch is piped, the caller writes the document to it
+pub fn build_command(doc: &LaunchDocument, config: &LaunchConfig, game: &GameDefinition) -> Result<(Command, Option<PathBuf>), std::io::Error> {
+    let mut command = Command::new(executable_path(game)?);
+    if let Some(dir) = &doc.scratch_dir {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let mut launch_file = None;
+    let extra_args = expand_args(&game.args, doc)?;
+    match config.mode {
+        LaunchMode::Argv => {
+            command.arg("-port").arg(doc.port.to_string());
+            command.args(expand_player_args(&game.player_args, doc)?);
+            command
+                .arg("-matchid")
+                .arg(doc.match_id.to_string())
+                .arg("-resulturl")
+                .arg(&doc.result_url)
+                .arg("-resultfile")
+                .arg(&doc.result_file);
+            // only the server's user can read a process's environment, anyone can read its command line
+            command.env(RESULT_SECRET_VAR, &doc.result_secret);
+            for (i, player) in doc.players.iter().enumerate() {
+                command.env(player_token_var(i + 1), &player.token);
+            }
+            if let Some(dir) = &doc.scratch_dir {
+                command.arg("-scratchdir").arg(dir);
+            }
+        }
+        LaunchMode::Stdin => {
+            command.arg("-launchstdin").arg("true").stdin(Stdio::piped());
+        }
+        LaunchMode::File => {
+            let path = write_launch_file(doc, &config.runtime_dir)?;
+            command.arg("-launchfile").arg(&path);
+            launch_file = Some(path);
+        }
+    }
+    command.args(extra_args);
+    Ok((command, launch_file))
+}
+
+/// Removes the launch file once the game is gone, the game may already have removed it after reading
+pub fn remove_launch_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path).or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) }) {
+        println!("Failed to remove launch file {}: {}", path.display(), e);
+    }
+}
+
+/// Removes the scratch dir of a game that is gone, with whatever it left there
+pub fn remove_scratch_dir(doc: &LaunchDocument) {
+    let Some(dir) = &doc.scratch_dir else {
+        return;
+    };
+    if let Err(e) = fs::remove_dir_all(dir).or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) }) {
+        println!("Failed to remove scratch dir {}: {}", dir, e);
+    }
+}
+
+/// The result a game that exited reported in its result file or, failing that, as its last line of stdout
+pub fn read_report(doc: &LaunchDocument, last_stdout_line: Option<&str>) -> Option<(OutcomeSource, GameResultReport)> {
+    take_result_file(doc)
+        .map(|report| (OutcomeSource::ResultFile, report))
+        .or_else(|| Some((OutcomeSource::Stdout, parse_report(last_stdout_line?, doc)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+
+    fn doc() -> LaunchDocument {
+        LaunchDocument {
+            match_id: 42,
+            game_type: String::from("knockout"),
+            port: 30000,
+            players: ["alice", "bob"]
+                .iter()
+                .enumerate()
+                .map(|(i, name)| LaunchPlayer {
+                    user_id: i as u64 + 1,
+                    username: name.to_string(),
+                    token: format!("token-{}", name),
+                    team: i,
+                })
+                .collect(),
+            teams: 2,
+            ports: BTreeMap::from([(String::from("game"), 30000), (String::from("query"), 31000)]),
+            settings: serde_json::json!({ "prize": 5 }),
+            result_url: String::from("http://127.0.0.1:8080/end_match"),
+            result_secret: String::from("secret"),
+            result_file: String::new(),
+            scratch_dir: None,
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn launch_args_get_the_match_and_its_ports() {
+        let expanded = expand_args(
+            &args(&["-id", "{match_id}", "-ports", "{port},{ports.query}", "-n{player_count}/{teams}"]),
+            &doc(),
+        );
+        assert_eq!(expanded.unwrap(), args(&["-id", "42", "-ports", "30000,31000", "-n2/2"]));
+    }
+
+    #[test]
+    fn launch_args_with_unknown_ports_are_rejected() {
+        assert!(expand_args(&args(&["{ports.voice}"]), &doc()).is_err());
+        assert!(expand_args(&args(&["{ports.query"]), &doc()).is_err());
+    }
+
+    #[test]
+    fn player_args_are_rendered_for_every_player() {
+        let expanded = expand_player_args(
+            &args(&["-p{player.number}", "{player.username}:{player.user_id}@{player.team}", "{port}"]),
+            &doc(),
+        );
+        assert_eq!(expanded.unwrap(), args(&["-p1", "alice:1@0", "30000", "-p2", "bob:2@1", "30000"]));
+    }
+
+    #[test]
+    fn player_args_with_unknown_ports_are_rejected() {
+        assert!(expand_player_args(&args(&["{player.username}", "{ports.voice}"]), &doc()).is_err());
+    }
+
+    fn signed(secret: &str, match_id: u32, winner: &str) -> String {
+        let report = format!(
+            r#"{{"match_id":{},"winner":"{}","duration_secs":60,"reason":"completed"}}"#,
+            match_id, winner
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(report.as_bytes());
+        format!("{} {}", hex::encode(mac.finalize().into_bytes()), report)
+    }
+
+    fn with_result_file(contents: &str) -> LaunchDocument {
+        let dir = env::temp_dir().join(format!("launch-test-{}", random_token()));
+        fs::create_dir(&dir).unwrap();
+        let mut doc = doc();
+        doc.result_file = result_file_path(doc.match_id, &dir);
+        fs::write(&doc.result_file, contents).unwrap();
+        doc
+    }
+
+    #[test]
+    fn signed_reports_for_the_match_are_accepted() {
+        let report = parse_report(&format!("{}\n", signed("secret", 42, "bob")), &doc()).unwrap();
+        assert_eq!(report.winner.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn unsigned_or_foreign_reports_are_ignored() {
+        let report = r#"{"match_id":42,"winner":"bob","duration_secs":60,"reason":"completed"}"#;
+        assert!(parse_report(report, &doc()).is_none());
+        assert!(parse_report(&format!("00 {}", report), &doc()).is_none());
+        assert!(parse_report(&signed("other secret", 42, "bob"), &doc()).is_none());
+        assert!(parse_report(&signed("secret", 43, "bob"), &doc()).is_none());
+    }
+
+    #[test]
+    fn result_file_takes_precedence_over_stdout() {
+        let doc = with_result_file(&signed("secret", 42, "alice"));
+        let (source, report) = read_report(&doc, Some(&signed("secret", 42, "bob"))).unwrap();
+        assert_eq!(source, OutcomeSource::ResultFile);
+        assert_eq!(report.winner.as_deref(), Some("alice"));
+        assert!(!Path::new(&doc.result_file).exists());
+        fs::remove_dir(Path::new(&doc.result_file).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stdout_is_read_when_the_result_file_is_missing_or_invalid() {
+        assert!(read_report(&doc(), None).is_none());
+        let (source, report) = read_report(&doc(), Some(&signed("secret", 42, "bob"))).unwrap();
+        assert_eq!(source, OutcomeSource::Stdout);
+        assert_eq!(report.winner.as_deref(), Some("bob"));
+
+        let doc = with_result_file(&signed("other secret", 42, "alice"));
+        let (source, report) = read_report(&doc, Some(&signed("secret", 42, "bob"))).unwrap();
+        assert_eq!(source, OutcomeSource::Stdout);
+        assert_eq!(report.winner.as_deref(), Some("bob"));
+        assert!(!Path::new(&doc.result_file).exists());
+        fs::remove_dir(Path::new(&doc.result_file).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn symlinked_result_files_are_not_followed() {
+        let doc = with_result_file("");
+        let target = Path::new(&doc.result_file).with_extension("target");
+        fs::write(&target, signed("secret", 42, "alice")).unwrap();
+        fs::remove_file(&doc.result_file).unwrap();
+        std::os::unix::fs::symlink(&target, &doc.result_file).unwrap();
+        assert!(read_report(&doc, None).is_none());
+        assert!(!Path::new(&doc.result_file).exists());
+        fs::remove_dir_all(target.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::now_secs;
+use crate::wallet::{SharedWallet, WalletError};
+
+const DEFAULT_ESCROW_PATH: &str = "escrow.json";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    /// Stake reserved from a player's balance into the match escrow
+    ESCROW,
+    /// Stake returned to a player that left the match before it started
+    RELEASE,
+    /// Reserved stake taken for good once the match was decided
+    CAPTURE,
+    /// Escrowed pot paid to the winner, or a share of it after a split draw
+    PAYOUT,
+    /// Stake returned because the game errored, could not be started or the server drained
+    REFUND,
+    /// Stake kept from a player kicked for not readying in time
+    FORFEIT,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Transaction {
+    pub id: u64,
+    pub match_id: u32,
+    pub user_id: u64,
+    pub kind: TransactionKind,
+    pub amount: u64,
+    pub created_at: u64,
+}
+
+/// A player's stake held in a match escrow, kept in the escrow file and the process records so a restarted server
+/// can settle it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Stake {
+    pub user_id: u64,
+    pub amount: u64,
+    pub reservation_id: String,
+}
+
+struct LedgerState {
+    escrow: BTreeMap<u32, Vec<Stake>>,
+    transactions: Vec<Transaction>,
+    next_id: u64,
+}
+
+/// Moves match stakes between player wallets and per-match escrow.
+/// Every movement is recorded as a transaction, appended to `path` as a JSON line when set.
+/// The escrow is saved to `escrow_path` whenever it changes, what a previous run left there is loaded back
+/// for `recover_processes` to settle
+pub struct Ledger {
+    wallet: SharedWallet,
+    state: Mutex<LedgerState>,
+    path: Option<String>,
+    escrow_path: Option<PathBuf>,
+}
+pub type SharedLedger = Arc<Ledger>;
+
+impl Ledger {
+    pub fn new(wallet: SharedWallet, path: Option<String>, escrow_path: Option<PathBuf>) -> Self {
+        let escrow: BTreeMap<u32, Vec<Stake>> = escrow_path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|contents| serde_json::from_str(&contents).expect("Invalid escrow file"))
+            .unwrap_or_default();
+        Self {
+            wallet,
+            state: Mutex::new(LedgerState {
+                escrow,
+                transactions: Vec::new(),
+                next_id: 1,
+            }),
+            path,
+            escrow_path,
+        }
+    }
+    pub fn from_env(wallet: SharedWallet) -> Self {
+        let escrow_path = match env::var("ESCROW_PATH") {
+            Ok(path) if path.is_empty() => None,
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_ESCROW_PATH)),
+        };
+        Self::new(wallet, env::var("LEDGER_PATH").ok().filter(|p| !p.is_empty()), escrow_path)
+    }
+    pub fn wallet(&self) -> &SharedWallet {
+        &self.wallet
+    }
+    /// Reserves `amount` of the user's balance into the match escrow, returning the reservation for `cancel_escrow`
+    pub async fn escrow(&self, match_id: u32, user_id: u64, amount: u64) -> Result<String, WalletError> {
+        let reservation_id = self.wallet.reserve(user_id, amount, &format!("match:{}", match_id)).await?;
+        let mut state = self.state.lock().unwrap();
+        state.escrow.entry(match_id).or_default().push(Stake {
+            user_id,
+            amount,
+            reservation_id: reservation_id.clone(),
+        });
+        self.save_escrow(&state);
+        self.record(&mut state, match_id, user_id, TransactionKind::ESCROW, amount);
+        Ok(reservation_id)
+    }
+    /// Returns a stake that was escrowed for a player who could not be added to the match after all
+    pub async fn cancel_escrow(&self, match_id: u32, reservation_id: &str) {
+        let stake = {
+            let mut state = self.state.lock().unwrap();
+            let Some(stakes) = state.escrow.get_mut(&match_id) else {
+                return;
+            };
+            let stake = stakes.iter().position(|s| s.reservation_id == reservation_id).map(|i| stakes.remove(i));
+            if stakes.is_empty() {
+                state.escrow.remove(&match_id);
+            }
+            self.save_escrow(&state);
+            stake
+        };
+        if let Some(stake) = stake {
+            self.release_stake(match_id, stake, TransactionKind::RELEASE).await;
+        }
+    }
+    /// Returns a single player's stake when they leave a match that has not started
+    pub async fn release(&self, match_id: u32, user_id: u64) {
+        for stake in self.take_user_stakes(match_id, user_id) {
+            self.release_stake(match_id, stake, TransactionKind::RELEASE).await;
+        }
+    }
+    /// Captures a single player's stake without paying it to anyone
+    pub async fn forfeit(&self, match_id: u32, user_id: u64) {
+        for stake in self.take_user_stakes(match_id, user_id) {
+            match self.wallet.capture(&stake.reservation_id).await {
+                Ok(()) => self.record(
+                    &mut self.state.lock().unwrap(),
+                    match_id,
+                    stake.user_id,
+                    TransactionKind::FORFEIT,
+                    stake.amount,
+                ),
+                Err(e) => println!(
+                    "Failed to forfeit {} from user {} for match {}: {:?}",
+                    stake.amount, stake.user_id, match_id, e
+                ),
+            }
+        }
+    }
+    /// Captures every stake of a match and pays the whole pot to the winner
+    pub async fn payout(&self, match_id: u32, winner_id: u64) {
+        self.split(match_id, &[winner_id]).await;
+    }
+    /// Captures every stake of a match and pays the pot to `winner_ids` in equal shares.
+    /// What doesn't divide evenly goes to the first of them, one unit each
+    pub async fn split(&self, match_id: u32, winner_ids: &[u64]) {
+        let stakes = self.take_stakes(match_id);
+        let mut pot = 0;
+        for stake in stakes {
+            match self.wallet.capture(&stake.reservation_id).await {
+                Ok(()) => {
+                    pot += stake.amount;
+                    self.record(
+                        &mut self.state.lock().unwrap(),
+                        match_id,
+                        stake.user_id,
+                        TransactionKind::CAPTURE,
+                        stake.amount,
+                    );
+                }
+                Err(e) => println!(
+                    "Failed to capture {} from user {} for match {}: {:?}",
+                    stake.amount, stake.user_id, match_id, e
+                ),
+            }
+        }
+        if pot == 0 || winner_ids.is_empty() {
+            return;
+        }
+        let share = pot / winner_ids.len() as u64;
+        let remainder = pot % winner_ids.len() as u64;
+        for (i, &winner_id) in winner_ids.iter().enumerate() {
+            let amount = share + if (i as u64) < remainder { 1 } else { 0 };
+            if amount == 0 {
+                continue;
+            }
+            match self.wallet.credit(winner_id, amount, &format!("match:{}", match_id)).await {
+                Ok(()) => self.record(&mut self.state.lock().unwrap(), match_id, winner_id, TransactionKind::PAYOUT, amount),
+                // the captures are recorded so the missing payout can be reconciled by hand
+                Err(e) => println!("Failed to pay {} to user {} for match {}: {:?}", amount, winner_id, match_id, e),
+            }
+        }
+    }
+    /// Returns every stake of a match to the player that put it in
+    pub async fn refund(&self, match_id: u32) {
+        for stake in self.take_stakes(match_id) {
+            self.release_stake(match_id, stake, TransactionKind::REFUND).await;
+        }
+    }
+    /// The stakes held for a match
+    pub fn stakes(&self, match_id: u32) -> Vec<Stake> {
+        self.state.lock().unwrap().escrow.get(&match_id).cloned().unwrap_or_default()
+    }
+    /// Matches holding stakes, including the ones a previous run of the server left in the escrow file
+    pub fn escrowed_matches(&self) -> Vec<u32> {
+        self.state.lock().unwrap().escrow.keys().copied().collect()
+    }
+    /// Puts back the stakes of a match a previous run of the server escrowed, their reservations are still open
+    pub fn restore(&self, match_id: u32, stakes: Vec<Stake>) {
+        if !stakes.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            state.escrow.insert(match_id, stakes);
+            self.save_escrow(&state);
+        }
+    }
+    pub fn transactions_for(&self, user_id: u64) -> Vec<Transaction> {
+        let state = self.state.lock().unwrap();
+        state.transactions.iter().filter(|t| t.user_id == user_id).cloned().collect()
+    }
+    fn take_user_stakes(&self, match_id: u32, user_id: u64) -> Vec<Stake> {
+        let mut state = self.state.lock().unwrap();
+        let Some(stakes) = state.escrow.get_mut(&match_id) else {
+            return Vec::new();
+        };
+        let (taken, kept): (Vec<Stake>, Vec<Stake>) = stakes.drain(..).partition(|s| s.user_id == user_id);
+        *stakes = kept;
+        if stakes.is_empty() {
+            state.escrow.remove(&match_id);
+        }
+        self.save_escrow(&state);
+        taken
+    }
+    fn take_stakes(&self, match_id: u32) -> Vec<Stake> {
+        let mut state = self.state.lock().unwrap();
+        let stakes = state.escrow.remove(&match_id).unwrap_or_default();
+        self.save_escrow(&state);
+        stakes
+    }
+    fn save_escrow(&self, state: &LedgerState) {
+        let Some(path) = &self.escrow_path else {
+            return;
+        };
+        // written aside and renamed so a crash never leaves half a file
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let written = serde_json::to_vec_pretty(&state.escrow)
+            .map_err(std::io::Error::from)
+            .and_then(|json| {
+                let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)?;
+                file.write_all(&json)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, path));
+        if let Err(e) = written {
+            println!("Failed to save escrow to {}: {}", path.display(), e);
+        }
+    }
+    async fn release_stake(&self, match_id: u32, stake: Stake, kind: TransactionKind) {
+        match self.wallet.release(&stake.reservation_id).await {
+            Ok(()) => self.record(&mut self.state.lock().unwrap(), match_id, stake.user_id, kind, stake.amount),
+            Err(e) => println!(
+                "Failed to release {} to user {} for match {}: {:?}",
+                stake.amount, stake.user_id, match_id, e
+            ),
+        }
+    }
+    fn record(&self, state: &mut LedgerState, match_id: u32, user_id: u64, kind: TransactionKind, amount: u64) {
+        let transaction = Transaction {
+            id: state.next_id,
+            match_id,
+            user_id,
+            kind,
+            amount,
+            created_at: now_secs(),
+        };
+        state.next_id += 1;
+        println!("Ledger: {:?} {} for user {} in match {}", kind, amount, user_id, match_id);
+        if let Some(path) = &self.path {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&transaction).unwrap()));
+            if let Err(e) = written {
+                println!("Failed to write transaction to {}: {}", path, e);
+            }
+        }
+        state.transactions.push(transaction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::InMemoryWallet;
+
+    async fn ledger_with_pot(match_id: u32, stakes: &[(u64, u64)]) -> Ledger {
+        let ledger = Ledger::new(Arc::new(InMemoryWallet::new(100)), None, None);
+        for &(user_id, amount) in stakes {
+            ledger.escrow(match_id, user_id, amount).await.unwrap();
+        }
+        ledger
+    }
+
+    fn paid(ledger: &Ledger, user_id: u64) -> u64 {
+        let transactions = ledger.transactions_for(user_id);
+        transactions.iter().filter(|t| t.kind == TransactionKind::PAYOUT).map(|t| t.amount).sum()
+    }
+
+    #[tokio::test]
+    async fn payout_gives_the_whole_pot_to_the_winner() {
+        let ledger = ledger_with_pot(1, &[(1, 10), (2, 10)]).await;
+        ledger.payout(1, 2).await;
+        assert_eq!(paid(&ledger, 1), 0);
+        assert_eq!(paid(&ledger, 2), 20);
+        assert_eq!(ledger.wallet().balance(1).await.unwrap(), 90);
+        assert_eq!(ledger.wallet().balance(2).await.unwrap(), 110);
+        assert!(ledger.stakes(1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn split_remainder_goes_to_the_first_winners() {
+        let ledger = ledger_with_pot(1, &[(1, 5), (2, 5), (3, 1)]).await;
+        ledger.split(1, &[3, 1, 2]).await;
+        assert_eq!(paid(&ledger, 3), 4);
+        assert_eq!(paid(&ledger, 1), 4);
+        assert_eq!(paid(&ledger, 2), 3);
+    }
+
+    #[tokio::test]
+    async fn split_of_a_small_pot_skips_empty_shares() {
+        let ledger = ledger_with_pot(1, &[(1, 1)]).await;
+        ledger.split(1, &[1, 2]).await;
+        assert_eq!(paid(&ledger, 1), 1);
+        assert!(ledger.transactions_for(2).is_empty());
+    }
+
+    #[tokio::test]
+    async fn refund_returns_every_stake() {
+        let ledger = ledger_with_pot(1, &[(1, 10), (2, 7)]).await;
+        ledger.refund(1).await;
+        assert_eq!(ledger.wallet().balance(1).await.unwrap(), 100);
+        assert_eq!(ledger.wallet().balance(2).await.unwrap(), 100);
+        assert!(ledger
+            .transactions_for(2)
+            .iter()
+            .any(|t| t.kind == TransactionKind::REFUND && t.amount == 7));
+    }
+
+    #[tokio::test]
+    async fn escrow_is_loaded_back_by_the_next_run() {
+        let wallet: SharedWallet = Arc::new(InMemoryWallet::new(100));
+        let path = env::temp_dir().join(format!("escrow-{}.json", crate::utils::random_token()));
+        let ledger = Ledger::new(wallet.clone(), None, Some(path.clone()));
+        ledger.escrow(1, 1, 10).await.unwrap();
+        ledger.escrow(2, 2, 10).await.unwrap();
+        ledger.escrow(2, 3, 5).await.unwrap();
+        ledger.release(2, 2).await;
+        ledger.escrow(3, 4, 10).await.unwrap();
+        ledger.refund(3).await;
+
+        let next = Ledger::new(wallet, None, Some(path.clone()));
+        assert_eq!(next.escrowed_matches(), vec![1, 2]);
+        let stakes = next.stakes(2);
+        assert_eq!(stakes.len(), 1);
+        assert_eq!((stakes[0].user_id, stakes[0].amount), (3, 5));
+        next.refund(2).await;
+        assert_eq!(next.wallet().balance(3).await.unwrap(), 100);
+        assert_eq!(
+            Ledger::new(Arc::new(InMemoryWallet::new(100)), None, Some(path.clone())).escrowed_matches(),
+            vec![1]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
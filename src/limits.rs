@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+use crate::outcome::OutcomeSource;
+use crate::recovery::{process_children, process_cpu_ticks};
+
+/// Seconds between the soft CPU limit (SIGXCPU) and the hard one (SIGKILL)
+const CPU_HARD_LIMIT_EXTRA_SECS: u64 = 5;
+/// How often the CPU time of a game with a `cpu_secs` limit is sampled, well within `CPU_HARD_LIMIT_EXTRA_SECS`
+const CPU_SAMPLE_MILLIS: u64 = 250;
+
+/// The type `setrlimit` takes resources as, glibc has one of its own
+#[cfg(target_env = "gnu")]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type RlimitResource = libc::c_int;
+
+/// Resource limits of a game's processes, applied after fork and before the game is executed
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GameLimits {
+    /// Address space in bytes (`RLIMIT_AS`). Allocations past it fail rather than the game being killed, so a game
+    /// going over it ends however it handles that and is not reported as `LimitExceeded::Memory`, which takes the
+    /// `memory.max` of a cgroup
+    pub memory_bytes: Option<u64>,
+    /// CPU time in seconds (`RLIMIT_CPU`), the game gets SIGXCPU once it has used it up and SIGKILL
+    /// `CPU_HARD_LIMIT_EXTRA_SECS` later
+    pub cpu_secs: Option<u64>,
+    /// Open file descriptors (`RLIMIT_NOFILE`)
+    pub open_files: Option<u64>,
+    /// Nice level from -20 to 19, going below the server's own needs `CAP_SYS_NICE`
+    pub nice: Option<i32>,
+    /// CPUs the game may run on, any when empty
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+    /// cgroup v2 directory the game is moved into, with its limits set up by the operator. The server's user must
+    /// be able to write its `cgroup.procs`. Games of any type may share one
+    pub cgroup: Option<String>,
+}
+
+/// Limit a game process was stopped by
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitExceeded {
+    /// Terminated by SIGXCPU after using up `cpu_secs`, or killed at the hard limit after ignoring it
+    CpuTime,
+    /// Killed by the OOM killer of a cgroup it had to itself
+    Memory,
+}
+
+impl LimitExceeded {
+    pub fn source(self) -> OutcomeSource {
+        match self {
+            LimitExceeded::CpuTime => OutcomeSource::CpuLimit,
+            LimitExceeded::Memory => OutcomeSource::MemoryLimit,
+        }
+    }
+}
+
+/// Sets a resource limit of the calling process, safe to call between fork and exec
+fn set_rlimit(resource: RlimitResource, soft: u64, hard: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the struct passed to it
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl GameLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if [self.memory_bytes, self.cpu_secs, self.open_files].contains(&Some(0)) {
+            return Err(String::from("limits must be above 0"));
+        }
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("nice {} is not between -20 and 19", nice));
+            }
+        }
+        if let Some(cpu) = self.cpu_affinity.iter().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize) {
+            return Err(format!("cpu {} is out of range", cpu));
+        }
+        if let Some(cgroup) = &self.cgroup {
+            if !Path::new(cgroup).is_absolute() {
+                return Err(format!("cgroup {} is not an absolute path", cgroup));
+            }
+            if !Path::new(cgroup).join("cgroup.procs").exists() {
+                println!("cgroup {} does not exist, games will fail to start", cgroup);
+            }
+        }
+        Ok(())
+    }
+    /// Makes `command` apply the limits to the game before it is executed. Failing to apply one fails the spawn
+    pub fn apply(&self, command: &mut Command) -> std::io::Result<()> {
+        if *self == GameLimits::default() {
+            return Ok(());
+        }
+        // everything is prepared here, the child may only make async-signal-safe calls
+        let cgroup_procs = self
+            .cgroup
+            .as_ref()
+            .map(|cgroup| CString::new(format!("{}/cgroup.procs", cgroup)))
+            .transpose()?;
+        let affinity = (!self.cpu_affinity.is_empty()).then(|| {
+            // SAFETY: cpu_set_t is a plain bit set, all zeroes is the empty set, and validate keeps cpus in range
+            unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                for cpu in &self.cpu_affinity {
+                    libc::CPU_SET(*cpu, &mut set);
+                }
+                set
+            }
+        });
+        let (nice, memory_bytes, cpu_secs, open_files) = (self.nice, self.memory_bytes, self.cpu_secs, self.open_files);
+        // SAFETY: the closure only makes syscalls and touches memory prepared before the fork
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(path) = &cgroup_procs {
+                    // writing 0 moves the writing process
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(set) = &affinity {
+                    if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(bytes) = memory_bytes {
+                    set_rlimit(libc::RLIMIT_AS, bytes, bytes)?;
+                }
+                if let Some(secs) = cpu_secs {
+                    set_rlimit(libc::RLIMIT_CPU, secs, secs + CPU_HARD_LIMIT_EXTRA_SECS)?;
+                }
+                if let Some(files) = open_files {
+                    set_rlimit(libc::RLIMIT_NOFILE, files, files)?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+    /// How many processes the OOM killer of the game's cgroup has killed so far
+    pub fn oom_kills(&self) -> Option<u64> {
+        let events = fs::read_to_string(Path::new(self.cgroup.as_ref()?).join("memory.events")).ok()?;
+        events.lines().find_map(|line| line.strip_prefix("oom_kill ")?.trim().parse().ok())
+    }
+    /// Starts watching a game that is about to be started, in `tenants` of its cgroup until the watch is dropped
+    pub fn watch<'a>(&'a self, tenants: &'a CgroupTenants) -> LimitWatch<'a> {
+        let (entered_before, alone) = match &self.cgroup {
+            Some(cgroup) => tenants.enter(cgroup),
+            None => (0, false),
+        };
+        LimitWatch {
+            limits: self,
+            tenants,
+            oom_kills_before: self.oom_kills(),
+            entered_before,
+            alone,
+            cpu_ticks: Arc::new(AtomicU64::new(0)),
+            sampler: None,
+        }
+    }
+}
+
+/// The games in each cgroup. The OOM kills of a cgroup are counted for all of its processes, so they are only put on
+/// a game that had its cgroup to itself
+#[derive(Default)]
+pub struct CgroupTenants {
+    cgroups: Mutex<HashMap<String, Tenancy>>,
+}
+
+#[derive(Default)]
+struct Tenancy {
+    running: usize,
+    /// Games that ever entered the cgroup, tells whether one came and went while another ran
+    entered: u64,
+}
+
+impl CgroupTenants {
+    /// Returns how many games entered the cgroup so far and whether the new one is alone in it
+    fn enter(&self, cgroup: &str) -> (u64, bool) {
+        let mut cgroups = self.cgroups.lock().unwrap();
+        let tenancy = cgroups.entry(cgroup.to_string()).or_default();
+        tenancy.running += 1;
+        tenancy.entered += 1;
+        (tenancy.entered, tenancy.running == 1)
+    }
+    fn leave(&self, cgroup: &str) {
+        if let Some(tenancy) = self.cgroups.lock().unwrap().get_mut(cgroup) {
+            tenancy.running -= 1;
+        }
+    }
+    fn entered(&self, cgroup: &str) -> u64 {
+        self.cgroups.lock().unwrap().get(cgroup).map_or(0, |tenancy| tenancy.entered)
+    }
+}
+
+/// What is known about a running game to tell which of its limits it went over, see `GameLimits::watch`
+pub struct LimitWatch<'a> {
+    limits: &'a GameLimits,
+    tenants: &'a CgroupTenants,
+    /// OOM kills of the cgroup before the game started
+    oom_kills_before: Option<u64>,
+    /// `CgroupTenants::entered` once the game entered its cgroup
+    entered_before: u64,
+    /// Whether no other game was in the cgroup when the game entered it
+    alone: bool,
+    /// The most CPU time one of the game's processes used so far, in clock ticks
+    cpu_ticks: Arc<AtomicU64>,
+    sampler: Option<JoinHandle<()>>,
+}
+
+impl LimitWatch<'_> {
+    /// Samples the CPU time of the started game `pid` until it is gone, when it has a `cpu_secs` limit. Its direct
+    /// children are sampled too, a sandboxed game runs as a child of `pid`
+    pub fn follow(&mut self, pid: u32) {
+        if self.limits.cpu_secs.is_none() {
+            return;
+        }
+        let cpu_ticks = self.cpu_ticks.clone();
+        self.sampler = Some(tokio::spawn(async move {
+            while let Some(ticks) = process_cpu_ticks(pid) {
+                let children = process_children(pid).into_iter().filter_map(process_cpu_ticks);
+                cpu_ticks.fetch_max(children.fold(ticks, u64::max), Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(CPU_SAMPLE_MILLIS)).await;
+            }
+        }));
+    }
+    fn cpu_secs(&self) -> u64 {
+        // SAFETY: sysconf only reads a configuration value
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+        self.cpu_ticks.load(Ordering::Relaxed) / ticks_per_sec
+    }
+    /// Whether no other game was in the cgroup at any time the game was
+    fn had_cgroup_to_itself(&self) -> bool {
+        let Some(cgroup) = &self.limits.cgroup else {
+            return false;
+        };
+        self.alone && self.tenants.entered(cgroup) == self.entered_before
+    }
+    /// The limit a game that exited on its own was stopped by
+    pub fn exceeded(&self, status: ExitStatus) -> Option<LimitExceeded> {
+        match status.signal()? {
+            libc::SIGXCPU if self.limits.cpu_secs.is_some() => Some(LimitExceeded::CpuTime),
+            // the kernel kills a game that ignored SIGXCPU at the hard limit
+            libc::SIGKILL if self.limits.cpu_secs.is_some_and(|secs| self.cpu_secs() >= secs) => Some(LimitExceeded::CpuTime),
+            libc::SIGKILL if self.had_cgroup_to_itself() => match (self.oom_kills_before, self.limits.oom_kills()) {
+                (Some(before), Some(after)) if after > before => Some(LimitExceeded::Memory),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Drop for LimitWatch<'_> {
+    fn drop(&mut self) {
+        if let Some(sampler) = &self.sampler {
+            sampler.abort();
+        }
+        if let Some(cgroup) = &self.limits.cgroup {
+            self.tenants.leave(cgroup);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::random_token;
+
+    fn limits(json: &str) -> GameLimits {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// A stand-in cgroup dir holding only `memory.events`
+    fn cgroup_with_oom_kills(kills: u64) -> GameLimits {
+        let dir = std::env::temp_dir().join(format!("limits-test-{}", random_token()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("memory.events"), format!("low 0\nhigh 0\nmax 4\noom 2\noom_kill {}\n", kills)).unwrap();
+        GameLimits {
+            cgroup: Some(dir.to_string_lossy().into_owned()),
+            ..GameLimits::default()
+        }
+    }
+
+    #[test]
+    fn limits_are_parsed_and_default_to_none() {
+        assert_eq!(limits("{}"), GameLimits::default());
+        let parsed = limits(r#"{ "memory_bytes": 1048576, "cpu_secs": 30, "open_files": 64, "nice": 10, "cpu_affinity": [0, 1] }"#);
+        assert_eq!(parsed.memory_bytes, Some(1048576));
+        assert_eq!(parsed.cpu_secs, Some(30));
+        assert_eq!(parsed.open_files, Some(64));
+        assert_eq!(parsed.nice, Some(10));
+        assert_eq!(parsed.cpu_affinity, vec![0, 1]);
+        assert!(parsed.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        assert!(limits(r#"{ "cpu_secs": 0 }"#).validate().is_err());
+        assert!(limits(r#"{ "nice": 20 }"#).validate().is_err());
+        assert!(limits(r#"{ "nice": -21 }"#).validate().is_err());
+        assert!(limits(r#"{ "cpu_affinity": [100000] }"#).validate().is_err());
+        assert!(limits(r#"{ "cgroup": "games/knockout" }"#).validate().is_err());
+        // a missing cgroup is only logged, the operator may create it after the catalogue is loaded
+        assert!(limits(r#"{ "cgroup": "/sys/fs/cgroup/does-not-exist" }"#).validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn rlimits_apply_to_the_game() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "ulimit -n; ulimit -t"]);
+        limits(r#"{ "cpu_secs": 30, "open_files": 64 }"#).apply(&mut command).unwrap();
+        let output = command.output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "64\n30\n");
+    }
+
+    #[test]
+    fn sigxcpu_is_the_cpu_limit_only_when_one_is_set() {
+        let tenants = CgroupTenants::default();
+        let status = ExitStatus::from_raw(libc::SIGXCPU);
+        let cpu_limited = limits(r#"{ "cpu_secs": 30 }"#);
+        assert_eq!(cpu_limited.watch(&tenants).exceeded(status), Some(LimitExceeded::CpuTime));
+        assert_eq!(GameLimits::default().watch(&tenants).exceeded(status), None);
+        assert_eq!(cpu_limited.watch(&tenants).exceeded(ExitStatus::from_raw(0)), None);
+        assert_eq!(LimitExceeded::CpuTime.source(), OutcomeSource::CpuLimit);
+    }
+
+    #[test]
+    fn sigkill_is_the_cpu_limit_once_the_game_used_its_cpu_time() {
+        let tenants = CgroupTenants::default();
+        let status = ExitStatus::from_raw(libc::SIGKILL);
+        let cpu_limited = limits(r#"{ "cpu_secs": 30 }"#);
+        let watch = cpu_limited.watch(&tenants);
+        // SAFETY: sysconf only reads a configuration value
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        watch.cpu_ticks.store(29 * ticks_per_sec, Ordering::Relaxed);
+        assert_eq!(watch.exceeded(status), None);
+        watch.cpu_ticks.store(35 * ticks_per_sec, Ordering::Relaxed);
+        assert_eq!(watch.exceeded(status), Some(LimitExceeded::CpuTime));
+    }
+
+    #[tokio::test]
+    async fn cpu_time_is_sampled_while_the_game_runs() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "while :; do :; done"]);
+        let mut child = command.spawn().unwrap();
+        let tenants = CgroupTenants::default();
+        let cpu_limited = limits(r#"{ "cpu_secs": 30 }"#);
+        let mut watch = cpu_limited.watch(&tenants);
+        watch.follow(child.id().unwrap());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        child.kill().await.unwrap();
+        assert!(watch.cpu_secs() >= 1, "sampled {} ticks", watch.cpu_ticks.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn sigkill_is_the_memory_limit_only_after_a_new_oom_kill() {
+        let limits = cgroup_with_oom_kills(2);
+        let cgroup = limits.cgroup.clone().unwrap();
+        let tenants = CgroupTenants::default();
+        let status = ExitStatus::from_raw(libc::SIGKILL);
+        let watch = limits.watch(&tenants);
+        assert_eq!(watch.exceeded(status), None);
+        fs::write(Path::new(&cgroup).join("memory.events"), "oom 3\noom_kill 3\n").unwrap();
+        assert_eq!(limits.oom_kills(), Some(3));
+        assert_eq!(watch.exceeded(status), Some(LimitExceeded::Memory));
+        assert_eq!(watch.exceeded(ExitStatus::from_raw(libc::SIGTERM)), None);
+        assert_eq!(LimitExceeded::Memory.source(), OutcomeSource::MemoryLimit);
+        fs::remove_dir_all(cgroup).unwrap();
+    }
+
+    #[test]
+    fn oom_kills_of_a_shared_cgroup_are_not_put_on_a_game() {
+        let limits = cgroup_with_oom_kills(2);
+        let cgroup = limits.cgroup.clone().unwrap();
+        let tenants = CgroupTenants::default();
+        let status = ExitStatus::from_raw(libc::SIGKILL);
+        let first = limits.watch(&tenants);
+        let second = limits.watch(&tenants);
+        fs::write(Path::new(&cgroup).join("memory.events"), "oom_kill 3\n").unwrap();
+        assert_eq!(first.exceeded(status), None);
+        assert_eq!(second.exceeded(status), None);
+        drop((first, second));
+        // nor on one that had it to itself when it started, if another one came and went meanwhile
+        let alone = limits.watch(&tenants);
+        drop(limits.watch(&tenants));
+        fs::write(Path::new(&cgroup).join("memory.events"), "oom_kill 4\n").unwrap();
+        assert_eq!(alone.exceeded(status), None);
+        drop(alone);
+        let alone = limits.watch(&tenants);
+        fs::write(Path::new(&cgroup).join("memory.events"), "oom_kill 5\n").unwrap();
+        assert_eq!(alone.exceeded(status), Some(LimitExceeded::Memory));
+        drop(alone);
+        fs::remove_dir_all(cgroup).unwrap();
+    }
+}
@@ -1,62 +1,255 @@
+use accounts::{hash_password, verify_password, AccountError, SharedUserStore, UserStore};
 use async_stream::stream;
+use catalogue::{run_catalogue_reloader, DrawPolicy, GameCatalogue, GameDefinition, SharedGameCatalogue};
 use dotenvy::dotenv;
-use error::{CannotBroadcastError, CannotJoinMatchError, IdGenerationError, InvalidInputError, NoAvailablePorts, UnauthorizedError};
-use futures::lock::Mutex;
-use getrandom;
-use info::get_max_players_for_game;
-use request::{JoinQuery, MatchRequest};
-use serde::{Deserialize, Serialize};
+use drain::{drained_result, run_drain, Drain, DrainState, SharedDrain};
+use error::{
+    CannotJoinMatchError, DrainingError, DuplicateResultError, IdGenerationError, InsufficientFundsError, InvalidInputError, InvalidMatchStateError,
+    NoAvailablePorts, PasswordHashingError, UnauthorizedError, UsernameTakenError, WalletUnavailableError,
+};
+use game_token::{GameTokens, SharedGameTokens};
+use history::{MatchHistory, SharedMatchHistory};
+use launch::{result_file_path, LaunchConfig, LaunchDocument, LaunchPlayer, SharedLaunchConfig};
+use ledger::{Ledger, SharedLedger};
+use outcome::{outcome_from_report, verify_signature, GameResultReport, MatchResult, Outcome, OutcomeSource};
+use ports::{PortLease, PortPool, SharedPortPool};
+use queue::{LaunchCapacity, LaunchQueue, LaunchSlot, SharedLaunchQueue};
+use reaper::{expired_result, run_reaper, ReaperConfig};
+use recovery::recover_processes;
+use request::{JoinQuery, LoginRequest, MatchRequest, RegisterRequest, VerifyGameTokenRequest};
+use serde::Serialize;
+use serde_json::json;
+use state::{add_player, broadcast, can_join, can_leave, can_ready, remove_player, start_ready_check, transition, MatchState, MatchUpdate};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::env;
 use std::net::Ipv4Addr;
-use std::ops::Index;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{collections::HashMap, sync::Arc};
-use tokio::process::Command;
-use tokio::sync::{watch, RwLock};
-use user::{with_user, User};
-use utils::{game_type_to_path, NumberPool, SharedNumberPool};
-use validation::{validate_can_join_match, validate_game_not_started, validate_game_type, validate_prize_amount, validate_user_in_game};
+use std::sync::Arc;
+use supervisor::{GameExit, SharedSupervisor, Supervisor};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify, RwLock};
+use user::{with_admin, with_user, Profile, User};
+use utils::{now_secs, random_token};
+use validation::{validate_display_name, validate_password, validate_user_in_game, validate_username};
+use wallet::{wallet_from_env, SharedWallet, WalletError};
 use warp::filters::sse;
 use warp::{http::StatusCode, reject::Rejection, reply::Reply, Filter};
+pub mod accounts;
+pub mod auth;
+pub mod catalogue;
+pub mod drain;
 pub mod error;
-pub mod info;
+pub mod game_token;
+pub mod history;
+pub mod launch;
+pub mod ledger;
+pub mod limits;
+pub mod outcome;
+pub mod ports;
+pub mod queue;
+pub mod reaper;
+pub mod recovery;
 pub mod request;
+pub mod sandbox;
+pub mod state;
+pub mod supervisor;
 pub mod user;
 pub mod utils;
 pub mod validation;
+pub mod wallet;
+use crate::auth::{auth_provider_from_env, SharedAuthProvider};
 use crate::error::NotFoundError;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub enum MatchState {
-    OPEN,
-    READYING,
-    PLAYING,
-}
 // must add game local url here
 #[derive(Serialize, Debug, Clone)]
 pub struct Match {
     pub id: u32,
     pub players: Vec<String>,
     #[serde(skip)]
-    pub player_tokens: Vec<String>,
+    pub player_ids: Vec<u64>,
+    /// Per-match tokens handed to the game server, minted at launch
+    #[serde(skip)]
+    pub game_tokens: Vec<String>,
+    /// Key the game server signs its `/end_match` report with, minted at launch
+    #[serde(skip)]
+    pub result_secret: Option<String>,
+    pub result: Option<MatchResult>,
     pub ready: Vec<bool>,
+    /// Team of each player, see `GameDefinition.teams`
+    pub teams: Vec<usize>,
     pub prize: u32,
     pub game_type: String,
+    /// The catalogue entry the match was created with
+    #[serde(skip)]
+    pub definition: Arc<GameDefinition>,
     pub expiry_time: u64,
+    /// The `game` port clients connect to
     pub port: u32,
+    /// Every port leased for the match by name
+    pub ports: BTreeMap<String, u32>,
+    /// Dropped when the match ends, the game process holds its own clone while it runs
+    #[serde(skip)]
+    pub port_lease: Option<Arc<PortLease>>,
     pub state: MatchState,
+    /// Position in the launch queue while `QUEUED`, 1 is next
+    pub queue_position: Option<usize>,
+    /// Players removed by the last ready check
+    pub kicked: Vec<String>,
+    /// Notified to stop the game process early
+    #[serde(skip)]
+    pub kill: Arc<Notify>,
+    /// Set when a drain stopped the game at its deadline, the match is refunded unless the game reported a result
+    #[serde(skip)]
+    pub drained: bool,
     #[serde(skip)]
-    pub state_channel: watch::Sender<(MatchState, Vec<bool>, Vec<String>, u32)>,
+    pub state_channel: watch::Sender<MatchUpdate>,
+}
+#[cfg(test)]
+impl Match {
+    /// An `OPEN` match of `definition` for `players`, with user ids from 1 and every player on team 0
+    pub fn for_tests(id: u32, definition: Arc<GameDefinition>, players: &[&str]) -> Self {
+        let players: Vec<String> = players.iter().map(|p| p.to_string()).collect();
+        let (state_channel, _) = watch::channel(MatchUpdate {
+            state: MatchState::OPEN,
+            ready: vec![false; players.len()],
+            players: players.clone(),
+            port: 0,
+            expiry_time: definition.timeouts.expiry_for(MatchState::OPEN),
+            kicked: Vec::new(),
+            ports: BTreeMap::new(),
+            queue_position: None,
+        });
+        Self {
+            id,
+            player_ids: (1..=players.len() as u64).collect(),
+            game_tokens: Vec::new(),
+            result_secret: None,
+            result: None,
+            ready: vec![false; players.len()],
+            teams: vec![0; players.len()],
+            players,
+            prize: 5,
+            game_type: definition.game_type.clone(),
+            expiry_time: definition.timeouts.expiry_for(MatchState::OPEN),
+            definition,
+            port: 0,
+            ports: BTreeMap::new(),
+            port_lease: None,
+            state: MatchState::OPEN,
+            queue_position: None,
+            kicked: Vec::new(),
+            kill: Arc::new(Notify::new()),
+            drained: false,
+            state_channel,
+        }
+    }
+}
+/// A match as seen by one of its players, including the token for its game server
+#[derive(Serialize)]
+struct MatchView<'a> {
+    #[serde(flatten)]
+    game: &'a Match,
+    game_token: Option<&'a String>,
 }
 type Matches = Arc<RwLock<HashMap<u32, Arc<RwLock<Match>>>>>;
+/// A game type clients can create matches for, as listed by `/games`
+#[derive(Serialize)]
+struct GameListing {
+    game_type: String,
+    min_players: usize,
+    max_players: usize,
+    teams: usize,
+    prizes: Vec<u32>,
+    ready_check_secs: u64,
+    /// False while a match could not get its ports
+    accepting: bool,
+    open_matches: usize,
+    playing_matches: usize,
+    #[serde(flatten)]
+    capacity: LaunchCapacity,
+}
 
-const GAME_EXPIRY_TIME_SECS: u64 = 60 * 20;
-
-async fn health_handler() -> Result<impl Reply, Rejection> {
+/// Fails while draining so load balancers stop sending players to this server
+async fn health_handler(drain: SharedDrain) -> Result<impl Reply, Rejection> {
+    if drain.is_draining() {
+        return Ok(warp::reply::with_status("Draining", StatusCode::SERVICE_UNAVAILABLE));
+    }
     Ok(warp::reply::with_status("Health check successful", StatusCode::OK))
 }
-async fn get_matches_handler(matches: Matches, user: User) -> Result<impl Reply, Rejection> {
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+    expires_at: u64,
+    user: Profile,
+}
+fn account_error_rejection(err: AccountError) -> Rejection {
+    match err {
+        AccountError::UsernameTaken => warp::reject::custom(UsernameTakenError),
+        AccountError::InvalidCredentials => warp::reject::custom(UnauthorizedError::new("invalid username or password")),
+        AccountError::Hashing => warp::reject::custom(PasswordHashingError),
+    }
+}
+fn wallet_error_rejection(err: WalletError) -> Rejection {
+    match err {
+        WalletError::InsufficientFunds => warp::reject::custom(InsufficientFundsError),
+        e => {
+            println!("Wallet error: {:?}", e);
+            warp::reject::custom(WalletUnavailableError)
+        }
+    }
+}
+async fn profile(wallet: &SharedWallet, id: u64, username: &str, display_name: &str) -> Result<Profile, Rejection> {
+    Ok(Profile {
+        id,
+        username: username.to_string(),
+        display_name: display_name.to_string(),
+        balance: wallet.balance(id).await.map_err(wallet_error_rejection)?,
+    })
+}
+async fn register_handler(users: SharedUserStore, wallet: SharedWallet, body: RegisterRequest) -> Result<impl Reply, Rejection> {
+    let display_name = body.display_name.unwrap_or_else(|| body.username.clone());
+    if !validate_username(&body.username) || !validate_password(&body.password) || !validate_display_name(&display_name) {
+        return Err(warp::reject::custom(InvalidInputError));
+    }
+    // hashing is slow, a taken name is turned down before it and the store is only locked to add the account
+    if users.read().unwrap().username_taken(&body.username) {
+        return Err(account_error_rejection(AccountError::UsernameTaken));
+    }
+    let password_hash = hash_password(body.password).await.map_err(account_error_rejection)?;
+    let account = users
+        .write()
+        .unwrap()
+        .register(&body.username, &display_name, password_hash)
+        .map_err(account_error_rejection)?;
+    println!("Registered user {} with id {}", account.username, account.id);
+    let profile = profile(&wallet, account.id, &account.username, &account.display_name).await?;
+    Ok(warp::reply::with_status(warp::reply::json(&profile), StatusCode::CREATED))
+}
+async fn login_handler(users: SharedUserStore, wallet: SharedWallet, body: LoginRequest) -> Result<impl Reply, Rejection> {
+    let credentials = users.read().unwrap().credentials(&body.username);
+    let (user_id, password_hash) = credentials.ok_or_else(|| account_error_rejection(AccountError::InvalidCredentials))?;
+    if !verify_password(body.password, password_hash).await {
+        return Err(account_error_rejection(AccountError::InvalidCredentials));
+    }
+    let login = users.write().unwrap().open_session(user_id);
+    let (token, expires_at, account) = login.map_err(account_error_rejection)?;
+    Ok(warp::reply::json(&LoginResponse {
+        token,
+        expires_at,
+        user: profile(&wallet, account.id, &account.username, &account.display_name).await?,
+    }))
+}
+async fn logout_handler(users: SharedUserStore, user: User) -> Result<impl Reply, Rejection> {
+    users.write().unwrap().logout(&user.auth_token);
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+async fn me_handler(wallet: SharedWallet, user: User) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&profile(&wallet, user.id, &user.username, &user.display_name).await?))
+}
+async fn transactions_handler(ledger: SharedLedger, user: User) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&ledger.transactions_for(user.id)))
+}
+async fn get_matches_handler(matches: Matches, _user: User) -> Result<impl Reply, Rejection> {
     let matches_read = matches.read().await;
     let mut matches_list = Vec::new();
 
@@ -66,124 +259,376 @@ async fn get_matches_handler(matches: Matches, user: User) -> Result<impl Reply,
     }
     Ok(warp::reply::json(&matches_list))
 }
-async fn get_match_handler(matches: Matches, query: JoinQuery, user: User) -> Result<impl Reply, Rejection> {
-    let matches_read = matches.read().await;
-    if let Some(game) = matches_read.get(&query.id) {
-        let game_read = game.read().await;
-        if !validate_user_in_game(&user.username, &game_read) {
-            return Err(warp::reject::custom(NotFoundError)); // need to return NotFoundError to not let user know whether or not game exists
+async fn games_handler(
+    matches: Matches,
+    catalogue: SharedGameCatalogue,
+    port_pool: SharedPortPool,
+    queue: SharedLaunchQueue,
+) -> Result<impl Reply, Rejection> {
+    let definitions: Vec<Arc<GameDefinition>> = catalogue.read().unwrap().games().filter(|g| g.enabled).cloned().collect();
+    let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+    for game in matches.read().await.values() {
+        let game = game.read().await;
+        let count = counts.entry(game.game_type.clone()).or_default();
+        match game.state {
+            MatchState::OPEN => count.0 += 1,
+            MatchState::STARTING | MatchState::PLAYING => count.1 += 1,
+            _ => {}
         }
-        Ok(warp::reply::json(&*game_read))
-    } else {
-        Err(warp::reject::custom(NotFoundError))
     }
+    let mut port_pool = port_pool.lock().unwrap();
+    let listings: Vec<GameListing> = definitions
+        .iter()
+        .map(|definition| {
+            let (open_matches, playing_matches) = counts.get(&definition.game_type).copied().unwrap_or_default();
+            GameListing {
+                game_type: definition.game_type.clone(),
+                min_players: definition.min_players,
+                max_players: definition.max_players,
+                teams: definition.teams,
+                prizes: definition.prizes.clone(),
+                ready_check_secs: definition.timeouts.ready_check_secs,
+                accepting: port_pool.can_lease(definition),
+                open_matches,
+                playing_matches,
+                capacity: queue.capacity(definition),
+            }
+        })
+        .collect();
+    Ok(warp::reply::json(&listings))
 }
-async fn create_match_handler(matches: Matches, port_pool: SharedNumberPool, new_match: MatchRequest, user: User) -> Result<impl Reply, Rejection> {
-    let mut matches_write = matches.write().await;
+async fn get_match_handler(matches: Matches, history: SharedMatchHistory, query: JoinQuery, user: User) -> Result<impl Reply, Rejection> {
+    let found = matches.read().await.get(&query.id).cloned();
+    // finished matches are looked up in the history, their game tokens are revoked by then
+    let game = match found {
+        Some(game) => game.read().await.clone(),
+        None => history
+            .read()
+            .unwrap()
+            .get(query.id)
+            .cloned()
+            .ok_or_else(|| warp::reject::custom(NotFoundError))?,
+    };
+    if !validate_user_in_game(&user.username, &game) {
+        return Err(warp::reject::custom(NotFoundError)); // need to return NotFoundError to not let user know whether or not game exists
+    }
+    let idx = game.players.iter().position(|p| *p == user.username);
+    let game_token = if game.state.is_terminal() {
+        None
+    } else {
+        idx.and_then(|i| game.game_tokens.get(i))
+    };
+    Ok(warp::reply::json(&MatchView { game: &game, game_token }))
+}
+async fn create_match_handler(
+    matches: Matches,
+    port_pool: SharedPortPool,
+    ledger: SharedLedger,
+    catalogue: SharedGameCatalogue,
+    drain: SharedDrain,
+    new_match: MatchRequest,
+    user: User,
+) -> Result<impl Reply, Rejection> {
+    if drain.is_draining() {
+        return Err(warp::reject::custom(DrainingError));
+    }
     let mut id: u32;
-    loop {
-        let mut buffer = [0u8; 4];
+    {
+        let matches_read = matches.read().await;
+        loop {
+            let mut buffer = [0u8; 4];
 
-        getrandom::fill(&mut buffer).map_err(|_| warp::reject::custom(IdGenerationError))?;
+            getrandom::fill(&mut buffer).map_err(|_| warp::reject::custom(IdGenerationError))?;
 
-        id = u32::from_ne_bytes(buffer);
+            id = u32::from_ne_bytes(buffer);
 
-        if !matches_write.contains_key(&id) {
-            break;
+            if !matches_read.contains_key(&id) {
+                break;
+            }
         }
     }
     // validate game type and prize
-    if !validate_game_type(&new_match.game_type) || !validate_prize_amount(&new_match.prize) {
-        return Err(warp::reject::custom(InvalidInputError));
-    }
-    let port = match port_pool.lock().await.get() {
-        Some(p) => p,
-        None => return Err(warp::reject::custom(NoAvailablePorts)),
+    let definition = catalogue
+        .read()
+        .unwrap()
+        .get(&new_match.game_type)
+        .filter(|d| d.enabled && d.prizes.contains(&new_match.prize))
+        .cloned()
+        .ok_or_else(|| warp::reject::custom(InvalidInputError))?;
+    let lease = PortLease::acquire(&port_pool, &definition).ok_or_else(|| warp::reject::custom(NoAvailablePorts))?;
+    let port = lease.main_port();
+    let ports = lease.ports().clone();
+    // the wallet may be a remote service, the stake is reserved before any match lock is taken
+    let reservation = match ledger.escrow(id, user.id, new_match.prize as u64).await {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            println!("Failed to escrow stake for user {}: {:?}", user.id, e);
+            return Err(wallet_error_rejection(e));
+        }
     };
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let (state_tx, _) = watch::channel((MatchState::OPEN, vec![false], vec![user.username.clone()], port));
+    let expiry_time = definition.timeouts.expiry_for(MatchState::OPEN);
+    let (state_tx, _) = watch::channel(MatchUpdate {
+        state: MatchState::OPEN,
+        ready: vec![false],
+        players: vec![user.username.clone()],
+        port,
+        expiry_time,
+        kicked: Vec::new(),
+        ports: ports.clone(),
+        queue_position: None,
+    });
     let new_match = Match {
         id,
         players: vec![user.username],
-        player_tokens: vec![user.auth_token],
+        player_ids: vec![user.id],
+        game_tokens: Vec::new(),
+        result_secret: None,
+        result: None,
         ready: vec![false],
+        teams: vec![0],
         prize: new_match.prize,
         game_type: new_match.game_type,
-        expiry_time: now + GAME_EXPIRY_TIME_SECS,
+        definition,
+        expiry_time,
+        kill: Arc::new(Notify::new()),
+        drained: false,
         state_channel: state_tx,
         port,
+        ports,
+        port_lease: Some(Arc::new(lease)),
         state: MatchState::OPEN,
+        queue_position: None,
+        kicked: Vec::new(),
     };
+    let mut matches_write = matches.write().await;
+    // checked under the lock so a drain that started never misses the match, see `run_drain`
+    let rejection = if drain.is_draining() {
+        Some(warp::reject::custom(DrainingError))
+    } else if matches_write.contains_key(&id) {
+        Some(warp::reject::custom(IdGenerationError))
+    } else {
+        None
+    };
+    if let Some(rejection) = rejection {
+        drop(matches_write);
+        ledger.cancel_escrow(id, &reservation).await;
+        return Err(rejection);
+    }
     println!("Inserting with id: {}", id);
     matches_write.insert(id, Arc::new(RwLock::new(new_match.clone())));
     Ok(warp::reply::json(&new_match))
 }
-async fn join_match_handler(matches: Matches, query: JoinQuery, user: User) -> Result<impl Reply, Rejection> {
-    let matches_read = matches.read().await;
-    if let Some(found) = matches_read.get(&query.id) {
-        let mut match_write = found.write().await;
-        if !validate_can_join_match(&match_write) {
+async fn join_match_handler(
+    matches: Matches,
+    ledger: SharedLedger,
+    drain: SharedDrain,
+    query: JoinQuery,
+    user: User,
+) -> Result<impl Reply, Rejection> {
+    let found = matches.read().await.get(&query.id).cloned();
+    let Some(found) = found else {
+        println!("Not found in id {}", query.id);
+        return Err(warp::reject::custom(NotFoundError));
+    };
+    let prize = {
+        let match_read = found.read().await;
+        if drain.is_draining() {
+            return Err(warp::reject::custom(DrainingError));
+        }
+        if !can_join(&match_read, &user.username) {
             return Err(warp::reject::custom(CannotJoinMatchError));
         }
-        match_write.players.push(user.username.clone());
-        match_write.ready.push(false);
-        match_write.player_tokens.push(user.auth_token.clone());
-        let max_players = get_max_players_for_game(&match_write.game_type);
-        if match_write.players.len() == max_players {
-            match_write.state = MatchState::READYING;
+        match_read.prize
+    };
+    // the wallet may be a remote service, the stake is reserved without holding any lock and returned
+    // if the match filled up, started or drained meanwhile
+    let reservation = match ledger.escrow(query.id, user.id, prize as u64).await {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            println!("Failed to escrow stake for user {}: {:?}", user.id, e);
+            return Err(wallet_error_rejection(e));
         }
-        match_write
-            .state_channel
-            .send((
-                match_write.state.clone(),
-                match_write.ready.clone(),
-                match_write.players.clone(),
-                match_write.port,
-            ))
-            .map_err(|err| {
-                println!("{:?}", err);
-                warp::reject::custom(CannotBroadcastError)
-            })?;
-        Ok(warp::reply::json(&*match_write))
+    };
+    let mut match_write = found.write().await;
+    let rejection = if drain.is_draining() {
+        Some(warp::reject::custom(DrainingError))
+    } else if !can_join(&match_write, &user.username) {
+        Some(warp::reject::custom(CannotJoinMatchError))
     } else {
-        println!("Not found in id {}", query.id);
-        Err(warp::reject::custom(NotFoundError))
+        None
+    };
+    if let Some(rejection) = rejection {
+        drop(match_write);
+        ledger.cancel_escrow(query.id, &reservation).await;
+        return Err(rejection);
+    }
+    // a player joining during the ready check has to ready up too before the game starts
+    add_player(&mut match_write, user.id, user.username.clone());
+    if !start_ready_check(&mut match_write).map_err(warp::reject::custom)? {
+        broadcast(&match_write);
     }
+    Ok(warp::reply::json(&*match_write))
 }
-async fn cancel_match_handler(matches: Matches, port_pool: SharedNumberPool, query: JoinQuery, user: User) -> Result<impl Reply, Rejection> {
-    let mut matches_write = matches.write().await;
-    let port: u32;
-    let mut remove: bool = false;
-    if let Some(match_data) = matches_write.get(&query.id) {
-        let mut match_data_write = match_data.write().await;
-        if !validate_user_in_game(&user.username, &match_data_write) || !validate_game_not_started(&match_data_write) {
+async fn cancel_match_handler(
+    matches: Matches,
+    ledger: SharedLedger,
+    history: SharedMatchHistory,
+    query: JoinQuery,
+    user: User,
+) -> Result<impl Reply, Rejection> {
+    let match_arc = matches
+        .read()
+        .await
+        .get(&query.id)
+        .cloned()
+        .ok_or_else(|| warp::reject::custom(NotFoundError))?;
+    let cancelled = {
+        let mut game = match_arc.write().await;
+        if !can_leave(&game, &user.username) {
             return Err(warp::reject::custom(InvalidInputError));
+        }
+        let index = game.players.iter().position(|t| *t == user.username).unwrap();
+        remove_player(&mut game, index);
+        if game.players.is_empty() {
+            transition(&mut game, MatchState::CANCELLED).map_err(warp::reject::custom)?;
+            true
+        } else if game.state == MatchState::READYING {
+            // everyone readies again, in a new ready check if the match still has enough players
+            game.ready.iter_mut().for_each(|r| *r = false);
+            transition(&mut game, MatchState::OPEN).map_err(warp::reject::custom)?;
+            start_ready_check(&mut game).map_err(warp::reject::custom)?;
+            false
         } else {
-            let index = match_data_write.players.iter().position(|t| *t == user.username).unwrap();
-            match_data_write.players.remove(index);
-            match_data_write.player_tokens.remove(index);
-            match_data_write.ready.remove(index);
-            port = match_data_write.port;
-            if match_data_write.players.len() == 0 {
-                remove = true;
-            }
+            broadcast(&game);
+            false
         }
-    } else {
-        return Err(warp::reject::custom(NotFoundError));
     };
-    if remove {
-        matches_write.remove(&query.id);
-        port_pool.lock().await.release(port);
+    // the wallet may be a remote service, the stake is returned once the match lock is released
+    ledger.release(query.id, user.id).await;
+    if cancelled {
+        retire_match(&matches, &history, query.id).await;
     }
-    return Ok(warp::reply::with_status("", StatusCode::OK));
+    Ok(warp::reply::with_status("", StatusCode::OK))
 }
 
-async fn end_match_handler() -> Result<impl Reply, Rejection> {
-    Ok(warp::reply())
+/// Removes a match that reached a terminal state from `matches` and keeps it in the history
+async fn retire_match(matches: &Matches, history: &SharedMatchHistory, id: u32) {
+    let removed = matches.write().await.remove(&id);
+    let Some(game) = removed else {
+        return;
+    };
+    let game = game.read().await.clone();
+    println!("Retired match {} in state {:?}", game.id, game.state);
+    history.write().unwrap().push(game);
+}
+/// Settles the escrow of a match exactly once and finishes it if it was starting or playing.
+/// Returns false when it was already settled
+async fn settle_match(game: &Arc<RwLock<Match>>, ledger: &SharedLedger, result: MatchResult) -> bool {
+    // decided under the match lock, which claims the settlement, the wallet may be a remote service and is only
+    // called once the lock is released
+    let (match_id, winners, next) = {
+        let mut game = game.write().await;
+        if let Some(existing) = &game.result {
+            println!("Match {} already settled by {:?}, ignoring {:?}", game.id, existing.source, result.source);
+            return false;
+        }
+        println!("Settling match {} with {:?} from {:?}", game.id, result.outcome, result.source);
+        // the players paid, the stakes are refunded when there are none
+        let (winners, next) = match result.outcome {
+            Outcome::Winner { player } if player < game.player_ids.len() => (Some(vec![game.player_ids[player]]), MatchState::FINISHED),
+            Outcome::TeamWinner { team } if game.teams.contains(&team) => {
+                let winners: Vec<u64> = game
+                    .player_ids
+                    .iter()
+                    .zip(&game.teams)
+                    .filter(|(_, t)| **t == team)
+                    .map(|(id, _)| *id)
+                    .collect();
+                (Some(winners), MatchState::FINISHED)
+            }
+            Outcome::Draw => match game.definition.draw_policy {
+                DrawPolicy::Split => (Some(game.player_ids.clone()), MatchState::FINISHED),
+                DrawPolicy::Refund => (None, MatchState::FINISHED),
+            },
+            // also a winner the match doesn't have, as an exit code may name one when fewer played
+            Outcome::Winner { .. } | Outcome::TeamWinner { .. } | Outcome::Refunded => (None, MatchState::ABORTED),
+        };
+        game.result = Some(result);
+        (game.id, winners, next)
+    };
+    match winners {
+        Some(winners) => ledger.split(match_id, &winners).await,
+        None => ledger.refund(match_id).await,
+    }
+    let mut game = game.write().await;
+    // an expired match stays expired
+    if matches!(game.state, MatchState::STARTING | MatchState::PLAYING) {
+        let _ = transition(&mut game, next);
+    }
+    true
 }
-async fn ready_handler(matches: Matches, query: JoinQuery, user: User) -> Result<impl Reply, Rejection> {
-    let matches = matches.read().await;
-    let match_arc = matches.get(&query.id).ok_or_else(|| warp::reject::custom(NotFoundError))?;
+/// Signed result callback from a game server, takes precedence over the process exit code
+async fn end_match_handler(
+    matches: Matches,
+    ledger: SharedLedger,
+    signature: String,
+    body: warp::hyper::body::Bytes,
+) -> Result<impl Reply, Rejection> {
+    let report: GameResultReport = serde_json::from_slice(&body).map_err(|_| warp::reject::custom(InvalidInputError))?;
+    let match_arc = matches
+        .read()
+        .await
+        .get(&report.match_id)
+        .cloned()
+        .ok_or_else(|| warp::reject::custom(NotFoundError))?;
+    let outcome = {
+        let game = match_arc.read().await;
+        let secret = game
+            .result_secret
+            .as_ref()
+            .ok_or_else(|| warp::reject::custom(UnauthorizedError::new("match has not launched")))?;
+        if !verify_signature(secret, &body, &signature) {
+            println!("Rejected forged result for match {}", report.match_id);
+            return Err(warp::reject::custom(UnauthorizedError::new("invalid result signature")));
+        }
+        if game.result.is_some() {
+            return Err(warp::reject::custom(DuplicateResultError));
+        }
+        outcome_from_report(&report, &game.players, &game.teams).ok_or_else(|| warp::reject::custom(InvalidInputError))?
+    };
+    let result = MatchResult {
+        outcome,
+        source: OutcomeSource::Callback,
+        report: Some(report),
+    };
+    if !settle_match(&match_arc, &ledger, result).await {
+        return Err(warp::reject::custom(DuplicateResultError));
+    }
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+/// Called by game servers to check the token a player connected with
+async fn verify_game_token_handler(game_tokens: SharedGameTokens, body: VerifyGameTokenRequest) -> Result<impl Reply, Rejection> {
+    let game_tokens = game_tokens.lock().unwrap();
+    let token = game_tokens
+        .verify(&body.token, body.match_id)
+        .ok_or_else(|| warp::reject::custom(UnauthorizedError::new("invalid game token")))?;
+    Ok(warp::reply::json(token))
+}
+/// Everything launching a match takes, shared by `/ready` and the launch queue
+pub struct Launcher {
+    matches: Matches,
+    ledger: SharedLedger,
+    history: SharedMatchHistory,
+    game_tokens: SharedGameTokens,
+    config: SharedLaunchConfig,
+    supervisor: SharedSupervisor,
+    queue: SharedLaunchQueue,
+}
+type SharedLauncher = Arc<Launcher>;
+
+async fn ready_handler(launcher: SharedLauncher, query: JoinQuery, user: User) -> Result<impl Reply, Rejection> {
+    let matches_read = launcher.matches.read().await;
+    let match_arc = matches_read.get(&query.id).ok_or_else(|| warp::reject::custom(NotFoundError))?;
     let mut game = match_arc.write().await;
 
     let idx = game
@@ -191,211 +636,686 @@ async fn ready_handler(matches: Matches, query: JoinQuery, user: User) -> Result
         .iter()
         .position(|u| u == &user.username)
         .ok_or_else(|| warp::reject::custom(InvalidInputError))?;
+    if !can_ready(&game) {
+        return Err(warp::reject::custom(InvalidMatchStateError));
+    }
 
     game.ready[idx] = true;
 
-    let all_ready = game.ready.iter().all(|&r| r);
-    if all_ready {
-        game.state = MatchState::PLAYING;
+    if !game.ready.iter().all(|&r| r) {
+        broadcast(&game);
+        return Ok(warp::reply::with_status("reply", StatusCode::OK));
     }
-    game.state_channel
-        .send((game.state.clone(), game.ready.clone(), game.players.clone(), game.port))
-        .map_err(|_| warp::reject::custom(CannotBroadcastError))?;
-    if all_ready {
-        let game_type = game.game_type.clone();
-        let port = game.port.clone();
-        let players = game.players.clone();
-        let player_tokens = game.player_tokens.clone();
-        // check that this does not block and the mutexes claimed earlier are released
-        tokio::spawn(async move {
-            let result = run_game_process(&game_type, port, &players[0], &player_tokens[0], &players[1], &player_tokens[1]).await;
-            match result {
-                Ok(exit_code) => {
-                    println!("Game process exited with code: {}", exit_code);
-                    if exit_code == 1001 {
-                        // player 1 won
-                    } else if exit_code == 1002 {
-                        // player 2 won
-                    } else {
-                        // error occurred, add back balance to both players
-                    }
+    match launcher.queue.start_or_enqueue(game.id, &game.definition) {
+        Ok(slot) => launch_match(&launcher, &mut game, match_arc.clone(), slot).map_err(warp::reject::custom)?,
+        Err(position) => {
+            println!("Match {} queued at position {}", game.id, position);
+            game.queue_position = Some(position);
+            transition(&mut game, MatchState::QUEUED).map_err(warp::reject::custom)?;
+        }
+    }
+    Ok(warp::reply::with_status("reply", StatusCode::OK))
+}
+/// Starts the game of a match whose players are all ready in its `slot` of the launch queue. The slot is given
+/// back when the game exits or the launch fails, `run_queue_dispatcher` then launches the queued matches that fit
+fn launch_match(launcher: &SharedLauncher, game: &mut Match, match_arc: Arc<RwLock<Match>>, slot: LaunchSlot) -> Result<(), InvalidMatchStateError> {
+    // tokens are minted before the match starts so players can fetch them by the time it is PLAYING. They last as
+    // long as the game may take to start and play
+    {
+        let timeouts = &game.definition.timeouts;
+        let game_ends_at = now_secs() + timeouts.startup_secs + timeouts.playing_secs;
+        let mut minted = launcher.game_tokens.lock().unwrap();
+        game.game_tokens = game
+            .player_ids
+            .iter()
+            .zip(game.players.iter())
+            .map(|(id, username)| minted.mint(game.id, *id, username, game_ends_at))
+            .collect();
+    }
+    game.result_secret = Some(random_token());
+    transition(game, MatchState::STARTING)?;
+    let match_id = game.id;
+    // a sandboxed game without one fails to launch, see `Supervisor::run`
+    let scratch_dir = game
+        .definition
+        .sandbox
+        .as_ref()
+        .and_then(|_| match launcher.supervisor.sandbox().scratch_dir(match_id) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                println!("No scratch dir for the sandboxed game of match {}: {}", match_id, e);
+                None
+            }
+        });
+    let doc = LaunchDocument {
+        match_id,
+        game_type: game.game_type.clone(),
+        port: game.port,
+        ports: game.ports.clone(),
+        players: (0..game.players.len())
+            .map(|i| LaunchPlayer {
+                user_id: game.player_ids[i],
+                username: game.players[i].clone(),
+                token: game.game_tokens[i].clone(),
+                team: game.teams[i],
+            })
+            .collect(),
+        teams: game.definition.teams,
+        settings: json!({ "prize": game.prize }),
+        result_url: launcher.config.result_url.clone(),
+        result_secret: game.result_secret.clone().unwrap_or_default(),
+        result_file: result_file_path(match_id, scratch_dir.as_deref().unwrap_or(&launcher.config.runtime_dir)),
+        scratch_dir: scratch_dir.as_ref().map(|dir| dir.to_string_lossy().into_owned()),
+    };
+    let players = game.players.clone();
+    let teams = game.teams.clone();
+    let kill = game.kill.clone();
+    // keeps the port leased until the game is gone, even if the match ends earlier
+    let lease = game.port_lease.clone();
+    let definition = game.definition.clone();
+    let stakes = launcher.ledger.stakes(match_id);
+    let launcher = launcher.clone();
+    tokio::spawn(async move {
+        // PLAYING tells players to connect, so it waits until the game accepts connections
+        let on_ready = || async {
+            let mut game = match_arc.write().await;
+            if game.state == MatchState::STARTING {
+                let _ = transition(&mut game, MatchState::PLAYING);
+            }
+        };
+        let result = launcher
+            .supervisor
+            .run(&doc, &launcher.config, &definition, stakes, &kill, on_ready)
+            .await;
+        drop(slot);
+        launcher.game_tokens.lock().unwrap().revoke_match(match_id);
+        // a result reported to /end_match wins over this one, settle_match ignores it then
+        let result = match result {
+            // whatever a game that went over its limits reported, it did not play to the end
+            Ok(GameExit {
+                limit_exceeded: Some(limit), ..
+            }) => MatchResult {
+                outcome: Outcome::Refunded,
+                source: limit.source(),
+                report: None,
+            },
+            Ok(exit) if !exit.started => {
+                println!("Game for match {} did not start, exited with code {}", match_id, exit.exit_code);
+                MatchResult {
+                    outcome: Outcome::Refunded,
+                    source: OutcomeSource::StartupFailure,
+                    report: None,
                 }
-                Err(e) => {
-                    // error occurred, add back balance to both players
-                    println!("Failed to run game process: {:?}", e);
+            }
+            Ok(exit) => {
+                println!("Game process exited with code: {}", exit.exit_code);
+                // the exit code of a game stopped by the reaper or a drain says nothing about the match
+                let (expired, drained) = {
+                    let game = match_arc.read().await;
+                    (game.state == MatchState::EXPIRED, game.drained)
+                };
+                reported_result(exit.report, match_id, &players, &teams).unwrap_or_else(|| match (expired, drained) {
+                    (true, _) => expired_result(),
+                    (_, true) => drained_result(),
+                    _ => MatchResult {
+                        outcome: definition.outcome_for_exit_code(exit.exit_code),
+                        source: OutcomeSource::ExitCode,
+                        report: None,
+                    },
+                })
+            }
+            Err(e) => {
+                println!("Failed to run game process: {:?}", e);
+                MatchResult {
+                    outcome: Outcome::Refunded,
+                    source: OutcomeSource::LaunchFailure,
+                    report: None,
                 }
             }
-        });
+        };
+        finish_game(&launcher, &match_arc, lease, result).await;
+    });
+    Ok(())
+}
+/// The result a game reported itself, `None` when it didn't or named players that are not in the match
+fn reported_result(report: Option<(OutcomeSource, GameResultReport)>, match_id: u32, players: &[String], teams: &[usize]) -> Option<MatchResult> {
+    let (source, report) = report?;
+    match outcome_from_report(&report, players, teams) {
+        Some(outcome) => Some(MatchResult {
+            outcome,
+            source,
+            report: Some(report),
+        }),
+        None => {
+            println!("Ignoring {:?} result naming players not in match {}", source, match_id);
+            None
+        }
+    }
+}
+/// Settles the match of a game that is gone and retires it. The process record is dropped only once the escrow is
+/// settled, so a restart in between still settles it
+async fn finish_game(launcher: &SharedLauncher, match_arc: &Arc<RwLock<Match>>, lease: Option<Arc<PortLease>>, result: MatchResult) {
+    let match_id = match_arc.read().await.id;
+    settle_match(match_arc, &launcher.ledger, result).await;
+    launcher.supervisor.records().remove(match_id);
+    drop(lease);
+    retire_match(&launcher.matches, &launcher.history, match_id).await;
+}
+/// Launches the queued matches that fit whenever a launch slot is given back, however the game that held it ended
+async fn run_queue_dispatcher(launcher: SharedLauncher) {
+    loop {
+        launcher.queue.slot_freed().await;
+        dispatch_queue(&launcher).await;
+    }
+}
+/// Launches the queued matches that fit now, and tells the ones still waiting their new position
+async fn dispatch_queue(launcher: &SharedLauncher) {
+    loop {
+        let startable = launcher.queue.take_startable();
+        if startable.is_empty() {
+            break;
+        }
+        for (match_id, slot) in startable {
+            // a slot that isn't used is given back when dropped
+            let Some(match_arc) = launcher.matches.read().await.get(&match_id).cloned() else {
+                continue;
+            };
+            let mut game = match_arc.write().await;
+            // the match may have expired or been cancelled by a drain since it was taken off the queue
+            if game.state == MatchState::QUEUED {
+                let _ = launch_match(launcher, &mut game, match_arc.clone(), slot);
+            }
+        }
+    }
+    broadcast_queue_positions(&launcher.matches, &launcher.queue).await;
+}
+/// Sends every queued match its position in the launch queue
+async fn broadcast_queue_positions(matches: &Matches, queue: &LaunchQueue) {
+    for (match_id, position) in queue.positions() {
+        let Some(match_arc) = matches.read().await.get(&match_id).cloned() else {
+            continue;
+        };
+        let mut game = match_arc.write().await;
+        if game.state == MatchState::QUEUED && game.queue_position != Some(position) {
+            game.queue_position = Some(position);
+            broadcast(&game);
+        }
     }
-    return Ok(warp::reply::with_status("reply", StatusCode::OK));
 }
-async fn match_ready_updates(matches: Matches, query: JoinQuery) -> Result<impl Reply, Rejection> {
+/// Running and recently exited game processes, for operators
+async fn admin_processes_handler(supervisor: SharedSupervisor) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&supervisor.statuses()))
+}
+/// Drain progress, for operators
+async fn admin_drain_status_handler(drain: SharedDrain, matches: Matches) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &json!({ "drain": drain.state(), "matches": matches.read().await.len() }),
+    ))
+}
+/// Starts draining without shutting the server down, it keeps refusing new matches until restarted
+async fn admin_drain_handler(drain: SharedDrain, matches: Matches) -> Result<impl Reply, Rejection> {
+    if drain.start() {
+        println!("Drain started by an admin");
+    }
+    admin_drain_status_handler(drain, matches).await
+}
+/// Port pool accounting, for operators
+async fn admin_ports_handler(port_pool: SharedPortPool, catalogue: SharedGameCatalogue) -> Result<impl Reply, Rejection> {
+    let catalogue = catalogue.read().unwrap();
+    let stats = port_pool.lock().unwrap().stats(catalogue.games().map(|g| g.as_ref()));
+    Ok(warp::reply::json(&stats))
+}
+/// Streams the match's updates until it ends, and a `draining` event with the drain deadline once the server drains
+async fn match_ready_updates(matches: Matches, drain: SharedDrain, query: JoinQuery) -> Result<impl Reply, Rejection> {
     let matches_read = matches.read().await;
     let match_arc = matches_read.get(&query.id).ok_or_else(|| warp::reject::custom(NotFoundError))?;
     let match_read = match_arc.read().await;
     let mut rx = match_read.state_channel.subscribe();
     drop(match_read);
     drop(matches_read);
+    let mut drain_rx = drain.subscribe();
+    // a subscriber joining during a drain hears about it right away
+    drain_rx.mark_changed();
+    let mut draining_sent = false;
     let stream = stream! {
         loop {
-            match rx.changed().await {
-                Ok(()) => {
+            // the drain starts before it cancels the match, so its event goes out before the match ends
+            tokio::select! {
+                biased;
+                changed = drain_rx.changed(), if !draining_sent => {
+                    let state = *drain_rx.borrow_and_update();
+                    if changed.is_err() {
+                        draining_sent = true;
+                    } else if let DrainState::Draining { .. } = state {
+                        draining_sent = true;
+                        yield Ok::<warp::sse::Event, warp::Error>(warp::sse::Event::default().event("draining").json_data(state).unwrap());
+                    }
+                }
+                changed = rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
                     let state = rx.borrow().clone();
-                    yield Ok::<warp::sse::Event, warp::Error>(
-                        warp::sse::Event::default().json_data(state).unwrap()
-                    )
-                },
-                Err(_) => break
+                    let terminal = state.state.is_terminal();
+                    yield Ok(warp::sse::Event::default().json_data(state).unwrap());
+                    if terminal {
+                        break;
+                    }
+                }
             }
         }
     };
     Ok(sse::reply(stream))
 }
-async fn run_game_process(
-    game_type: &String,
-    port: u32,
-    player_1: &String,
-    player_1_token: &String,
-    player_2: &String,
-    player_2_token: &String,
-) -> Result<i32, std::io::Error> {
-    // Example: Running a command as a process
-    let path = game_type_to_path(game_type);
-    let formatted = match env::var("ENVIRONMENT").ok() {
-        Some(_) => String::from("./target/release/game-simulation"),
-        None => format!("./builds/{}", path),
-    };
-    println!(
-        "Starting {} game at port {} for players {}, {} with tokens {}, {}",
-        game_type, port, player_1, player_2, player_1_token, player_2_token
-    );
-    let mut child = Command::new(formatted)
-        .arg("-port")
-        .arg(port.to_string())
-        .arg("-username1")
-        .arg(player_1)
-        .arg("-player1token")
-        .arg(player_1_token)
-        .arg("-username2")
-        .arg(player_2)
-        .arg("-player2token")
-        .arg(player_2_token)
-        .spawn()?;
-    let exit_status = child.wait().await?;
-    Ok(exit_status.code().unwrap_or(1000)) // Return the exit code, or -1 on error
-}
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    if err.is_not_found() {
+    let (message, status) = if err.is_not_found() {
         println!("Not found");
-        Ok(warp::reply::with_status("Not Found", StatusCode::NOT_FOUND))
-    } else if let Some(_) = err.find::<NotFoundError>() {
+        (String::from("Not Found"), StatusCode::NOT_FOUND)
+    } else if err.find::<NotFoundError>().is_some() {
         println!("Not found error");
-        Ok(warp::reply::with_status("Match not found", StatusCode::NOT_FOUND))
-    } else if let Some(_) = err.find::<UnauthorizedError>() {
-        println!("Unauthorized");
-        Ok(warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED))
-    } else if let Some(_) = err.find::<InvalidInputError>() {
+        (String::from("Match not found"), StatusCode::NOT_FOUND)
+    } else if let Some(e) = err.find::<UnauthorizedError>() {
+        println!("Unauthorized: {}", e.reason);
+        (format!("Unauthorized: {}", e.reason), StatusCode::UNAUTHORIZED)
+    } else if err.find::<UsernameTakenError>().is_some() {
+        println!("Username taken");
+        (String::from("Username already taken"), StatusCode::CONFLICT)
+    } else if err.find::<PasswordHashingError>().is_some() {
+        println!("Password hashing failed");
+        (String::from("Internal Server Error"), StatusCode::INTERNAL_SERVER_ERROR)
+    } else if err.find::<InvalidInputError>().is_some() {
         println!("Invalid input");
-        Ok(warp::reply::with_status("Invalid input", StatusCode::BAD_REQUEST))
-    } else if let Some(_) = err.find::<CannotJoinMatchError>() {
+        (String::from("Invalid input"), StatusCode::BAD_REQUEST)
+    } else if err.find::<InsufficientFundsError>().is_some() {
+        println!("Insufficient funds");
+        (String::from("Insufficient funds"), StatusCode::PAYMENT_REQUIRED)
+    } else if err.find::<DrainingError>().is_some() {
+        println!("Draining");
+        (
+            String::from("Server is shutting down, not accepting matches"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+    } else if err.find::<WalletUnavailableError>().is_some() {
+        println!("Wallet unavailable");
+        (String::from("Wallet unavailable"), StatusCode::SERVICE_UNAVAILABLE)
+    } else if err.find::<CannotJoinMatchError>().is_some() {
         println!("Cannot join match");
-        Ok(warp::reply::with_status("Cannot join selected match", StatusCode::FORBIDDEN))
-    } else if let Some(_) = err.find::<CannotBroadcastError>() {
-        println!("Broadcasting failed");
-        Ok(warp::reply::with_status("Broadcasting failed", StatusCode::INTERNAL_SERVER_ERROR))
-    } else if let Some(_) = err.find::<IdGenerationError>() {
+        (String::from("Cannot join selected match"), StatusCode::FORBIDDEN)
+    } else if err.find::<InvalidMatchStateError>().is_some() {
+        println!("Invalid match state");
+        (String::from("Match is not in a state that allows this"), StatusCode::CONFLICT)
+    } else if err.find::<DuplicateResultError>().is_some() {
+        println!("Duplicate result");
+        (String::from("Match already settled"), StatusCode::CONFLICT)
+    } else if err.find::<IdGenerationError>().is_some() {
         println!("Id generation failed");
-        Ok(warp::reply::with_status("ID generation failed", StatusCode::INTERNAL_SERVER_ERROR))
-    } else if let Some(_) = err.find::<NoAvailablePorts>() {
+        (String::from("ID generation failed"), StatusCode::INTERNAL_SERVER_ERROR)
+    } else if err.find::<NoAvailablePorts>().is_some() {
         println!("No available ports");
-        Ok(warp::reply::with_status("No available ports", StatusCode::INTERNAL_SERVER_ERROR))
+        (String::from("No available ports"), StatusCode::INTERNAL_SERVER_ERROR)
     } else {
         println!("Other error: {:?}", err);
-        Ok(warp::reply::with_status("Internal Server Error", StatusCode::INTERNAL_SERVER_ERROR))
-    }
+        (String::from("Internal Server Error"), StatusCode::INTERNAL_SERVER_ERROR)
+    };
+    Ok(warp::reply::with_status(message, status))
 }
 #[tokio::main]
 async fn main() {
     dotenv().ok();
     let matches: Matches = Arc::new(RwLock::new(HashMap::new()));
-    let low_port: u32 = env::var("PORT_START")
-        .unwrap_or_else(|_| "30000".to_string())
-        .parse()
-        .expect("Invalid low port");
-    let high_port: u32 = env::var("PORT_END")
-        .unwrap_or_else(|_| "31000".to_string())
-        .parse()
-        .expect("Invalid high port");
-    let port_pool: SharedNumberPool = Arc::new(Mutex::new(NumberPool::new(low_port..high_port)));
+    let port_pool: SharedPortPool = Arc::new(std::sync::Mutex::new(PortPool::from_env()));
+    let users: SharedUserStore = Arc::new(std::sync::RwLock::new(UserStore::from_env()));
+    let auth: SharedAuthProvider = auth_provider_from_env(users.clone());
+    let wallet: SharedWallet = wallet_from_env();
+    let ledger: SharedLedger = Arc::new(Ledger::from_env(wallet.clone()));
+    let game_tokens: SharedGameTokens = Arc::new(std::sync::Mutex::new(GameTokens::from_env()));
+    // admin routes reject every request when unset
+    let admin_token: Option<Arc<str>> = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()).map(Arc::from);
+    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+
+    // Parse host and port
+    let host: Ipv4Addr = host.parse().expect("Invalid HOST address");
+    let port: u16 = port.parse().expect("Invalid PORT number");
+    let launch_config: SharedLaunchConfig = Arc::new(LaunchConfig::from_env(&host.to_string(), port));
+    let supervisor: SharedSupervisor = Arc::new(Supervisor::from_env(&launch_config.runtime_dir));
+    let catalogue: SharedGameCatalogue = Arc::new(std::sync::RwLock::new(GameCatalogue::from_env(supervisor.sandbox())));
+    tokio::spawn(run_catalogue_reloader(catalogue.clone(), supervisor.clone()));
+    let history: SharedMatchHistory = Arc::new(std::sync::RwLock::new(MatchHistory::from_env()));
+    let launch_queue: SharedLaunchQueue = Arc::new(LaunchQueue::from_env());
+    tokio::spawn(run_reaper(
+        matches.clone(),
+        ledger.clone(),
+        history.clone(),
+        launch_queue.clone(),
+        ReaperConfig::from_env(),
+    ));
+    let drain: SharedDrain = Arc::new(Drain::from_env());
+    tokio::spawn(run_drain(
+        drain.clone(),
+        matches.clone(),
+        ledger.clone(),
+        history.clone(),
+        launch_queue.clone(),
+    ));
+    let launcher: SharedLauncher = Arc::new(Launcher {
+        matches: matches.clone(),
+        ledger: ledger.clone(),
+        history: history.clone(),
+        game_tokens: game_tokens.clone(),
+        config: launch_config,
+        supervisor: supervisor.clone(),
+        queue: launch_queue.clone(),
+    });
+    recover_processes(&launcher, &catalogue, &port_pool).await;
+    tokio::spawn(run_queue_dispatcher(launcher.clone()));
     fn with_matches(matches: Matches) -> impl Filter<Extract = (Matches,), Error = std::convert::Infallible> + Clone {
-        return warp::any().map(move || matches.clone()); // .clone() just cloned the ref because it is an Arc
+        warp::any().map(move || matches.clone()) // .clone() just cloned the ref because it is an Arc
+    }
+    fn with_port_pool(port_pool: SharedPortPool) -> impl Filter<Extract = (SharedPortPool,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || port_pool.clone())
+    }
+    fn with_users(users: SharedUserStore) -> impl Filter<Extract = (SharedUserStore,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || users.clone())
+    }
+    let register_route = warp::path("register")
+        .and(warp::post())
+        .and(with_users(users.clone()))
+        .and(with_wallet(wallet.clone()))
+        .and(warp::body::json())
+        .and_then(register_handler);
+    let login_route = warp::path("login")
+        .and(warp::post())
+        .and(with_users(users.clone()))
+        .and(with_wallet(wallet.clone()))
+        .and(warp::body::json())
+        .and_then(login_handler);
+    let logout_route = warp::path("logout")
+        .and(warp::post())
+        .and(with_users(users.clone()))
+        .and(with_user(auth.clone()))
+        .and_then(logout_handler);
+    fn with_ledger(ledger: SharedLedger) -> impl Filter<Extract = (SharedLedger,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || ledger.clone())
+    }
+    fn with_wallet(wallet: SharedWallet) -> impl Filter<Extract = (SharedWallet,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || wallet.clone())
+    }
+    fn with_game_tokens(game_tokens: SharedGameTokens) -> impl Filter<Extract = (SharedGameTokens,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || game_tokens.clone())
+    }
+    fn with_history(history: SharedMatchHistory) -> impl Filter<Extract = (SharedMatchHistory,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || history.clone())
+    }
+    fn with_supervisor(supervisor: SharedSupervisor) -> impl Filter<Extract = (SharedSupervisor,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || supervisor.clone())
+    }
+    fn with_launch_queue(queue: SharedLaunchQueue) -> impl Filter<Extract = (SharedLaunchQueue,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || queue.clone())
+    }
+    fn with_launcher(launcher: SharedLauncher) -> impl Filter<Extract = (SharedLauncher,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || launcher.clone())
+    }
+    fn with_drain(drain: SharedDrain) -> impl Filter<Extract = (SharedDrain,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || drain.clone())
     }
-    fn with_port_pool(port_pool: SharedNumberPool) -> impl Filter<Extract = (SharedNumberPool,), Error = std::convert::Infallible> + Clone {
-        return warp::any().map(move || port_pool.clone());
+    fn with_catalogue(catalogue: SharedGameCatalogue) -> impl Filter<Extract = (SharedGameCatalogue,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || catalogue.clone())
     }
+    let me_route = warp::path("me")
+        .and(warp::get())
+        .and(with_wallet(wallet.clone()))
+        .and(with_user(auth.clone()))
+        .and_then(me_handler);
+    let transactions_route = warp::path("transactions")
+        .and(warp::get())
+        .and(with_ledger(ledger.clone()))
+        .and(with_user(auth.clone()))
+        .and_then(transactions_handler);
     let matches_route = warp::path!("matches")
         .and(warp::get())
         .and(with_matches(matches.clone()))
-        .and(with_user())
+        .and(with_user(auth.clone()))
         .and_then(get_matches_handler);
+    let games_route = warp::path!("games")
+        .and(warp::get())
+        .and(with_matches(matches.clone()))
+        .and(with_catalogue(catalogue.clone()))
+        .and(with_port_pool(port_pool.clone()))
+        .and(with_launch_queue(launch_queue.clone()))
+        .and_then(games_handler);
     let match_route = warp::path("match")
         .and(warp::get())
         .and(with_matches(matches.clone()))
+        .and(with_history(history.clone()))
         .and(warp::query::<JoinQuery>())
-        .and(with_user())
+        .and(with_user(auth.clone()))
         .and_then(get_match_handler);
     let create_match_route = warp::path("create")
         .and(warp::post())
         .and(with_matches(matches.clone()))
         .and(with_port_pool(port_pool.clone()))
+        .and(with_ledger(ledger.clone()))
+        .and(with_catalogue(catalogue.clone()))
+        .and(with_drain(drain.clone()))
         .and(warp::body::json())
-        .and(with_user())
+        .and(with_user(auth.clone()))
         .and_then(create_match_handler);
     let join_match_route = warp::path("join")
         .and(warp::post())
         .and(with_matches(matches.clone()))
+        .and(with_ledger(ledger.clone()))
+        .and(with_drain(drain.clone()))
         .and(warp::query::<JoinQuery>()) // Use struct instead of raw u64
-        .and(with_user())
+        .and(with_user(auth.clone()))
         .and_then(join_match_handler);
     let cancel_match_route = warp::path("cancel")
         .and(warp::post())
         .and(with_matches(matches.clone()))
-        .and(with_port_pool(port_pool.clone()))
+        .and(with_ledger(ledger.clone()))
+        .and(with_history(history.clone()))
         .and(warp::query::<JoinQuery>())
-        .and(with_user())
+        .and(with_user(auth.clone()))
         .and_then(cancel_match_handler);
     let ready_route = warp::path("ready")
         .and(warp::post())
-        .and(with_matches(matches.clone()))
+        .and(with_launcher(launcher.clone()))
         .and(warp::query::<JoinQuery>())
-        .and(with_user())
+        .and(with_user(auth.clone()))
         .and_then(ready_handler);
     let match_updates_route = warp::path("updates")
         .and(warp::get())
         .and(with_matches(matches.clone()))
+        .and(with_drain(drain.clone()))
         .and(warp::query::<JoinQuery>())
         .and_then(match_ready_updates);
-    let end_match_route = warp::path("end_match").and(warp::post()).and_then(end_match_handler);
-    let health_route = warp::path("health").and(warp::get()).and_then(health_handler);
-    let routes = matches_route
-        .or(match_route)
-        .or(create_match_route)
-        .or(join_match_route)
-        .or(cancel_match_route)
-        .or(end_match_route)
-        .or(ready_route)
-        .or(match_updates_route)
-        .or(health_route)
+    let end_match_route = warp::path("end_match")
+        .and(warp::post())
+        .and(with_matches(matches.clone()))
+        .and(with_ledger(ledger.clone()))
+        .and(warp::header::<String>("X-Signature"))
+        .and(warp::body::bytes())
+        .and_then(end_match_handler);
+    let verify_game_token_route = warp::path!("game" / "verify")
+        .and(warp::post())
+        .and(with_game_tokens(game_tokens.clone()))
+        .and(warp::body::json())
+        .and_then(verify_game_token_handler);
+    let admin_ports_route = warp::path!("admin" / "ports")
+        .and(warp::get())
+        .and(with_admin(admin_token.clone()))
+        .and(with_port_pool(port_pool.clone()))
+        .and(with_catalogue(catalogue.clone()))
+        .and_then(admin_ports_handler);
+    let admin_processes_route = warp::path!("admin" / "processes")
+        .and(warp::get())
+        .and(with_admin(admin_token.clone()))
+        .and(with_supervisor(supervisor.clone()))
+        .and_then(admin_processes_handler);
+    let admin_drain_status_route = warp::path!("admin" / "drain")
+        .and(warp::get())
+        .and(with_admin(admin_token.clone()))
+        .and(with_drain(drain.clone()))
+        .and(with_matches(matches.clone()))
+        .and_then(admin_drain_status_handler);
+    let admin_drain_route = warp::path!("admin" / "drain")
+        .and(warp::post())
+        .and(with_admin(admin_token.clone()))
+        .and(with_drain(drain.clone()))
+        .and(with_matches(matches.clone()))
+        .and_then(admin_drain_handler);
+    let health_route = warp::path("health")
+        .and(warp::get())
+        .and(with_drain(drain.clone()))
+        .and_then(health_handler);
+    let routes = register_route
+        .or(login_route)
+        .or(logout_route)
+        .or(me_route)
+        .or(transactions_route)
+        .or(matches_route
+            .or(games_route)
+            .or(match_route)
+            .or(create_match_route)
+            .or(join_match_route)
+            .or(cancel_match_route)
+            .or(end_match_route)
+            .or(verify_game_token_route)
+            .or(ready_route)
+            .or(match_updates_route)
+            .or(admin_ports_route)
+            .or(admin_processes_route)
+            .or(admin_drain_status_route)
+            .or(admin_drain_route)
+            .or(health_route))
         .recover(handle_rejection);
 
-    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    println!("Starting server at {}:{}", host, port);
 
-    // Parse host and port
-    let host: Ipv4Addr = host.parse().expect("Invalid HOST address");
-    let port: u16 = port.parse().expect("Invalid PORT number");
+    // SIGTERM (systemctl stop/restart) drains before the server exits, an admin drain leaves it running
+    let shutdown = async move {
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        sigterm.recv().await;
+        println!("Received SIGTERM");
+        drain.start();
+        drain.drained().await;
+    };
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown((host, port), shutdown);
+    server.await;
+    println!("Server stopped");
+}
 
-    println!("Starting server at {}:{}", host, port);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::TransactionKind;
+    use crate::wallet::InMemoryWallet;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    const SECRET: &str = "result-secret";
+
+    /// A `PLAYING` match 1 between alice and bobby who staked 10 each
+    async fn playing_match() -> (Matches, SharedLedger) {
+        let ledger: SharedLedger = Arc::new(Ledger::new(Arc::new(InMemoryWallet::new(100)), None, None));
+        let mut game = Match::for_tests(1, GameDefinition::for_tests(""), &["alice", "bobby"]);
+        for user_id in &game.player_ids {
+            ledger.escrow(1, *user_id, 10).await.unwrap();
+        }
+        game.result_secret = Some(SECRET.to_string());
+        game.state = MatchState::PLAYING;
+        let matches: Matches = Arc::new(RwLock::new(HashMap::new()));
+        matches.write().await.insert(1, Arc::new(RwLock::new(game)));
+        (matches, ledger)
+    }
+
+    fn report(winner: &str) -> String {
+        json!({ "match_id": 1, "winner": winner, "duration_secs": 60, "reason": "completed" }).to_string()
+    }
+
+    fn sign(secret: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    async fn end_match(matches: &Matches, ledger: &SharedLedger, signature: String, body: String) -> Result<(), Rejection> {
+        end_match_handler(matches.clone(), ledger.clone(), signature, body.into())
+            .await
+            .map(|_| ())
+    }
 
-    warp::serve(routes).run((host, port)).await;
+    async fn state_of(matches: &Matches) -> (MatchState, bool) {
+        let game = matches.read().await[&1].read().await.clone();
+        (game.state, game.result.is_some())
+    }
+
+    fn paid(ledger: &SharedLedger, user_id: u64) -> u64 {
+        let transactions = ledger.transactions_for(user_id);
+        transactions.iter().filter(|t| t.kind == TransactionKind::PAYOUT).map(|t| t.amount).sum()
+    }
+
+    #[tokio::test]
+    async fn forged_results_are_rejected() {
+        let (matches, ledger) = playing_match().await;
+        let body = report("alice");
+        let forged = end_match(&matches, &ledger, sign("guessed-secret", &body), body.clone()).await;
+        assert!(forged.err().unwrap().find::<UnauthorizedError>().is_some());
+        // signed for another body
+        let tampered = end_match(&matches, &ledger, sign(SECRET, &report("bobby")), body).await;
+        assert!(tampered.err().unwrap().find::<UnauthorizedError>().is_some());
+        assert_eq!(state_of(&matches).await, (MatchState::PLAYING, false));
+        assert_eq!(ledger.stakes(1).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_match_is_settled_by_its_first_result_only() {
+        let (matches, ledger) = playing_match().await;
+        let body = report("alice");
+        assert!(end_match(&matches, &ledger, sign(SECRET, &body), body).await.is_ok());
+        assert_eq!(state_of(&matches).await, (MatchState::FINISHED, true));
+        assert_eq!(paid(&ledger, 1), 20);
+
+        let body = report("bobby");
+        let second = end_match(&matches, &ledger, sign(SECRET, &body), body).await;
+        assert!(second.err().unwrap().find::<DuplicateResultError>().is_some());
+        // nor by the exit code of the game once it is gone
+        let exit = MatchResult {
+            outcome: Outcome::Winner { player: 1 },
+            source: OutcomeSource::ExitCode,
+            report: None,
+        };
+        assert!(!settle_match(&matches.read().await[&1], &ledger, exit).await);
+        assert_eq!((paid(&ledger, 1), paid(&ledger, 2)), (20, 0));
+    }
+
+    #[tokio::test]
+    async fn results_naming_players_outside_the_match_are_rejected() {
+        let (matches, ledger) = playing_match().await;
+        let body = report("mallory");
+        let rejected = end_match(&matches, &ledger, sign(SECRET, &body), body).await;
+        assert!(rejected.err().unwrap().find::<InvalidInputError>().is_some());
+        assert_eq!(state_of(&matches).await, (MatchState::PLAYING, false));
+        assert_eq!(ledger.stakes(1).len(), 2);
+
+        // the same report read from a result file is ignored, the exit code decides
+        let foreign: GameResultReport = serde_json::from_str(&report("mallory")).unwrap();
+        let players = [String::from("alice"), String::from("bobby")];
+        assert!(reported_result(Some((OutcomeSource::ResultFile, foreign)), 1, &players, &[0, 0]).is_none());
+        let reported: GameResultReport = serde_json::from_str(&report("bobby")).unwrap();
+        let result = reported_result(Some((OutcomeSource::ResultFile, reported)), 1, &players, &[0, 0]).unwrap();
+        assert_eq!(result.outcome, Outcome::Winner { player: 1 });
+    }
+
+    #[tokio::test]
+    async fn results_for_matches_that_did_not_launch_are_rejected() {
+        let (matches, ledger) = playing_match().await;
+        matches.read().await[&1].write().await.result_secret = None;
+        let body = report("alice");
+        let rejected = end_match(&matches, &ledger, sign(SECRET, &body), body).await;
+        assert!(rejected.err().unwrap().find::<UnauthorizedError>().is_some());
+        let unknown = json!({ "match_id": 2, "winner": "alice", "duration_secs": 60, "reason": "completed" }).to_string();
+        let rejected = end_match(&matches, &ledger, sign(SECRET, &unknown), unknown).await;
+        assert!(rejected.err().unwrap().find::<NotFoundError>().is_some());
+    }
 }
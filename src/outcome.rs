@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultReason {
+    /// The game was played to the end
+    Completed,
+    /// A player gave up
+    Forfeit,
+    /// A player disconnected and did not come back
+    Disconnect,
+    /// The game ran out of time
+    Timeout,
+    /// Nobody won, `winner` must be `None`. Settled according to the game's `draw_policy`
+    Draw,
+    /// The game could not be decided, stakes are refunded
+    Error,
+}
+
+/// Result a game server reports to `/end_match`, signed with the match's result secret.
+/// The game process can also write it to its result file or as its last line of stdout, see `launch::read_report`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameResultReport {
+    pub match_id: u32,
+    /// Username of the winner, `None` when nobody won
+    pub winner: Option<String>,
+    /// Index of the winning team in team games, instead of `winner`
+    #[serde(default)]
+    pub winning_team: Option<usize>,
+    #[serde(default)]
+    pub scores: HashMap<String, i64>,
+    pub duration_secs: u64,
+    pub reason: ResultReason,
+}
+
+/// How a match was decided, also how the catalogue gives meaning to exit codes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "outcome")]
+pub enum Outcome {
+    /// Index into `Match.players` of the winner
+    Winner { player: usize },
+    /// Every player on the team shares the pot, see `Match.teams`
+    TeamWinner { team: usize },
+    /// The game ended even, the pot is split or refunded according to the game's `draw_policy`
+    Draw,
+    /// The game could not be decided, every stake goes back
+    Refunded,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeSource {
+    /// Signed report posted to `/end_match`
+    Callback,
+    /// Report written to the result file handed to the game at launch
+    ResultFile,
+    /// Report printed as the last line of the game's stdout
+    Stdout,
+    /// Exit code of the game process
+    ExitCode,
+    /// The game process could not be started or waited on
+    LaunchFailure,
+    /// The game exited or was stopped before it accepted connections
+    StartupFailure,
+    /// The game used up its CPU time limit, see `limits::GameLimits`
+    CpuLimit,
+    /// The game was killed for going over the memory of its cgroup
+    MemoryLimit,
+    /// The match timed out, see `reaper`
+    Expired,
+    /// The server drained and stopped the game at the drain deadline, see `drain`
+    Drained,
+    /// The game outlived a restart of the server and ended without reporting a result, see `recovery`
+    Orphaned,
+}
+
+/// Final result of a match, kept on the match once it is settled
+#[derive(Serialize, Debug, Clone)]
+pub struct MatchResult {
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    pub source: OutcomeSource,
+    pub report: Option<GameResultReport>,
+}
+
+/// Outcome of a report for a match between `players` on `teams`, `None` when it names someone who is not playing,
+/// a team nobody is on, both a winner and a winning team, or is a draw with a winner
+pub fn outcome_from_report(report: &GameResultReport, players: &[String], teams: &[usize]) -> Option<Outcome> {
+    if report.scores.keys().any(|p| !players.contains(p)) {
+        return None;
+    }
+    match (&report.winner, report.winning_team, report.reason) {
+        (_, _, ResultReason::Error) => Some(Outcome::Refunded),
+        (None, None, ResultReason::Draw) => Some(Outcome::Draw),
+        (_, _, ResultReason::Draw) | (Some(_), Some(_), _) => None,
+        (None, None, _) => Some(Outcome::Refunded),
+        (None, Some(team), _) => teams.contains(&team).then_some(Outcome::TeamWinner { team }),
+        (Some(winner), None, _) => players.iter().position(|p| p == winner).map(|player| Outcome::Winner { player }),
+    }
+}
+
+/// Checks a hex encoded HMAC-SHA256 signature of `body` in constant time
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(winner: Option<&str>, reason: ResultReason) -> GameResultReport {
+        GameResultReport {
+            match_id: 1,
+            winner: winner.map(String::from),
+            winning_team: None,
+            scores: HashMap::new(),
+            duration_secs: 60,
+            reason,
+        }
+    }
+
+    fn players() -> Vec<String> {
+        vec![String::from("alice"), String::from("bob")]
+    }
+
+    #[test]
+    fn winner_is_the_index_of_the_reported_player() {
+        let outcome = outcome_from_report(&report(Some("bob"), ResultReason::Completed), &players(), &[0, 1]);
+        assert_eq!(outcome, Some(Outcome::Winner { player: 1 }));
+    }
+
+    #[test]
+    fn reports_naming_someone_else_are_rejected() {
+        assert_eq!(
+            outcome_from_report(&report(Some("mallory"), ResultReason::Completed), &players(), &[0, 1]),
+            None
+        );
+        let mut scored = report(Some("alice"), ResultReason::Completed);
+        scored.scores.insert(String::from("mallory"), 3);
+        assert_eq!(outcome_from_report(&scored, &players(), &[0, 1]), None);
+    }
+
+    #[test]
+    fn undecided_matches_are_refunded() {
+        assert_eq!(
+            outcome_from_report(&report(None, ResultReason::Timeout), &players(), &[0, 1]),
+            Some(Outcome::Refunded)
+        );
+        assert_eq!(
+            outcome_from_report(&report(Some("alice"), ResultReason::Error), &players(), &[0, 1]),
+            Some(Outcome::Refunded)
+        );
+    }
+
+    #[test]
+    fn draws_have_no_winner() {
+        assert_eq!(
+            outcome_from_report(&report(None, ResultReason::Draw), &players(), &[0, 1]),
+            Some(Outcome::Draw)
+        );
+        assert_eq!(outcome_from_report(&report(Some("alice"), ResultReason::Draw), &players(), &[0, 1]), None);
+        let mut team_draw = report(None, ResultReason::Draw);
+        team_draw.winning_team = Some(0);
+        assert_eq!(outcome_from_report(&team_draw, &players(), &[0, 1]), None);
+    }
+
+    #[test]
+    fn winning_team_must_have_players() {
+        let mut team_win = report(None, ResultReason::Completed);
+        team_win.winning_team = Some(1);
+        assert_eq!(outcome_from_report(&team_win, &players(), &[0, 1]), Some(Outcome::TeamWinner { team: 1 }));
+        assert_eq!(outcome_from_report(&team_win, &players(), &[0, 0]), None);
+        team_win.winner = Some(String::from("bob"));
+        assert_eq!(outcome_from_report(&team_win, &players(), &[0, 1]), None);
+    }
+
+    #[test]
+    fn signatures_are_checked_against_the_body() {
+        let mut mac = HmacSha256::new_from_slice(b"secret").unwrap();
+        mac.update(b"body");
+        let signature = hex::encode(mac.finalize().into_bytes());
+        assert!(verify_signature("secret", b"body", &signature));
+        assert!(!verify_signature("secret", b"other body", &signature));
+        assert!(!verify_signature("other secret", b"body", &signature));
+        assert!(!verify_signature("secret", b"body", "not hex"));
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fmt;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::catalogue::GameDefinition;
+use crate::utils::now_secs;
+
+const DEFAULT_PORT_COOLDOWN_SECS: u64 = 60;
+const DEFAULT_PORT_QUARANTINE_SECS: u64 = 300;
+
+/// A named port a game type needs, such as its game port or a query port, and the range it is picked from
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortRequirement {
+    pub name: String,
+    pub range: Range<u32>,
+}
+
+/// Ports game servers can be started on. Each game type declares the named ports it needs in the catalogue and
+/// a match gets all of them as one `PortLease`, which puts them back when dropped. Ranges may
+/// overlap, a port is only ever leased once. A returned port cools down before it is leased
+/// again, and a port some other process holds is quarantined instead of being leased
+pub struct PortPool {
+    leased: BTreeSet<u32>,
+    /// Returned ports and when they can be leased again
+    cooling: BTreeMap<u32, u64>,
+    quarantined: BTreeMap<u32, Quarantine>,
+    /// Where the next search in each range starts, so recently returned ports are picked last
+    cursors: HashMap<(u32, u32), u32>,
+    cooldown_secs: u64,
+    quarantine_secs: u64,
+}
+pub type SharedPortPool = Arc<Mutex<PortPool>>;
+
+#[derive(Serialize, Clone)]
+pub struct Quarantine {
+    pub reason: String,
+    /// When the port is probed again
+    pub until: u64,
+}
+
+#[derive(Serialize)]
+pub struct CoolingPort {
+    pub port: u32,
+    pub until: u64,
+}
+
+#[derive(Serialize)]
+pub struct QuarantinedPort {
+    pub port: u32,
+    #[serde(flatten)]
+    pub quarantine: Quarantine,
+}
+
+#[derive(Serialize)]
+pub struct RangeStats {
+    pub game_type: String,
+    pub name: String,
+    pub start: u32,
+    /// Inclusive
+    pub end: u32,
+    pub free: usize,
+}
+
+/// What `/admin/ports` reports
+#[derive(Serialize)]
+pub struct PortPoolStats {
+    pub ranges: Vec<RangeStats>,
+    pub leased: Vec<u32>,
+    pub cooling: Vec<CoolingPort>,
+    pub quarantined: Vec<QuarantinedPort>,
+}
+
+/// Checks nothing holds the port over TCP or UDP. Mirror's kcp2k transport listens on UDP
+fn probe(port: u32) -> Result<(), String> {
+    let port = u16::try_from(port).map_err(|_| String::from("not a valid port"))?;
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).map_err(|e| format!("tcp: {}", e))?;
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).map_err(|e| format!("udp: {}", e))?;
+    Ok(())
+}
+
+/// Parses `30000-30999`, the end is inclusive
+pub fn parse_port_range(spec: &str) -> Result<Range<u32>, String> {
+    let invalid = || format!("invalid port range {}, expected start-end", spec);
+    let (start, end) = spec.split_once('-').ok_or_else(invalid)?;
+    let start: u32 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u32 = end.trim().parse().map_err(|_| invalid())?;
+    port_range(start, end).map_err(|_| invalid())
+}
+
+/// The ports from `start` to `end`, both included like the ranges of `parse_port_range`
+pub fn port_range(start: u32, end: u32) -> Result<Range<u32>, String> {
+    if start > end || end > u16::MAX as u32 {
+        return Err(format!("invalid port range {}-{}", start, end));
+    }
+    Ok(start..end + 1)
+}
+
+impl PortPool {
+    pub fn new(cooldown_secs: u64, quarantine_secs: u64) -> Self {
+        Self {
+            leased: BTreeSet::new(),
+            cooling: BTreeMap::new(),
+            quarantined: BTreeMap::new(),
+            cursors: HashMap::new(),
+            cooldown_secs,
+            quarantine_secs,
+        }
+    }
+    pub fn from_env() -> Self {
+        let secs = |key: &str, default: u64| {
+            env::var(key)
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {}", key)))
+                .unwrap_or(default)
+        };
+        Self::new(
+            secs("PORT_COOLDOWN_SECS", DEFAULT_PORT_COOLDOWN_SECS),
+            secs("PORT_QUARANTINE_SECS", DEFAULT_PORT_QUARANTINE_SECS),
+        )
+    }
+    /// Accounting of every leased, cooling and quarantined port, and how many ports of each game's ranges are free
+    pub fn stats<'a>(&mut self, games: impl Iterator<Item = &'a GameDefinition>) -> PortPoolStats {
+        self.promote();
+        let mut ranges = Vec::new();
+        for game in games {
+            for requirement in &game.ports {
+                ranges.push(RangeStats {
+                    game_type: game.game_type.clone(),
+                    name: requirement.name.clone(),
+                    start: requirement.range.start,
+                    end: requirement.range.end.saturating_sub(1),
+                    free: requirement.range.clone().filter(|p| self.is_free(*p)).count(),
+                });
+            }
+        }
+        PortPoolStats {
+            ranges,
+            leased: self.leased.iter().copied().collect(),
+            cooling: self.cooling.iter().map(|(&port, &until)| CoolingPort { port, until }).collect(),
+            quarantined: self
+                .quarantined
+                .iter()
+                .map(|(&port, quarantine)| QuarantinedPort {
+                    port,
+                    quarantine: quarantine.clone(),
+                })
+                .collect(),
+        }
+    }
+    /// Whether every port `game` declares has a free one left, without probing them
+    pub fn can_lease(&mut self, game: &GameDefinition) -> bool {
+        self.promote();
+        game.ports.iter().all(|requirement| requirement.range.clone().any(|p| self.is_free(p)))
+    }
+    fn is_free(&self, port: u32) -> bool {
+        !self.leased.contains(&port) && !self.cooling.contains_key(&port) && !self.quarantined.contains_key(&port)
+    }
+    /// Ends cooldowns and quarantines that are over
+    fn promote(&mut self) {
+        let now = now_secs();
+        self.cooling.retain(|_, until| *until > now);
+        self.quarantined.retain(|_, q| q.until > now);
+    }
+    /// Leases one free port of `range` that passes the bind probe
+    fn take(&mut self, range: &Range<u32>) -> Option<u32> {
+        let len = range.end.saturating_sub(range.start);
+        let cursor = self.cursors.get(&(range.start, range.end)).copied().unwrap_or(0);
+        for offset in 0..len {
+            let port = range.start + (cursor + offset) % len;
+            if !self.is_free(port) {
+                continue;
+            }
+            match probe(port) {
+                Ok(()) => {
+                    self.leased.insert(port);
+                    self.cursors.insert((range.start, range.end), (cursor + offset + 1) % len);
+                    return Some(port);
+                }
+                Err(reason) => {
+                    println!("Port {} is in use ({}), quarantined for {}s", port, reason, self.quarantine_secs);
+                    self.quarantined.insert(
+                        port,
+                        Quarantine {
+                            reason,
+                            until: now_secs() + self.quarantine_secs,
+                        },
+                    );
+                }
+            }
+        }
+        None
+    }
+    /// Leases every port `game` declares, or none of them
+    fn take_all(&mut self, game: &GameDefinition) -> Option<BTreeMap<String, u32>> {
+        self.promote();
+        let mut ports = BTreeMap::new();
+        for requirement in &game.ports {
+            match self.take(&requirement.range) {
+                Some(port) => {
+                    ports.insert(requirement.name.clone(), port);
+                }
+                None => {
+                    println!("No free {} port for {}", requirement.name, game.game_type);
+                    // never used, so they skip the cooldown
+                    for port in ports.values() {
+                        self.leased.remove(port);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(ports)
+    }
+    /// Leases ports a game is already running on, without probing them
+    fn claim(&mut self, ports: &BTreeMap<String, u32>) {
+        for port in ports.values() {
+            self.cooling.remove(port);
+            self.quarantined.remove(port);
+            if !self.leased.insert(*port) {
+                println!("Port {} was claimed but is already leased", port);
+            }
+        }
+    }
+    fn release(&mut self, port: u32) {
+        if self.leased.remove(&port) {
+            // the last game server on it may still have sockets in TIME_WAIT
+            self.cooling.insert(port, now_secs() + self.cooldown_secs);
+        } else {
+            println!("Port {} was released but is not leased", port);
+        }
+    }
+}
+
+/// The ports owned by a match, by name. Shared between the match and its game process, so the
+/// ports are only free again once both are done with them
+pub struct PortLease {
+    ports: BTreeMap<String, u32>,
+    pool: SharedPortPool,
+}
+
+impl PortLease {
+    pub fn acquire(pool: &SharedPortPool, game: &GameDefinition) -> Option<Self> {
+        let ports = pool.lock().unwrap().take_all(game)?;
+        Some(Self { ports, pool: pool.clone() })
+    }
+    /// Takes over the ports of a game started by a previous run of the server, see `recovery`
+    pub fn claim(pool: &SharedPortPool, ports: BTreeMap<String, u32>) -> Self {
+        pool.lock().unwrap().claim(&ports);
+        Self { ports, pool: pool.clone() }
+    }
+    pub fn ports(&self) -> &BTreeMap<String, u32> {
+        &self.ports
+    }
+    /// The `game` port, or the first one by name for games without it. Clients connect to this one
+    pub fn main_port(&self) -> u32 {
+        self.ports.get("game").or_else(|| self.ports.values().next()).copied().unwrap_or_default()
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        for port in self.ports.values() {
+            pool.release(*port);
+        }
+        println!("Released ports {:?}", self.ports);
+    }
+}
+
+impl fmt::Debug for PortLease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortLease").field("ports", &self.ports).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalogue::GameCatalogue;
+
+    /// A game leasing its ports from `ports`, by name
+    fn game(ports: &str) -> Arc<GameDefinition> {
+        let catalogue = format!(
+            r#"{{ "games": {{ "test": {{ "min_players": 2, "max_players": 2, "executable": "/bin/true", "prizes": [5], "ports": {} }} }} }}"#,
+            ports
+        );
+        GameCatalogue::parse(&catalogue, &(0..0)).unwrap().get("test").unwrap().clone()
+    }
+
+    fn pool(cooldown_secs: u64) -> SharedPortPool {
+        Arc::new(Mutex::new(PortPool::new(cooldown_secs, 300)))
+    }
+
+    #[test]
+    fn dropping_a_lease_returns_its_ports() {
+        let pool = pool(0);
+        let game = game(r#"{ "game": "47100-47101" }"#);
+        let lease = PortLease::acquire(&pool, &game).unwrap();
+        let port = lease.main_port();
+        assert!((47100..=47101).contains(&port));
+        assert_eq!(pool.lock().unwrap().leased.iter().copied().collect::<Vec<_>>(), vec![port]);
+        drop(lease);
+        assert!(pool.lock().unwrap().leased.is_empty());
+        assert!(pool.lock().unwrap().can_lease(&game));
+    }
+
+    #[test]
+    fn a_shared_lease_is_returned_by_its_last_holder() {
+        let pool = pool(0);
+        let lease = Arc::new(PortLease::acquire(&pool, &game(r#"{ "game": "47110-47110" }"#)).unwrap());
+        let held_by_game = lease.clone();
+        drop(lease);
+        assert_eq!(pool.lock().unwrap().leased.len(), 1);
+        drop(held_by_game);
+        assert!(pool.lock().unwrap().leased.is_empty());
+    }
+
+    #[test]
+    fn returned_ports_cool_down_before_they_are_leased_again() {
+        let pool = pool(60);
+        let game = game(r#"{ "game": "47120-47120" }"#);
+        drop(PortLease::acquire(&pool, &game).unwrap());
+        assert!(pool.lock().unwrap().cooling.contains_key(&47120));
+        assert!(!pool.lock().unwrap().can_lease(&game));
+        assert!(PortLease::acquire(&pool, &game).is_none());
+    }
+
+    #[test]
+    fn ports_in_use_are_quarantined_and_skipped() {
+        let _held = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 47130)).unwrap();
+        let pool = pool(0);
+        let lease = PortLease::acquire(&pool, &game(r#"{ "game": "47130-47131" }"#)).unwrap();
+        assert_eq!(lease.main_port(), 47131);
+        let pool = pool.lock().unwrap();
+        assert!(pool.quarantined[&47130].reason.starts_with("tcp"));
+        assert!(!pool.is_free(47130));
+    }
+
+    #[test]
+    fn port_ranges_include_their_end() {
+        assert_eq!(parse_port_range("30000-30999"), Ok(30000..31000));
+        assert_eq!(parse_port_range(" 7777 - 7777 "), Ok(7777..7778));
+        assert!(parse_port_range("30999-30000").is_err());
+        assert!(parse_port_range("30000").is_err());
+        assert!(parse_port_range("30000-70000").is_err());
+        assert!(parse_port_range("a-b").is_err());
+        assert_eq!(port_range(30000, 31000), Ok(30000..31001));
+        assert!(port_range(31000, 30000).is_err());
+    }
+
+    #[test]
+    fn every_named_port_is_leased_from_its_own_range() {
+        let pool = pool(0);
+        let lease = PortLease::acquire(&pool, &game(r#"{ "game": "47150-47150", "query": "47160-47160" }"#)).unwrap();
+        assert_eq!(
+            lease.ports(),
+            &BTreeMap::from([(String::from("game"), 47150), (String::from("query"), 47160)])
+        );
+        assert_eq!(lease.main_port(), 47150);
+    }
+
+    #[test]
+    fn a_game_gets_all_of_its_ports_or_none() {
+        let pool = pool(60);
+        let game = game(r#"{ "game": "47170-47170", "query": "47180-47180" }"#);
+        pool.lock().unwrap().leased.insert(47180);
+        assert!(PortLease::acquire(&pool, &game).is_none());
+        // the game port was never used, it is free again right away
+        let pool = pool.lock().unwrap();
+        assert!(pool.is_free(47170));
+        assert!(pool.cooling.is_empty());
+    }
+
+    #[test]
+    fn claimed_ports_skip_the_probe_and_the_cooldown() {
+        let _held = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 47140)).unwrap();
+        let pool = pool(60);
+        pool.lock().unwrap().cooling.insert(47140, now_secs() + 60);
+        let lease = PortLease::claim(&pool, BTreeMap::from([(String::from("game"), 47140)]));
+        assert_eq!(lease.main_port(), 47140);
+        assert!(pool.lock().unwrap().cooling.is_empty());
+        assert!(pool.lock().unwrap().leased.contains(&47140));
+    }
+}
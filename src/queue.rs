@@ -0,0 +1,298 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::catalogue::GameDefinition;
+
+struct QueuedLaunch {
+    match_id: u32,
+    definition: Arc<GameDefinition>,
+}
+
+struct QueueState {
+    /// Game processes running per game type
+    running: HashMap<String, usize>,
+    waiting: VecDeque<QueuedLaunch>,
+}
+
+/// Launch capacity of one game type, as listed by `/games`
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct LaunchCapacity {
+    pub running_games: usize,
+    /// The game's own cap, `None` when only the host-wide one applies
+    pub max_running_games: Option<usize>,
+    pub queued_matches: usize,
+    /// Whether a match that is ready now would launch without waiting
+    pub can_launch: bool,
+}
+
+/// A game process counted against the caps of a `LaunchQueue`. The slot is given back when this is dropped,
+/// whether the game ran and exited or the launch failed before it started, and goes to the queued matches first
+pub struct LaunchSlot {
+    queue: Arc<LaunchQueue>,
+    game_type: String,
+}
+
+impl Drop for LaunchSlot {
+    fn drop(&mut self) {
+        self.queue.finish(&self.game_type);
+    }
+}
+
+/// Caps the game processes running at once, host-wide with `MAX_GAME_PROCESSES` and per game type with the
+/// catalogue's `max_running`. Matches that are ready while their game is at a cap wait in first in, first out
+/// order. A match is only passed over for one queued later when that one's game has room and its own doesn't
+pub struct LaunchQueue {
+    /// Host-wide cap, `None` for no cap
+    max_running: Option<usize>,
+    state: Mutex<QueueState>,
+    /// Notified when a slot is given back, see `slot_freed`
+    freed: Notify,
+}
+pub type SharedLaunchQueue = Arc<LaunchQueue>;
+
+impl LaunchQueue {
+    pub fn new(max_running: Option<usize>) -> Self {
+        Self {
+            max_running,
+            state: Mutex::new(QueueState {
+                running: HashMap::new(),
+                waiting: VecDeque::new(),
+            }),
+            freed: Notify::new(),
+        }
+    }
+    pub fn from_env() -> Self {
+        Self::new(
+            env::var("MAX_GAME_PROCESSES")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse().expect("Invalid MAX_GAME_PROCESSES"))
+                .filter(|max| *max > 0),
+        )
+    }
+    fn has_room(&self, running: &HashMap<String, usize>, definition: &GameDefinition) -> bool {
+        let total: usize = running.values().sum();
+        let running = running.get(&definition.game_type).copied().unwrap_or(0);
+        self.max_running.is_none_or(|max| total < max) && definition.max_running.is_none_or(|max| running < max)
+    }
+    /// Whether a match of `definition` has room once the queued matches that have room got their slots. They only
+    /// have room for as long as a slot that was given back waits for `slot_freed` to hand it out
+    fn has_room_after_queue(&self, state: &QueueState, definition: &GameDefinition) -> bool {
+        let mut running = state.running.clone();
+        for queued in &state.waiting {
+            if self.has_room(&running, &queued.definition) {
+                *running.entry(queued.definition.game_type.clone()).or_default() += 1;
+            }
+        }
+        self.has_room(&running, definition)
+    }
+    fn take_slot(self: &Arc<Self>, state: &mut QueueState, definition: &GameDefinition) -> LaunchSlot {
+        *state.running.entry(definition.game_type.clone()).or_default() += 1;
+        LaunchSlot {
+            queue: self.clone(),
+            game_type: definition.game_type.clone(),
+        }
+    }
+    /// Takes a slot for a match that is ready, or queues it and returns its position, 1 being next
+    pub fn start_or_enqueue(self: &Arc<Self>, match_id: u32, definition: &Arc<GameDefinition>) -> Result<LaunchSlot, usize> {
+        let mut state = self.state.lock().unwrap();
+        // a slot that was just given back goes to the queued matches, the match doesn't jump ahead of anyone
+        if self.has_room_after_queue(&state, definition) {
+            return Ok(self.take_slot(&mut state, definition));
+        }
+        state.waiting.push_back(QueuedLaunch {
+            match_id,
+            definition: definition.clone(),
+        });
+        Err(state.waiting.len())
+    }
+    /// Takes a slot for a game that is already running, even over the caps, see `recovery`
+    pub fn adopt(self: &Arc<Self>, definition: &GameDefinition) -> LaunchSlot {
+        self.take_slot(&mut self.state.lock().unwrap(), definition)
+    }
+    /// Gives back the slot of a game that exited, see `LaunchSlot`
+    fn finish(&self, game_type: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(running) = state.running.get_mut(game_type) {
+            *running = running.saturating_sub(1);
+        }
+        self.freed.notify_one();
+    }
+    /// Resolves once a slot was given back since the last call, the queued matches that fit should be started then
+    pub async fn slot_freed(&self) {
+        self.freed.notified().await;
+    }
+    /// Takes queued matches off the queue in order while their game has room, taking their slots
+    pub fn take_startable(self: &Arc<Self>) -> Vec<(u32, LaunchSlot)> {
+        let mut state = self.state.lock().unwrap();
+        let mut started = Vec::new();
+        let mut i = 0;
+        while i < state.waiting.len() {
+            if self.has_room(&state.running, &state.waiting[i].definition) {
+                let queued = state.waiting.remove(i).unwrap();
+                started.push((queued.match_id, self.take_slot(&mut state, &queued.definition)));
+            } else {
+                i += 1;
+            }
+        }
+        started
+    }
+    /// Drops a match that left the queue without launching, returns whether it was queued
+    pub fn remove(&self, match_id: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.waiting.len();
+        state.waiting.retain(|q| q.match_id != match_id);
+        state.waiting.len() != before
+    }
+    /// Position of every queued match, 1 being next
+    pub fn positions(&self) -> Vec<(u32, usize)> {
+        let state = self.state.lock().unwrap();
+        state.waiting.iter().enumerate().map(|(i, q)| (q.match_id, i + 1)).collect()
+    }
+    pub fn capacity(&self, definition: &GameDefinition) -> LaunchCapacity {
+        let state = self.state.lock().unwrap();
+        LaunchCapacity {
+            running_games: state.running.get(&definition.game_type).copied().unwrap_or(0),
+            max_running_games: definition.max_running,
+            queued_matches: state.waiting.iter().filter(|q| q.definition.game_type == definition.game_type).count(),
+            can_launch: self.has_room_after_queue(&state, definition),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalogue::GameCatalogue;
+
+    /// A game type named `name`, capped at `max_running` processes when set
+    fn game(name: &str, max_running: Option<usize>) -> Arc<GameDefinition> {
+        let max_running = max_running.map_or(String::new(), |max| format!(r#", "max_running": {}"#, max));
+        let catalogue = format!(
+            r#"{{ "games": {{ "{}": {{ "min_players": 2, "max_players": 2, "executable": "/bin/true", "prizes": [5]{} }} }} }}"#,
+            name, max_running
+        );
+        GameCatalogue::parse(&catalogue, &(0..0)).unwrap().get(name).unwrap().clone()
+    }
+
+    fn ids(started: &[(u32, LaunchSlot)]) -> Vec<u32> {
+        started.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn matches_over_the_host_cap_wait_in_order() {
+        let queue = Arc::new(LaunchQueue::new(Some(1)));
+        let chess = game("chess", None);
+        let slot = queue.start_or_enqueue(1, &chess).unwrap();
+        assert_eq!(queue.start_or_enqueue(2, &chess).err(), Some(1));
+        assert_eq!(queue.start_or_enqueue(3, &chess).err(), Some(2));
+        assert_eq!(queue.positions(), vec![(2, 1), (3, 2)]);
+        assert!(queue.take_startable().is_empty());
+
+        drop(slot);
+        let started = queue.take_startable();
+        assert_eq!(ids(&started), vec![2]);
+        assert_eq!(queue.positions(), vec![(3, 1)]);
+    }
+
+    #[test]
+    fn dropping_a_slot_frees_it() {
+        let queue = Arc::new(LaunchQueue::new(None));
+        let chess = game("chess", Some(2));
+        let slots: Vec<_> = (1..=2).map(|id| queue.start_or_enqueue(id, &chess).unwrap()).collect();
+        let capacity = queue.capacity(&chess);
+        assert_eq!(
+            (capacity.running_games, capacity.max_running_games, capacity.can_launch),
+            (2, Some(2), false)
+        );
+        drop(slots);
+        let capacity = queue.capacity(&chess);
+        assert_eq!((capacity.running_games, capacity.can_launch), (0, true));
+    }
+
+    #[test]
+    fn matches_only_skip_ahead_when_their_game_has_room() {
+        let queue = Arc::new(LaunchQueue::new(Some(3)));
+        let chess = game("chess", Some(1));
+        let go = game("go", None);
+        let chess_slot = queue.start_or_enqueue(1, &chess).unwrap();
+        // chess is at its own cap, go still has room under the host cap
+        assert_eq!(queue.start_or_enqueue(2, &chess).err(), Some(1));
+        let mut go_slots = vec![queue.start_or_enqueue(3, &go).unwrap()];
+        let capacity = queue.capacity(&chess);
+        assert_eq!((capacity.running_games, capacity.queued_matches, capacity.can_launch), (1, 1, false));
+        assert!(queue.capacity(&go).can_launch);
+
+        go_slots.push(queue.start_or_enqueue(4, &go).unwrap());
+        // the host is full, go waits behind chess
+        assert_eq!(queue.start_or_enqueue(5, &go).err(), Some(2));
+        drop(chess_slot);
+        let started = queue.take_startable();
+        assert_eq!(ids(&started), vec![2]);
+        assert_eq!(queue.positions(), vec![(5, 1)]);
+    }
+
+    #[test]
+    fn a_freed_host_slot_goes_to_the_first_match_whose_game_has_room() {
+        let queue = Arc::new(LaunchQueue::new(Some(2)));
+        let chess = game("chess", Some(1));
+        let go = game("go", None);
+        let _chess_slot = queue.start_or_enqueue(1, &chess).unwrap();
+        let go_slot = queue.start_or_enqueue(2, &go).unwrap();
+        assert_eq!(queue.start_or_enqueue(3, &chess).err(), Some(1));
+        assert_eq!(queue.start_or_enqueue(4, &go).err(), Some(2));
+        drop(go_slot);
+        // chess is still at its own cap, so the go match behind it takes the slot
+        let started = queue.take_startable();
+        assert_eq!(ids(&started), vec![4]);
+        assert_eq!(queue.positions(), vec![(3, 1)]);
+    }
+
+    #[test]
+    fn removed_and_adopted_matches() {
+        let queue = Arc::new(LaunchQueue::new(Some(1)));
+        let chess = game("chess", None);
+        // adopted games count even over the cap
+        let first = queue.adopt(&chess);
+        let second = queue.adopt(&chess);
+        assert_eq!(queue.capacity(&chess).running_games, 2);
+        assert_eq!(queue.start_or_enqueue(1, &chess).err(), Some(1));
+        assert!(queue.remove(1));
+        assert!(!queue.remove(1));
+        drop((first, second));
+        assert!(queue.take_startable().is_empty());
+        assert!(queue.capacity(&chess).can_launch);
+    }
+
+    #[tokio::test]
+    async fn freed_slots_go_to_queued_matches_before_new_ones() {
+        let queue = Arc::new(LaunchQueue::new(Some(1)));
+        let chess = game("chess", None);
+        let slot = queue.start_or_enqueue(1, &chess).unwrap();
+        assert_eq!(queue.start_or_enqueue(2, &chess).err(), Some(1));
+        drop(slot);
+        tokio::time::timeout(std::time::Duration::from_secs(1), queue.slot_freed()).await.unwrap();
+        // match 2 has not been dispatched yet, match 3 got ready meanwhile
+        assert!(!queue.capacity(&chess).can_launch);
+        assert_eq!(queue.start_or_enqueue(3, &chess).err(), Some(2));
+        let started = queue.take_startable();
+        assert_eq!(ids(&started), vec![2]);
+        assert_eq!(queue.positions(), vec![(3, 1)]);
+    }
+
+    #[test]
+    fn freed_slots_only_hold_back_matches_competing_for_them() {
+        let queue = Arc::new(LaunchQueue::new(None));
+        let (chess, go) = (game("chess", Some(1)), game("go", Some(1)));
+        let chess_slot = queue.start_or_enqueue(1, &chess).unwrap();
+        assert_eq!(queue.start_or_enqueue(2, &chess).err(), Some(1));
+        drop(chess_slot);
+        // the freed chess slot waits for match 2, go has room of its own
+        let _go_slot = queue.start_or_enqueue(3, &go).unwrap();
+        assert_eq!(ids(&queue.take_startable()), vec![2]);
+    }
+}
@@ -0,0 +1,248 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::RwLock;
+
+use crate::history::SharedMatchHistory;
+use crate::ledger::SharedLedger;
+use crate::outcome::{MatchResult, Outcome, OutcomeSource};
+use crate::queue::SharedLaunchQueue;
+use crate::state::{kick_unready, transition, MatchState};
+use crate::utils::now_secs;
+use crate::{broadcast_queue_positions, retire_match, settle_match, Match, Matches};
+
+const DEFAULT_REAPER_INTERVAL_SECS: u64 = 1;
+
+/// What happens to the stake of a player kicked for not readying in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadyCheckPolicy {
+    /// The stake goes back to the player
+    Release,
+    /// The stake is captured and not returned
+    Forfeit,
+}
+
+/// The reaper acts on matches past their `expiry_time`, set from the timeouts of their game type.
+/// Running out of the ready check kicks unready players instead of expiring the match
+#[derive(Debug, Clone, Copy)]
+pub struct ReaperConfig {
+    pub ready_check_policy: ReadyCheckPolicy,
+    /// How often the reaper looks for expired matches
+    pub interval: u64,
+}
+
+impl ReaperConfig {
+    pub fn from_env() -> Self {
+        Self {
+            ready_check_policy: match env::var("READY_CHECK_POLICY").unwrap_or_else(|_| "release".to_string()).as_str() {
+                "release" | "" => ReadyCheckPolicy::Release,
+                "forfeit" => ReadyCheckPolicy::Forfeit,
+                other => panic!("Unknown READY_CHECK_POLICY {}", other),
+            },
+            interval: env::var("REAPER_INTERVAL_SECS")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse().expect("Invalid REAPER_INTERVAL_SECS"))
+                .unwrap_or(DEFAULT_REAPER_INTERVAL_SECS)
+                .max(1),
+        }
+    }
+}
+
+/// Players kicked from one match by a ready check
+struct ReadyCheckOutcome {
+    match_id: u32,
+    kicked: Vec<u64>,
+    cancelled: bool,
+}
+
+/// Expires matches past their `expiry_time`: subscribers get an `EXPIRED` update and stakes are refunded.
+/// Matches that never launched are settled and retired right away and leave the launch queue. A running game is
+/// killed and its match settled once it exited, from a result it reported meanwhile if any, see `launch_match`.
+/// A `READYING` match past its ready check loses its unready players instead
+pub async fn run_reaper(matches: Matches, ledger: SharedLedger, history: SharedMatchHistory, queue: SharedLaunchQueue, config: ReaperConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+    loop {
+        interval.tick().await;
+        let (expired, ready_checks) = sweep(&matches).await;
+        let mut dequeued = false;
+        for game in expired {
+            settle_match(&game, &ledger, expired_result()).await;
+            let id = game.read().await.id;
+            dequeued |= queue.remove(id);
+            retire_match(&matches, &history, id).await;
+        }
+        if dequeued {
+            broadcast_queue_positions(&matches, &queue).await;
+        }
+        for check in ready_checks {
+            for user_id in check.kicked {
+                match config.ready_check_policy {
+                    ReadyCheckPolicy::Release => ledger.release(check.match_id, user_id).await,
+                    ReadyCheckPolicy::Forfeit => ledger.forfeit(check.match_id, user_id).await,
+                }
+            }
+            if check.cancelled {
+                retire_match(&matches, &history, check.match_id).await;
+            }
+        }
+    }
+}
+
+/// The result of a match that ran out of time without a game reporting one
+pub fn expired_result() -> MatchResult {
+    MatchResult {
+        outcome: Outcome::Refunded,
+        source: OutcomeSource::Expired,
+        report: None,
+    }
+}
+
+/// Moves every match past its expiry to `EXPIRED` and runs the ready check of `READYING` matches past their
+/// deadline. Running games are told to stop, the matches returned are the ones without a game to wait for
+async fn sweep(matches: &Matches) -> (Vec<Arc<RwLock<Match>>>, Vec<ReadyCheckOutcome>) {
+    let now = now_secs();
+    // the map isn't held while waiting on each match, which may be busy settling
+    let games: Vec<Arc<RwLock<Match>>> = matches.read().await.values().cloned().collect();
+    let mut expired = Vec::new();
+    let mut ready_checks = Vec::new();
+    for game in games {
+        let mut game_write = game.write().await;
+        // the supervisor gives up on games that don't start in time
+        if game_write.state.is_terminal() || game_write.state == MatchState::STARTING || game_write.expiry_time > now {
+            continue;
+        }
+        if game_write.state == MatchState::READYING {
+            let Ok(kicked) = kick_unready(&mut game_write) else {
+                continue;
+            };
+            ready_checks.push(ReadyCheckOutcome {
+                match_id: game_write.id,
+                kicked,
+                cancelled: game_write.state == MatchState::CANCELLED,
+            });
+            continue;
+        }
+        let was_playing = game_write.state == MatchState::PLAYING;
+        if transition(&mut game_write, MatchState::EXPIRED).is_err() {
+            continue;
+        }
+        if was_playing {
+            game_write.kill.notify_one();
+        } else {
+            drop(game_write);
+            expired.push(game);
+        }
+    }
+    (expired, ready_checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalogue::GameDefinition;
+    use crate::history::MatchHistory;
+    use crate::ledger::{Ledger, TransactionKind};
+    use crate::queue::LaunchQueue;
+    use crate::wallet::InMemoryWallet;
+    use std::collections::HashMap;
+
+    /// A match past its expiry in `state` whose players staked 10 each, and whose first `ready` players readied
+    fn expired_match(id: u32, state: MatchState, ready: usize) -> Match {
+        let mut game = Match::for_tests(id, GameDefinition::for_tests(""), &["alice", "bobby"]);
+        game.state = state;
+        game.expiry_time = 0;
+        game.ready.iter_mut().take(ready).for_each(|r| *r = true);
+        game
+    }
+
+    /// Runs the reaper over `games` for one sweep
+    async fn reap(games: Vec<Match>, policy: ReadyCheckPolicy) -> (Matches, SharedLedger, SharedMatchHistory) {
+        let ledger: SharedLedger = Arc::new(Ledger::new(Arc::new(InMemoryWallet::new(100)), None, None));
+        let matches: Matches = Arc::new(RwLock::new(HashMap::new()));
+        for game in games {
+            for user_id in &game.player_ids {
+                ledger.escrow(game.id, *user_id, 10).await.unwrap();
+            }
+            matches.write().await.insert(game.id, Arc::new(RwLock::new(game)));
+        }
+        let history: SharedMatchHistory = Arc::new(std::sync::RwLock::new(MatchHistory::new(10)));
+        let config = ReaperConfig {
+            ready_check_policy: policy,
+            interval: 60,
+        };
+        let reaper = tokio::spawn(run_reaper(
+            matches.clone(),
+            ledger.clone(),
+            history.clone(),
+            Arc::new(LaunchQueue::new(None)),
+            config,
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        reaper.abort();
+        (matches, ledger, history)
+    }
+
+    fn kinds(ledger: &SharedLedger, user_id: u64) -> Vec<TransactionKind> {
+        ledger.transactions_for(user_id).iter().map(|t| t.kind).collect()
+    }
+
+    #[tokio::test]
+    async fn expired_open_matches_are_refunded_and_retired() {
+        let (matches, ledger, history) = reap(vec![expired_match(1, MatchState::OPEN, 0)], ReadyCheckPolicy::Release).await;
+        assert!(matches.read().await.is_empty());
+        let retired = history.read().unwrap().get(1).cloned().unwrap();
+        assert_eq!(retired.state, MatchState::EXPIRED);
+        assert_eq!(retired.result.unwrap().source, OutcomeSource::Expired);
+        for user_id in [1, 2] {
+            assert_eq!(kinds(&ledger, user_id), vec![TransactionKind::ESCROW, TransactionKind::REFUND]);
+            assert_eq!(ledger.wallet().balance(user_id).await.unwrap(), 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn unready_players_are_kicked_when_the_ready_check_runs_out() {
+        let games = vec![expired_match(1, MatchState::READYING, 1), expired_match(2, MatchState::READYING, 0)];
+        let (matches, ledger, history) = reap(games, ReadyCheckPolicy::Forfeit).await;
+        // the player who readied waits for another one
+        let waiting = matches.read().await[&1].read().await.clone();
+        assert_eq!(
+            (waiting.state, waiting.players, waiting.kicked),
+            (MatchState::OPEN, vec![String::from("alice")], vec![String::from("bobby")])
+        );
+        assert_eq!(ledger.stakes(1).len(), 1);
+        // nobody readied in the other one
+        assert_eq!(history.read().unwrap().get(2).unwrap().state, MatchState::CANCELLED);
+        assert!(ledger.stakes(2).is_empty());
+        assert_eq!(ledger.wallet().balance(2).await.unwrap(), 80);
+        assert!(kinds(&ledger, 2).contains(&TransactionKind::FORFEIT));
+    }
+
+    #[tokio::test]
+    async fn expired_games_are_killed_before_their_match_is_settled() {
+        let playing = expired_match(1, MatchState::PLAYING, 2);
+        let kill = playing.kill.clone();
+        let (matches, ledger, history) = reap(vec![playing], ReadyCheckPolicy::Release).await;
+        // the match waits for its game to exit, see `launch_match`
+        tokio::time::timeout(Duration::from_secs(1), kill.notified()).await.unwrap();
+        let game = matches.read().await[&1].clone();
+        assert_eq!(game.read().await.state, MatchState::EXPIRED);
+        assert!(game.read().await.result.is_none());
+        assert_eq!(ledger.stakes(1).len(), 2);
+        assert!(history.read().unwrap().get(1).is_none());
+
+        assert!(settle_match(&game, &ledger, expired_result()).await);
+        assert_eq!(game.read().await.state, MatchState::EXPIRED);
+        assert_eq!(ledger.wallet().balance(1).await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn kicked_players_get_their_stake_back_by_default() {
+        let (matches, ledger, _) = reap(vec![expired_match(1, MatchState::READYING, 1)], ReadyCheckPolicy::Release).await;
+        assert_eq!(matches.read().await[&1].read().await.state, MatchState::OPEN);
+        assert_eq!(kinds(&ledger, 2), vec![TransactionKind::ESCROW, TransactionKind::RELEASE]);
+        assert_eq!(ledger.wallet().balance(2).await.unwrap(), 100);
+        assert_eq!(ledger.wallet().balance(1).await.unwrap(), 90);
+    }
+}
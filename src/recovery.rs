@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify, RwLock};
+
+use crate::catalogue::{GameDefinition, GameTimeouts, SharedGameCatalogue};
+use crate::launch::{read_report, remove_launch_file, remove_scratch_dir, LaunchDocument};
+use crate::ledger::Stake;
+use crate::outcome::{MatchResult, Outcome, OutcomeSource};
+use crate::ports::{PortLease, SharedPortPool};
+use crate::reaper::expired_result;
+use crate::state::{MatchState, MatchUpdate};
+use crate::supervisor::GameOutput;
+use crate::{finish_game, reported_result, Match, SharedLauncher};
+
+const DEFAULT_PROCESS_RECORDS_PATH: &str = "game-processes.json";
+
+/// What happens to a game process that is still running when the server starts again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrphanPolicy {
+    /// Its match is put back as `PLAYING` and the game is watched until it exits, as if the server never stopped
+    Adopt,
+    /// The game is stopped and its match settled from whatever it reported, refunded otherwise
+    Kill,
+}
+
+impl OrphanPolicy {
+    pub fn from_env() -> Self {
+        match env::var("ORPHAN_POLICY").unwrap_or_default().as_str() {
+            "adopt" | "" => OrphanPolicy::Adopt,
+            "kill" => OrphanPolicy::Kill,
+            other => panic!("Unknown ORPHAN_POLICY {}", other),
+        }
+    }
+}
+
+/// A game process as it was started, with everything needed to settle its match after a restart
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessRecord {
+    pub pid: u32,
+    /// When the process started in clock ticks since boot, tells it apart from a later process given the same pid
+    pub start_ticks: Option<u64>,
+    pub started_at: u64,
+    /// Whether the game runs in namespaces of its own, its pid is then the process waiting on it
+    pub sandboxed: bool,
+    pub launch_file: Option<PathBuf>,
+    /// Where the game writes its output
+    pub output: GameOutput,
+    pub doc: LaunchDocument,
+    /// The match escrow when the game was started
+    pub stakes: Vec<Stake>,
+}
+
+/// State and start time of a process, from `/proc/<pid>/stat`
+fn process_stat(pid: u32) -> Option<(char, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name in parentheses may contain spaces, the fields after it don't
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    // starttime is the 22nd field, 20 after the state
+    let start_ticks = fields.nth(18)?.parse().ok()?;
+    Some((state, start_ticks))
+}
+
+/// Start time of a running process in clock ticks since boot
+pub fn process_start_ticks(pid: u32) -> Option<u64> {
+    process_stat(pid).map(|(_, ticks)| ticks)
+}
+
+/// CPU time a process used in user and kernel mode, in clock ticks
+pub fn process_cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // utime and stime are the 14th and 15th fields, 11 and 12 after the state
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().skip(11);
+    let user: u64 = fields.next()?.parse().ok()?;
+    let system: u64 = fields.next()?.parse().ok()?;
+    Some(user + system)
+}
+
+/// Pids of the children of a process
+pub fn process_children(pid: u32) -> Vec<u32> {
+    fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid))
+        .map(|children| children.split_whitespace().filter_map(|child| child.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+impl ProcessRecord {
+    /// Whether the recorded process is still running, a zombie or a process reusing the pid is not it
+    pub fn is_alive(&self) -> bool {
+        match process_stat(self.pid) {
+            Some((state, ticks)) => !matches!(state, 'Z' | 'X') && self.start_ticks.is_none_or(|t| t == ticks),
+            None => false,
+        }
+    }
+    /// Sends `signal` to the process unless it is gone
+    pub fn signal(&self, signal: libc::c_int) {
+        if self.is_alive() {
+            // SAFETY: kill only sends a signal, the pid was just checked to still be the game
+            unsafe {
+                libc::kill(self.pid as libc::pid_t, signal);
+            }
+        }
+    }
+}
+
+/// Game processes that are running or whose match is not settled yet, saved to `GAME_PROCESS_RECORDS_PATH` so a
+/// restarted server can pick them up, see `recover_processes`. The file holds result secrets and game tokens and
+/// is only readable by the server's user
+pub struct ProcessRecords {
+    path: Option<PathBuf>,
+    records: Mutex<BTreeMap<u32, ProcessRecord>>,
+}
+
+impl ProcessRecords {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let records: Vec<ProcessRecord> = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|contents| serde_json::from_str(&contents).expect("Invalid process record file"))
+            .unwrap_or_default();
+        Self {
+            path,
+            records: Mutex::new(records.into_iter().map(|r| (r.doc.match_id, r)).collect()),
+        }
+    }
+    pub fn from_env() -> Self {
+        Self::new(match env::var("GAME_PROCESS_RECORDS_PATH") {
+            Ok(path) if path.is_empty() => None,
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_PROCESS_RECORDS_PATH)),
+        })
+    }
+    /// Every record, including the ones left by the last run
+    pub fn all(&self) -> Vec<ProcessRecord> {
+        self.records.lock().unwrap().values().cloned().collect()
+    }
+    pub fn insert(&self, record: ProcessRecord) {
+        let mut records = self.records.lock().unwrap();
+        records.insert(record.doc.match_id, record);
+        self.save(&records);
+    }
+    /// Drops the record of a match that is settled
+    pub fn remove(&self, match_id: u32) {
+        let mut records = self.records.lock().unwrap();
+        if records.remove(&match_id).is_some() {
+            self.save(&records);
+        }
+    }
+    fn save(&self, records: &BTreeMap<u32, ProcessRecord>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let records: Vec<&ProcessRecord> = records.values().collect();
+        // written aside and renamed so a crash never leaves half a file
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let written = serde_json::to_vec_pretty(&records)
+            .map_err(std::io::Error::from)
+            .and_then(|json| {
+                let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)?;
+                file.write_all(&json)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, path));
+        if let Err(e) = written {
+            println!("Failed to save process records to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The match of a recorded game, as `PLAYING` with the ports and stakes it had
+fn recovered_match(record: &ProcessRecord, definition: Arc<GameDefinition>, lease: Arc<PortLease>) -> Match {
+    let doc = &record.doc;
+    let players: Vec<String> = doc.players.iter().map(|p| p.username.clone()).collect();
+    let port = lease.main_port();
+    let expiry_time = record.started_at + definition.timeouts.for_state(MatchState::PLAYING);
+    let (state_tx, _) = watch::channel(MatchUpdate {
+        state: MatchState::PLAYING,
+        ready: vec![true; players.len()],
+        players: players.clone(),
+        port,
+        expiry_time,
+        kicked: Vec::new(),
+        ports: doc.ports.clone(),
+        queue_position: None,
+    });
+    Match {
+        id: doc.match_id,
+        player_ids: doc.players.iter().map(|p| p.user_id).collect(),
+        game_tokens: doc.players.iter().map(|p| p.token.clone()).collect(),
+        result_secret: Some(doc.result_secret.clone()),
+        result: None,
+        ready: vec![true; players.len()],
+        teams: doc.players.iter().map(|p| p.team).collect(),
+        players,
+        prize: doc.settings["prize"].as_u64().unwrap_or_default() as u32,
+        game_type: doc.game_type.clone(),
+        definition,
+        expiry_time,
+        port,
+        ports: doc.ports.clone(),
+        port_lease: Some(lease),
+        state: MatchState::PLAYING,
+        queue_position: None,
+        kicked: Vec::new(),
+        kill: Arc::new(Notify::new()),
+        drained: false,
+        state_channel: state_tx,
+    }
+}
+
+/// Refunds the stakes the escrow file holds for matches without a process record, their match was lost with the
+/// last run before its game was started
+async fn refund_unlaunched(launcher: &SharedLauncher, records: &[ProcessRecord]) {
+    for match_id in launcher.ledger.escrowed_matches() {
+        if records.iter().all(|r| r.doc.match_id != match_id) {
+            println!("Refunding match {} the last run left unlaunched", match_id);
+            launcher.ledger.refund(match_id).await;
+        }
+    }
+}
+
+/// Picks up the game processes the last run of the server left behind, before any request is served.
+/// Their ports and escrow are taken back, then a game that is still running is adopted or stopped according to
+/// `ORPHAN_POLICY`. Once it is gone its match is settled from the result it reported to its result file or to
+/// `/end_match` meanwhile, and refunded as `orphaned` otherwise.
+/// The escrow of matches that never got a game, still open, readying or queued when the server stopped, is refunded
+pub async fn recover_processes(launcher: &SharedLauncher, catalogue: &SharedGameCatalogue, port_pool: &SharedPortPool) {
+    let records = launcher.supervisor.records().all();
+    refund_unlaunched(launcher, &records).await;
+    if records.is_empty() {
+        return;
+    }
+    let policy = OrphanPolicy::from_env();
+    println!("Recovering {} game processes left by the last run ({:?})", records.len(), policy);
+    for record in records {
+        let match_id = record.doc.match_id;
+        let alive = record.is_alive();
+        launcher.ledger.restore(match_id, record.stakes.clone());
+        let definition = catalogue.read().unwrap().get(&record.doc.game_type).cloned();
+        let Some(definition) = definition else {
+            println!(
+                "Game type {} of match {} is no longer in the catalogue, refunding it",
+                record.doc.game_type, match_id
+            );
+            if alive {
+                launcher.supervisor.stop_orphan(&record, GameTimeouts::default().kill_grace_secs).await;
+            }
+            launcher.ledger.refund(match_id).await;
+            launcher.supervisor.records().remove(match_id);
+            continue;
+        };
+        let lease = Arc::new(PortLease::claim(port_pool, record.doc.ports.clone()));
+        let game = recovered_match(&record, definition.clone(), lease.clone());
+        let kill = game.kill.clone();
+        let match_arc = Arc::new(RwLock::new(game));
+        launcher.matches.write().await.insert(match_id, match_arc.clone());
+        let adopt = alive && policy == OrphanPolicy::Adopt;
+        let slot = adopt.then(|| launcher.queue.adopt(&definition));
+        if adopt {
+            let mut tokens = launcher.game_tokens.lock().unwrap();
+            let game_ends_at = record.started_at + definition.timeouts.playing_secs;
+            for player in &record.doc.players {
+                tokens.restore(&player.token, match_id, player.user_id, &player.username, game_ends_at);
+            }
+        }
+        let launcher = launcher.clone();
+        tokio::spawn(async move {
+            if adopt {
+                println!("Adopting game process {} of match {}", record.pid, match_id);
+                launcher.supervisor.adopt(&record, &definition, &kill).await;
+                drop(slot);
+            } else if alive {
+                println!("Stopping game process {} of match {}", record.pid, match_id);
+                launcher.supervisor.stop_orphan(&record, definition.timeouts.kill_grace_secs).await;
+            } else {
+                println!("Game process {} of match {} exited while the server was down", record.pid, match_id);
+            }
+            launcher.game_tokens.lock().unwrap().revoke_match(match_id);
+            if let Some(path) = &record.launch_file {
+                remove_launch_file(path);
+            }
+            let (players, teams) = {
+                let game = match_arc.read().await;
+                (game.players.clone(), game.teams.clone())
+            };
+            let last_line = record.output.last_stdout_line();
+            let report = read_report(&record.doc, last_line.as_deref());
+            record.output.clean_up();
+            remove_scratch_dir(&record.doc);
+            let expired = match_arc.read().await.state == MatchState::EXPIRED;
+            let result = reported_result(report, match_id, &players, &teams).unwrap_or_else(|| match expired {
+                true => expired_result(),
+                false => MatchResult {
+                    outcome: Outcome::Refunded,
+                    source: OutcomeSource::Orphaned,
+                    report: None,
+                },
+            });
+            finish_game(&launcher, &match_arc, Some(lease), result).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::game_token::GameTokens;
+    use crate::history::MatchHistory;
+    use crate::launch::{LaunchConfig, LaunchMode};
+    use crate::ledger::{Ledger, TransactionKind};
+    use crate::queue::LaunchQueue;
+    use crate::supervisor::Supervisor;
+    use crate::utils::random_token;
+    use crate::wallet::{InMemoryWallet, SharedWallet};
+    use crate::Launcher;
+
+    fn record(match_id: u32) -> ProcessRecord {
+        let doc = serde_json::json!({
+            "match_id": match_id,
+            "game_type": "knockout",
+            "port": 30000,
+            "players": [{ "user_id": 1, "username": "alice", "token": "token-alice", "team": 0 }],
+            "teams": 1,
+            "ports": { "game": 30000 },
+            "settings": null,
+            "result_url": "http://127.0.0.1:8080/end_match",
+            "result_secret": "secret",
+            "result_file": "",
+        });
+        ProcessRecord {
+            pid: std::process::id(),
+            start_ticks: process_start_ticks(std::process::id()),
+            started_at: 1,
+            sandboxed: false,
+            launch_file: None,
+            output: GameOutput {
+                stdout: PathBuf::from(format!("logs/match-{}.stdout.log", match_id)),
+                stderr: PathBuf::from(format!("logs/match-{}.stderr.log", match_id)),
+                echo: false,
+            },
+            doc: serde_json::from_value(doc).unwrap(),
+            stakes: vec![Stake {
+                user_id: 1,
+                amount: 5,
+                reservation_id: String::from("reservation"),
+            }],
+        }
+    }
+
+    #[test]
+    fn records_are_saved_for_the_next_run() {
+        let path = env::temp_dir().join(format!("process-records-test-{}.json", random_token()));
+        let records = ProcessRecords::new(Some(path.clone()));
+        assert!(records.all().is_empty());
+        records.insert(record(1));
+        records.insert(record(2));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let reloaded = ProcessRecords::new(Some(path.clone()));
+        let all = reloaded.all();
+        assert_eq!(all.iter().map(|r| r.doc.match_id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(all[0].doc.result_secret, "secret");
+        assert_eq!(all[0].stakes[0].reservation_id, "reservation");
+        assert_eq!(all[1].output.stdout, PathBuf::from("logs/match-2.stdout.log"));
+
+        reloaded.remove(1);
+        reloaded.remove(3);
+        let all = ProcessRecords::new(Some(path.clone())).all();
+        assert_eq!(all.iter().map(|r| r.doc.match_id).collect::<Vec<_>>(), vec![2]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_process_reusing_the_pid_is_not_the_game() {
+        let mut record = record(1);
+        assert!(record.is_alive());
+        record.start_ticks = record.start_ticks.map(|t| t + 1);
+        assert!(!record.is_alive());
+    }
+
+    #[tokio::test]
+    async fn escrow_of_matches_that_never_got_a_game_is_refunded() {
+        let wallet: SharedWallet = Arc::new(InMemoryWallet::new(100));
+        let path = env::temp_dir().join(format!("escrow-{}.json", random_token()));
+        let ledger = Ledger::new(wallet.clone(), None, Some(path.clone()));
+        // match 1 has a game the record settles, match 2 was still open
+        ledger.escrow(1, 1, 10).await.unwrap();
+        ledger.escrow(2, 2, 10).await.unwrap();
+
+        let ledger = Arc::new(Ledger::new(wallet.clone(), None, Some(path.clone())));
+        let launcher: SharedLauncher = Arc::new(Launcher {
+            matches: Arc::new(RwLock::new(Default::default())),
+            ledger: ledger.clone(),
+            history: Arc::new(std::sync::RwLock::new(MatchHistory::new(10))),
+            game_tokens: Arc::new(Mutex::new(GameTokens::new(0))),
+            config: Arc::new(LaunchConfig {
+                mode: LaunchMode::Stdin,
+                result_url: String::new(),
+                runtime_dir: env::temp_dir(),
+            }),
+            supervisor: Supervisor::for_tests(),
+            queue: Arc::new(LaunchQueue::new(None)),
+        });
+        refund_unlaunched(&launcher, &[record(1)]).await;
+        assert_eq!(ledger.escrowed_matches(), vec![1]);
+        let kinds: Vec<_> = ledger.transactions_for(2).iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, vec![TransactionKind::REFUND]);
+        assert_eq!(wallet.balance(2).await.unwrap(), 100);
+        assert_eq!(wallet.balance(1).await.unwrap(), 90);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
 pub struct MatchRequest {
@@ -6,6 +6,25 @@ pub struct MatchRequest {
     pub game_type: String,
 }
 
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+    pub display_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyGameTokenRequest {
+    pub match_id: u32,
+    pub token: String,
+}
+
 #[derive(Deserialize)]
 pub struct JoinQuery {
     pub id: u32,
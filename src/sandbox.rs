@@ -0,0 +1,581 @@
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command as StdCommand;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+const DEFAULT_SCRATCH_DIR: &str = "scratch";
+/// Namespaces a game gets, and may not create again once seccomp applies
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWCGROUP;
+/// Signals the process waiting on a namespaced game passes on to it
+const FORWARDED_SIGNALS: [libc::c_int; 5] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2];
+
+/// System calls a sandboxed game gets `EPERM` for: debugging other processes, mounts and namespaces, kernel
+/// modules and keyrings, and kernel interfaces games have no use for
+const BLOCKED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_mount_setattr,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+];
+
+/// `AUDIT_ARCH_*` of the architectures seccomp filters are built for, see `linux/audit.h`
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+/// Set on the x32 ABI's system call numbers, which would get around the filter
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+fn default_true() -> bool {
+    true
+}
+
+/// A game's `sandbox` in the catalogue. Games without one run as the server's own user with its view of the system
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SandboxConfig {
+    /// Run the game in its own user, mount and PID namespaces, see `Sandbox`
+    #[serde(default = "default_true")]
+    pub namespaces: bool,
+    /// Block `BLOCKED_SYSCALLS`, `no_new_privs` is set either way
+    #[serde(default = "default_true")]
+    pub seccomp: bool,
+}
+
+impl SandboxConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.seccomp && AUDIT_ARCH.is_none() {
+            println!("seccomp filters are not supported on {}, games run without one", env::consts::ARCH);
+        }
+        Ok(())
+    }
+}
+
+/// Everything a sandboxed launch needs, prepared before the fork since the child may only make async-signal-safe calls
+struct Prepared {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    scratch_root: CString,
+    scratch: CString,
+    runtime_dir: CString,
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a file in one call, safe to call between fork and exec
+unsafe fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    check(fd)?;
+    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+    let error = io::Error::last_os_error();
+    libc::close(fd);
+    if written != contents.len() as isize {
+        return Err(error);
+    }
+    Ok(())
+}
+
+unsafe fn set_mount_attr(path: &CStr, flags: libc::c_uint, attr: libc::mount_attr) -> io::Result<()> {
+    let ret = libc::syscall(
+        libc::SYS_mount_setattr,
+        libc::AT_FDCWD,
+        path.as_ptr(),
+        flags,
+        &attr as *const libc::mount_attr,
+        std::mem::size_of::<libc::mount_attr>(),
+    );
+    check(ret as libc::c_int)
+}
+
+impl Prepared {
+    fn new(scratch_root: &Path, scratch: &Path, runtime_dir: &Path) -> io::Result<Self> {
+        // SAFETY: getuid and getgid can't fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Self {
+            uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+            scratch_root: c_path(scratch_root)?,
+            scratch: c_path(scratch)?,
+            runtime_dir: c_path(runtime_dir)?,
+        })
+    }
+    /// Moves the calling process into new user, mount and PID namespaces, keeping its own uid and gid. Every
+    /// mount is made read-only, the runtime dir is hidden, the scratch dir is mounted over `/tmp` and the other
+    /// matches' scratch dirs are hidden behind an empty tmpfs, leaving only this one at its usual path. The caller's children are in the
+    /// new PID namespace, the caller itself is not
+    unsafe fn enter(&self) -> io::Result<()> {
+        let none = c"none";
+        let tmp = c"/tmp";
+        check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID))?;
+        // an unprivileged process must give up setgroups before it may map its group
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)?;
+        // nothing mounted from here on shows up outside
+        check(libc::mount(
+            none.as_ptr(),
+            c"/".as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        ))?;
+        let read_only = libc::mount_attr {
+            attr_set: libc::MOUNT_ATTR_RDONLY,
+            attr_clr: 0,
+            propagation: 0,
+            userns_fd: 0,
+        };
+        set_mount_attr(c"/", libc::AT_RECURSIVE as libc::c_uint, read_only)?;
+        // the launch and result files of unsandboxed games are readable by the server's user
+        check(libc::mount(
+            c"tmpfs".as_ptr(),
+            self.runtime_dir.as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY,
+            c"mode=0500,size=4k".as_ptr().cast(),
+        ))?;
+        check(libc::mount(
+            self.scratch.as_ptr(),
+            tmp.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND,
+            std::ptr::null(),
+        ))?;
+        // a bind mount starts out with the flags of the mount it was taken from
+        let writable = libc::mount_attr {
+            attr_set: 0,
+            attr_clr: libc::MOUNT_ATTR_RDONLY,
+            propagation: 0,
+            userns_fd: 0,
+        };
+        set_mount_attr(tmp, 0, writable)?;
+        check(libc::mount(
+            c"tmpfs".as_ptr(),
+            self.scratch_root.as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            c"mode=0755,size=64k".as_ptr().cast(),
+        ))?;
+        check(libc::mkdir(self.scratch.as_ptr(), 0o700))?;
+        check(libc::mount(
+            tmp.as_ptr(),
+            self.scratch.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND,
+            std::ptr::null(),
+        ))?;
+        Ok(())
+    }
+}
+
+/// Pid of the game, for `forward_signal`. Only set in the process waiting on a namespaced game
+static GAME_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = GAME_PID.load(Ordering::Relaxed);
+    if pid > 0 {
+        // SAFETY: kill is async-signal-safe
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+/// Forks the game off as pid 1 of the new PID namespace. The parent stays behind as the process the supervisor
+/// knows: it passes signals on, and exits like the game did once it is gone. Returns in the game only
+unsafe fn fork_game() -> io::Result<()> {
+    let pid = libc::fork();
+    check(pid)?;
+    if pid == 0 {
+        // a killed parent takes the game, and with it everything the game started, down too
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        // the game's /proc shows its own namespace, the host's stays readable when that fails
+        libc::mount(
+            c"proc".as_ptr(),
+            c"/proc".as_ptr(),
+            c"proc".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            std::ptr::null(),
+        );
+        return Ok(());
+    }
+    GAME_PID.store(pid, Ordering::Relaxed);
+    for signal in FORWARDED_SIGNALS {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = forward_signal as *const () as libc::sighandler_t;
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+    // the spawn waits for its exec error pipe to close and the game's output is closed once the game exits, so this
+    // process holds on to none of them
+    if libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) < 0 {
+        for fd in 0..1024 {
+            libc::close(fd);
+        }
+    }
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(1);
+        }
+    }
+    if libc::WIFEXITED(status) {
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+    // dies of the same signal, so limits and the supervisor see what the game died of
+    let signal = libc::WTERMSIG(status);
+    let no_core = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+    libc::signal(signal, libc::SIG_DFL);
+    let mut unblocked: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut unblocked);
+    libc::sigaddset(&mut unblocked, signal);
+    libc::sigprocmask(libc::SIG_UNBLOCK, &unblocked, std::ptr::null_mut());
+    libc::kill(libc::getpid(), signal);
+    libc::_exit(128 + signal)
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// The seccomp program blocking `BLOCKED_SYSCALLS` and new namespaces, `None` on architectures without one.
+/// `clone3` is answered with `ENOSYS` since its flags can't be checked, libc falls back to `clone`
+fn seccomp_filter() -> Option<Vec<libc::sock_filter>> {
+    let arch = AUDIT_ARCH?;
+    let eperm = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let load = |offset: usize| stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset as u32);
+    let mut filter = vec![
+        load(std::mem::offset_of!(libc::seccomp_data, arch)),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        load(std::mem::offset_of!(libc::seccomp_data, nr)),
+        jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, eperm),
+    ];
+    for syscall in BLOCKED_SYSCALLS {
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, *syscall as u32, 0, 1));
+        filter.push(stmt(libc::BPF_RET | libc::BPF_K, eperm));
+    }
+    filter.extend([
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, libc::SYS_clone3 as u32, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, libc::SYS_clone as u32, 0, 3),
+        // the flags are the first argument, their lower half holds the namespace ones
+        load(std::mem::offset_of!(libc::seccomp_data, args)),
+        jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, NAMESPACE_FLAGS as u32, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, eperm),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+    ]);
+    Some(filter)
+}
+
+/// Sets `no_new_privs` and installs `filter`, safe to call between fork and exec
+unsafe fn restrict(filter: &mut Option<Vec<libc::sock_filter>>) -> io::Result<()> {
+    check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+    if let Some(filter) = filter {
+        let program = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        check(libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &program as *const libc::sock_fprog,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Launches games of types with a `sandbox` in the catalogue, each with a scratch dir of its own under
+/// `GAME_SCRATCH_DIR` that is its `HOME` and `/tmp`. With namespaces, the game runs as pid 1 of its own PID
+/// namespace, under its own user namespace mapping only the server's user, and sees every mount read-only but
+/// its scratch dir. Without unprivileged user namespaces, which `prepare` finds out, sandboxed games only get
+/// `no_new_privs` and seccomp
+pub struct Sandbox {
+    /// `GAME_SCRATCH_DIR` as configured, nothing is created there until a sandboxed game is loaded
+    root: PathBuf,
+    /// `launch::LaunchConfig::runtime_dir`, hidden from namespaced games
+    runtime_dir: PathBuf,
+    host: OnceLock<SandboxHost>,
+}
+
+/// What `Sandbox::prepare` found out about the host
+struct SandboxHost {
+    scratch_root: PathBuf,
+    /// Why namespaces can't be used, `None` when they can
+    unavailable: Option<String>,
+}
+
+impl Sandbox {
+    pub fn from_env(runtime_dir: &Path) -> Self {
+        let root = env::var("GAME_SCRATCH_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| DEFAULT_SCRATCH_DIR.to_string());
+        Self {
+            root: PathBuf::from(root),
+            runtime_dir: runtime_dir.to_path_buf(),
+            host: OnceLock::new(),
+        }
+    }
+    /// Creates the scratch root and checks whether namespaces work, the first time a catalogue with a sandboxed
+    /// game is loaded. Fails when sandboxed games can't be launched at all
+    pub fn prepare(&self) -> io::Result<()> {
+        if self.host.get().is_some() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.root)?;
+        let scratch_root = fs::canonicalize(&self.root)?;
+        // /tmp is replaced before the scratch dirs are hidden, see `Prepared::enter`
+        if scratch_root.starts_with("/tmp") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "GAME_SCRATCH_DIR must not be in /tmp"));
+        }
+        let unavailable = Self::probe(&scratch_root, &self.runtime_dir).err().map(|e| e.to_string());
+        match &unavailable {
+            Some(reason) => println!(
+                "Namespaces are not available for game sandboxes ({}), sandboxed games only get seccomp",
+                reason
+            ),
+            None => println!("Game sandboxes get namespaces, scratch dirs are in {}", scratch_root.display()),
+        }
+        // a concurrent load may have prepared it first, both found the same
+        let _ = self.host.set(SandboxHost { scratch_root, unavailable });
+        Ok(())
+    }
+    fn host(&self) -> io::Result<&SandboxHost> {
+        self.host.get().ok_or_else(|| io::Error::other("the sandbox is not prepared"))
+    }
+    /// Sets up the namespaces of a sandbox in a throwaway child, failing as a launch would
+    fn probe(scratch_root: &Path, runtime_dir: &Path) -> io::Result<()> {
+        let scratch = scratch_root.join(".probe");
+        fs::create_dir_all(&scratch)?;
+        let prepared = Prepared::new(scratch_root, &scratch, runtime_dir)?;
+        let mut command = StdCommand::new("true");
+        // SAFETY: enter only makes async-signal-safe calls, and the child exits before it would exec
+        unsafe {
+            std::os::unix::process::CommandExt::pre_exec(&mut command, move || {
+                prepared.enter()?;
+                libc::_exit(0)
+            });
+        }
+        let status = command.spawn().and_then(|mut child| child.wait());
+        let _ = fs::remove_dir(&scratch);
+        match status? {
+            status if status.success() => Ok(()),
+            status => Err(io::Error::other(format!("probe exited with {}", status))),
+        }
+    }
+    /// Scratch dir of a match's game, once `prepare` succeeded
+    pub fn scratch_dir(&self, match_id: u32) -> io::Result<PathBuf> {
+        Ok(self.host()?.scratch_root.join(format!("match-{}", match_id)))
+    }
+    /// Makes `command` start the game in its sandbox with `scratch` as its scratch dir, which must exist. Returns
+    /// whether the game gets namespaces
+    pub fn apply(&self, config: &SandboxConfig, command: &mut Command, scratch: &Path) -> io::Result<bool> {
+        let host = self.host()?;
+        command.env("HOME", scratch).env("TMPDIR", "/tmp");
+        let namespaces = (config.namespaces && host.unavailable.is_none())
+            .then(|| Prepared::new(&host.scratch_root, scratch, &self.runtime_dir))
+            .transpose()?;
+        if namespaces.is_none() {
+            // the game's temp files go to its scratch dir, the host's /tmp is shared
+            command.env("TMPDIR", scratch);
+        }
+        let sandboxed = namespaces.is_some();
+        let mut filter = config.seccomp.then(seccomp_filter).flatten();
+        // SAFETY: the closure only makes async-signal-safe calls and touches memory prepared before the fork
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(prepared) = &namespaces {
+                    prepared.enter()?;
+                    fork_game()?;
+                }
+                // last, it blocks the calls setting up the namespaces
+                restrict(&mut filter)
+            });
+        }
+        Ok(sandboxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `filter` the way the kernel would for a call of `nr` with `flags` as its first argument on `arch`
+    fn run_filter(filter: &[libc::sock_filter], arch: u32, nr: libc::c_long, flags: libc::c_int) -> u32 {
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = filter[pc];
+            pc += 1;
+            match instruction.code as u32 {
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    accumulator = match instruction.k as usize {
+                        offset if offset == std::mem::offset_of!(libc::seccomp_data, arch) => arch,
+                        offset if offset == std::mem::offset_of!(libc::seccomp_data, nr) => nr as u32,
+                        // the lower half of the first argument on little endian architectures
+                        offset if offset == std::mem::offset_of!(libc::seccomp_data, args) => flags as u32,
+                        offset => panic!("unexpected load at {}", offset),
+                    }
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    pc += if accumulator == instruction.k { instruction.jt } else { instruction.jf } as usize;
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K => {
+                    pc += if accumulator & instruction.k != 0 {
+                        instruction.jt
+                    } else {
+                        instruction.jf
+                    } as usize;
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return instruction.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn seccomp_filter_blocks_namespaces_and_listed_calls() {
+        let (Some(filter), Some(arch)) = (seccomp_filter(), AUDIT_ARCH) else {
+            return;
+        };
+        let eperm = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        for syscall in BLOCKED_SYSCALLS {
+            assert_eq!(run_filter(&filter, arch, *syscall, 0), eperm, "syscall {} is not blocked", syscall);
+        }
+        assert_eq!(run_filter(&filter, arch, libc::SYS_read, 0), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run_filter(&filter, arch, libc::SYS_clone, libc::SIGCHLD), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run_filter(&filter, arch, libc::SYS_clone, libc::CLONE_NEWUSER | libc::SIGCHLD), eperm);
+        assert_eq!(run_filter(&filter, arch, libc::SYS_clone, libc::CLONE_NEWNET), eperm);
+        assert_eq!(
+            run_filter(&filter, arch, libc::SYS_clone3, 0),
+            libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32
+        );
+        assert_eq!(run_filter(&filter, arch, (X32_SYSCALL_BIT as libc::c_long) | libc::SYS_read, 0), eperm);
+        assert_eq!(run_filter(&filter, !arch, libc::SYS_read, 0), libc::SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn sandbox_config_defaults_to_everything() {
+        let config: SandboxConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.namespaces && config.seccomp);
+        let config: SandboxConfig = serde_json::from_str(r#"{ "namespaces": false }"#).unwrap();
+        assert!(!config.namespaces && config.seccomp);
+    }
+
+    #[test]
+    fn scratch_dirs_are_not_handed_out_before_prepare_or_under_tmp() {
+        let sandbox = Sandbox {
+            root: PathBuf::from(format!("/tmp/sandbox-test-{}", crate::utils::random_token())),
+            runtime_dir: PathBuf::from("runtime"),
+            host: OnceLock::new(),
+        };
+        assert!(sandbox.scratch_dir(1).is_err());
+        assert_eq!(sandbox.prepare().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(sandbox.scratch_dir(1).is_err());
+        fs::remove_dir(&sandbox.root).unwrap();
+
+        // what prepare found once it succeeded, whether or not namespaces work
+        let _ = sandbox.host.set(SandboxHost {
+            scratch_root: PathBuf::from("/srv/scratch"),
+            unavailable: Some(String::from("no user namespaces")),
+        });
+        assert_eq!(sandbox.scratch_dir(7).unwrap(), PathBuf::from("/srv/scratch/match-7"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs unprivileged user namespaces, run with --ignored where they are allowed"]
+    async fn namespaced_games_run_in_their_scratch_dir() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("target/sandbox-test-{}", crate::utils::random_token()));
+        let sandbox = Sandbox {
+            root: root.clone(),
+            runtime_dir: env::temp_dir(),
+            host: OnceLock::new(),
+        };
+        sandbox.prepare().unwrap();
+        assert_eq!(sandbox.host().unwrap().unavailable, None);
+        let scratch = sandbox.scratch_dir(1).unwrap();
+        fs::create_dir_all(&scratch).unwrap();
+        let mut command = Command::new("sh");
+        // the game is pid 1 of its own namespace, sees its scratch dir as HOME and everything else read-only
+        command.args(["-c", "test \"$$\" = 1 && touch \"$HOME/written\" && ! touch /written 2>/dev/null"]);
+        let config: SandboxConfig = serde_json::from_str("{}").unwrap();
+        assert!(sandbox.apply(&config, &mut command, &scratch).unwrap());
+        assert!(command.status().await.unwrap().success());
+        assert!(scratch.join("written").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
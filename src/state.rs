@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::InvalidMatchStateError;
+use crate::Match;
+
+/// Lifecycle of a match. Every change goes through `transition`:
+///
+/// ```text
+/// OPEN ──min_players──▶ READYING ──all ready──▶ STARTING ──accepts connections──▶ PLAYING ──result──▶ FINISHED
+///  ▲ │              │    │                  ▲  │                              ├──no winner / launch failed──▶ ABORTED
+///  │ │              │    └─at cap─▶ QUEUED ─┘  └──did not start in time / exited──────────────────────────▶ ABORTED
+///  │ └─last leaves──┼──▶ CANCELLED ◀──nobody ready in time
+///  └─player leaves / ready check kicks someone
+/// OPEN, QUEUED, PLAYING ──timeout──▶ EXPIRED
+/// OPEN, READYING, QUEUED ──drain──▶ CANCELLED
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MatchState {
+    OPEN,
+    READYING,
+    /// Everyone is ready, the game waits in the launch queue for a free slot, see `queue`
+    QUEUED,
+    /// The game process is started, players are told to connect once it accepts connections
+    STARTING,
+    PLAYING,
+    /// The game reported or exited with a winner, the pot was paid out
+    FINISHED,
+    /// Every player left before the game started, or the server drained before it did
+    CANCELLED,
+    /// Timed out and removed by the reaper, stakes were refunded
+    EXPIRED,
+    /// The game could not be decided or failed to launch, stakes were refunded
+    ABORTED,
+}
+
+impl MatchState {
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            MatchState::FINISHED | MatchState::CANCELLED | MatchState::EXPIRED | MatchState::ABORTED
+        )
+    }
+    pub fn can_transition_to(self, next: MatchState) -> bool {
+        use MatchState::*;
+        matches!(
+            (self, next),
+            (OPEN, READYING)
+                | (OPEN, CANCELLED)
+                | (OPEN, EXPIRED)
+                | (READYING, OPEN)
+                | (READYING, STARTING)
+                | (READYING, QUEUED)
+                | (READYING, CANCELLED)
+                | (QUEUED, STARTING)
+                | (QUEUED, CANCELLED)
+                | (QUEUED, EXPIRED)
+                | (STARTING, PLAYING)
+                | (STARTING, FINISHED)
+                | (STARTING, ABORTED)
+                | (PLAYING, FINISHED)
+                | (PLAYING, ABORTED)
+                | (PLAYING, EXPIRED)
+        )
+    }
+}
+
+/// Sent to `/updates` subscribers whenever the match changes
+#[derive(Serialize, Debug, Clone)]
+pub struct MatchUpdate {
+    pub state: MatchState,
+    pub ready: Vec<bool>,
+    pub players: Vec<String>,
+    pub port: u32,
+    /// The ready check deadline while `READYING`
+    pub expiry_time: u64,
+    /// Players kicked by the last ready check
+    pub kicked: Vec<String>,
+    /// Every port of the match by name, `port` is the `game` one
+    pub ports: BTreeMap<String, u32>,
+    /// Position in the launch queue while `QUEUED`, 1 being next
+    pub queue_position: Option<usize>,
+}
+
+/// Moves the match to `next`, resets its expiry for the new state and notifies subscribers
+pub fn transition(game: &mut Match, next: MatchState) -> Result<(), InvalidMatchStateError> {
+    if !game.state.can_transition_to(next) {
+        println!("Match {} cannot go from {:?} to {:?}", game.id, game.state, next);
+        return Err(InvalidMatchStateError);
+    }
+    println!("Match {}: {:?} -> {:?}", game.id, game.state, next);
+    game.state = next;
+    game.expiry_time = game.definition.timeouts.expiry_for(next);
+    if next.is_terminal() {
+        // the port is free once nothing else holds the lease, see `PortLease`
+        game.port_lease = None;
+    }
+    if next == MatchState::READYING {
+        game.kicked.clear();
+    }
+    if next != MatchState::QUEUED {
+        game.queue_position = None;
+    }
+    broadcast(game);
+    Ok(())
+}
+
+/// Pushes the current state to `/updates` subscribers. Having none is fine
+pub fn broadcast(game: &Match) {
+    game.state_channel.send_replace(MatchUpdate {
+        state: game.state,
+        ready: game.ready.clone(),
+        players: game.players.clone(),
+        port: game.port,
+        expiry_time: game.expiry_time,
+        kicked: game.kicked.clone(),
+        ports: game.ports.clone(),
+        queue_position: game.queue_position,
+    });
+}
+
+/// Ends a ready check that ran out: players who are not ready are removed and the match goes back to `OPEN`,
+/// or is cancelled when nobody was ready. A match that still has enough players starts a new ready check.
+/// Returns the ids of the kicked players, their stakes are still escrowed
+pub fn kick_unready(game: &mut Match) -> Result<Vec<u64>, InvalidMatchStateError> {
+    if game.state != MatchState::READYING {
+        return Err(InvalidMatchStateError);
+    }
+    let mut kicked_ids = Vec::new();
+    let mut kicked = Vec::new();
+    for i in (0..game.players.len()).rev() {
+        if !game.ready[i] {
+            let (user_id, username) = remove_player(game, i);
+            kicked_ids.push(user_id);
+            kicked.push(username);
+        }
+    }
+    println!("Ready check of match {} kicked {:?}", game.id, kicked);
+    // whoever stayed readies again in the next ready check
+    game.ready.iter_mut().for_each(|r| *r = false);
+    if game.players.is_empty() {
+        transition(game, MatchState::CANCELLED)?;
+    } else {
+        transition(game, MatchState::OPEN)?;
+        start_ready_check(game)?;
+    }
+    // set last, a new ready check clears it
+    game.kicked = kicked;
+    broadcast(game);
+    Ok(kicked_ids)
+}
+
+/// Whether the match has the players its game needs to start: `min_players`, and in team games a player on every
+/// team with no team more than one player ahead of another. Teams can get lopsided when players leave, the ones
+/// joining then fill the smaller teams first, see `add_player`
+pub fn has_enough_players(game: &Match) -> bool {
+    let definition = &game.definition;
+    if game.players.len() < definition.min_players {
+        return false;
+    }
+    // every player is on their own team, there is nothing to balance
+    if definition.teams == definition.max_players {
+        return true;
+    }
+    let sizes: Vec<usize> = (0..definition.teams)
+        .map(|team| game.teams.iter().filter(|t| **t == team).count())
+        .collect();
+    let smallest = sizes.iter().min().copied().unwrap_or(0);
+    let largest = sizes.iter().max().copied().unwrap_or(0);
+    smallest > 0 && largest - smallest <= 1
+}
+
+/// Moves an `OPEN` match to `READYING` once it has enough players, see `has_enough_players`.
+/// Returns whether the ready check started
+pub fn start_ready_check(game: &mut Match) -> Result<bool, InvalidMatchStateError> {
+    if game.state != MatchState::OPEN || !has_enough_players(game) {
+        return Ok(false);
+    }
+    transition(game, MatchState::READYING)?;
+    Ok(true)
+}
+
+/// Adds a player to the team with the fewest players, the lowest one on a tie
+pub fn add_player(game: &mut Match, user_id: u64, username: String) {
+    let team = (0..game.definition.teams)
+        .min_by_key(|team| game.teams.iter().filter(|t| *t == team).count())
+        .unwrap_or(0);
+    game.players.push(username);
+    game.player_ids.push(user_id);
+    game.ready.push(false);
+    game.teams.push(team);
+}
+/// Removes the player at `index`, returning their user id and username
+pub fn remove_player(game: &mut Match, index: usize) -> (u64, String) {
+    game.ready.remove(index);
+    game.teams.remove(index);
+    (game.player_ids.remove(index), game.players.remove(index))
+}
+
+/// Players may join until the match is full, also during its ready check
+pub fn can_join(game: &Match, username: &str) -> bool {
+    matches!(game.state, MatchState::OPEN | MatchState::READYING)
+        && game.players.len() < game.definition.max_players
+        && !game.players.iter().any(|p| p == username)
+}
+pub fn can_leave(game: &Match, username: &str) -> bool {
+    matches!(game.state, MatchState::OPEN | MatchState::READYING) && game.players.iter().any(|p| p == username)
+}
+/// Players only ready up once the match has `min_players`, so a lone player can't launch it
+pub fn can_ready(game: &Match) -> bool {
+    game.state == MatchState::READYING
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MatchState::{self, *};
+    use super::*;
+    use crate::catalogue::GameDefinition;
+
+    const ALL: [MatchState; 9] = [OPEN, READYING, QUEUED, STARTING, PLAYING, FINISHED, CANCELLED, EXPIRED, ABORTED];
+
+    #[test]
+    fn terminal_states_have_no_way_out() {
+        for state in ALL.into_iter().filter(|s| s.is_terminal()) {
+            assert!(ALL.iter().all(|next| !state.can_transition_to(*next)), "{:?} can be left", state);
+        }
+        assert_eq!(ALL.iter().filter(|s| s.is_terminal()).count(), 4);
+    }
+
+    #[test]
+    fn matches_follow_the_lifecycle() {
+        let path = [OPEN, READYING, QUEUED, STARTING, PLAYING, FINISHED];
+        for pair in path.windows(2) {
+            assert!(pair[0].can_transition_to(pair[1]), "{:?} -> {:?}", pair[0], pair[1]);
+        }
+        assert!(READYING.can_transition_to(STARTING));
+        assert!(READYING.can_transition_to(OPEN));
+    }
+
+    #[test]
+    fn illegal_transitions_are_rejected() {
+        assert!(!OPEN.can_transition_to(PLAYING));
+        assert!(!OPEN.can_transition_to(STARTING));
+        assert!(!PLAYING.can_transition_to(OPEN));
+        assert!(!PLAYING.can_transition_to(CANCELLED));
+        assert!(!STARTING.can_transition_to(EXPIRED));
+        assert!(!READYING.can_transition_to(EXPIRED));
+        assert!(ALL.iter().all(|state| !state.can_transition_to(*state)));
+    }
+
+    /// An `OPEN` match of a game for 2 to 4 players in 2 teams, joined by `players`
+    fn team_match(players: &[&str]) -> Match {
+        let mut game = Match::for_tests(1, GameDefinition::for_tests(r#""max_players": 4, "teams": 2"#), &[]);
+        for (i, player) in players.iter().enumerate() {
+            add_player(&mut game, i as u64 + 1, player.to_string());
+        }
+        game
+    }
+
+    #[test]
+    fn players_join_the_smallest_team() {
+        let mut game = team_match(&["alice", "bobby", "carol"]);
+        assert_eq!(game.teams, vec![0, 1, 0]);
+        remove_player(&mut game, 0);
+        add_player(&mut game, 4, String::from("dave"));
+        assert_eq!(game.teams, vec![1, 0, 0]);
+        assert_eq!(game.player_ids, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn ready_checks_need_min_players_on_balanced_teams() {
+        let mut game = team_match(&["alice"]);
+        assert!(!has_enough_players(&game));
+        assert!(!start_ready_check(&mut game).unwrap());
+        add_player(&mut game, 2, String::from("bobby"));
+        assert!(has_enough_players(&game));
+        // a team left empty, or one more than a player ahead of another
+        game.teams = vec![0, 0];
+        assert!(!has_enough_players(&game));
+        add_player(&mut game, 3, String::from("carol"));
+        add_player(&mut game, 4, String::from("dave"));
+        game.teams = vec![0, 0, 0, 1];
+        assert!(!has_enough_players(&game));
+        game.teams = vec![0, 0, 1, 1];
+        assert!(start_ready_check(&mut game).unwrap());
+        assert_eq!(game.state, READYING);
+        // only an open match starts one
+        assert!(!start_ready_check(&mut game).unwrap());
+    }
+
+    #[test]
+    fn kicking_unready_players_restarts_the_ready_check() {
+        let mut game = team_match(&["alice", "bobby", "carol"]);
+        start_ready_check(&mut game).unwrap();
+        game.ready = vec![true, false, true];
+        assert_eq!(kick_unready(&mut game).unwrap(), vec![2]);
+        assert_eq!(game.players, vec!["alice", "carol"]);
+        assert_eq!(game.kicked, vec!["bobby"]);
+        // alice and carol were on the same team, so it waits for another player
+        assert_eq!(game.state, OPEN);
+        add_player(&mut game, 4, String::from("dave"));
+        assert!(start_ready_check(&mut game).unwrap());
+        assert!(game.kicked.is_empty());
+        game.ready = vec![true, true, false];
+        assert_eq!(kick_unready(&mut game).unwrap(), vec![4]);
+        assert_eq!(game.state, OPEN);
+
+        let mut game = Match::for_tests(1, GameDefinition::for_tests(r#""max_players": 3"#), &["alice", "bobby", "carol"]);
+        start_ready_check(&mut game).unwrap();
+        game.ready = vec![true, true, false];
+        assert_eq!(kick_unready(&mut game).unwrap(), vec![3]);
+        assert_eq!((game.state, game.ready.clone()), (READYING, vec![false, false]));
+    }
+
+    #[test]
+    fn a_ready_check_nobody_answers_cancels_the_match() {
+        let mut game = team_match(&["alice", "bobby"]);
+        assert!(kick_unready(&mut game).is_err());
+        start_ready_check(&mut game).unwrap();
+        assert_eq!(kick_unready(&mut game).unwrap(), vec![2, 1]);
+        assert_eq!(game.state, CANCELLED);
+        assert!(game.players.is_empty());
+    }
+}
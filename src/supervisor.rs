@@ -0,0 +1,884 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::process::Child;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::catalogue::GameDefinition;
+use crate::launch::{build_command, read_report, remove_launch_file, remove_scratch_dir, LaunchConfig, LaunchDocument};
+use crate::ledger::Stake;
+use crate::limits::{CgroupTenants, LimitExceeded};
+use crate::outcome::{GameResultReport, OutcomeSource};
+use crate::recovery::{process_start_ticks, ProcessRecord, ProcessRecords};
+use crate::sandbox::Sandbox;
+use crate::utils::now_secs;
+
+const DEFAULT_GAME_LOG_DIR: &str = "logs";
+const DEFAULT_GAME_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_GAME_LOG_FILES: usize = 5;
+/// How long exited processes stay listed by `/admin/processes`
+const EXITED_RETENTION_SECS: u64 = 600;
+/// Line a game prints on stdout once it accepts connections, see `ReadyProbe::Stdout`
+const READY_LINE: &str = "READY";
+/// How often an adopted game process is checked for having exited
+const ORPHAN_POLL_MILLIS: u64 = 500;
+/// How often game output files are checked for new lines
+const OUTPUT_POLL_MILLIS: u64 = 200;
+/// How much of the end of its stdout file is searched for the last line a game printed
+const LAST_LINE_MAX_BYTES: u64 = 64 * 1024;
+
+/// How the supervisor tells that a game accepts connections
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadyProbe {
+    /// A TCP connection to the `game` port succeeds
+    #[default]
+    Tcp,
+    /// The game prints `READY` on its own line of stdout, for games that only listen on UDP
+    Stdout,
+    /// The game is considered ready as soon as it is started
+    None,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessState {
+    /// Started, not accepting connections yet
+    Starting,
+    Running,
+    Exited,
+}
+
+/// A supervised game process, as listed by `/admin/processes`
+#[derive(Serialize, Debug, Clone)]
+pub struct ProcessStatus {
+    pub match_id: u32,
+    pub game_type: String,
+    pub pid: Option<u32>,
+    pub state: ProcessState,
+    pub started_at: u64,
+    pub uptime_secs: u64,
+    pub ended_at: Option<u64>,
+    pub exit_code: Option<i32>,
+    /// Signal the process was terminated by
+    pub signal: Option<i32>,
+    pub limit_exceeded: Option<LimitExceeded>,
+    /// Where its output is logged, `None` when it is printed with the server log
+    pub stdout_log: Option<String>,
+    pub stderr_log: Option<String>,
+    /// Started by a previous run of the server, its output and exit code are not known
+    pub adopted: bool,
+    /// Running in namespaces of its own, see `sandbox`
+    pub sandboxed: bool,
+}
+
+/// How a game process ended. `report` is the result it wrote to its result file or, failing that,
+/// printed as its last line of stdout. The exit code is only used when there is none
+pub struct GameExit {
+    pub exit_code: i32,
+    pub report: Option<(OutcomeSource, GameResultReport)>,
+    /// False when the game exited or was stopped before it accepted connections
+    pub started: bool,
+    /// Set when the game exited because it went over one of its `limits`
+    pub limit_exceeded: Option<LimitExceeded>,
+}
+
+/// Where game stdout and stderr are written, `GAME_LOG_DIR/match-<id>.stdout.log` and `.stderr.log`. Lines go to
+/// the server's console when unset
+pub struct GameLogConfig {
+    dir: Option<PathBuf>,
+    /// Size a log reaches before it is rotated to `.1`, `.2` and so on
+    max_bytes: u64,
+    /// How many rotated files of a log are kept
+    files: usize,
+}
+
+impl GameLogConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dir: match env::var("GAME_LOG_DIR") {
+                Ok(dir) if dir.is_empty() => None,
+                Ok(dir) => Some(PathBuf::from(dir)),
+                Err(_) => Some(PathBuf::from(DEFAULT_GAME_LOG_DIR)),
+            },
+            max_bytes: env::var("GAME_LOG_MAX_BYTES")
+                .map(|v| v.parse().expect("Invalid GAME_LOG_MAX_BYTES"))
+                .unwrap_or(DEFAULT_GAME_LOG_MAX_BYTES),
+            files: env::var("GAME_LOG_FILES")
+                .map(|v| v.parse().expect("Invalid GAME_LOG_FILES"))
+                .unwrap_or(DEFAULT_GAME_LOG_FILES),
+        }
+    }
+    /// The output files of the game of `match_id`, in the runtime dir when there is no log dir
+    fn output(&self, match_id: u32, runtime_dir: &Path) -> GameOutput {
+        let (dir, echo) = match &self.dir {
+            Some(dir) => (dir.as_path(), false),
+            None => (runtime_dir, true),
+        };
+        GameOutput {
+            stdout: dir.join(format!("match-{}.stdout.log", match_id)),
+            stderr: dir.join(format!("match-{}.stderr.log", match_id)),
+            echo,
+        }
+    }
+    /// When and how an output file is rotated, the lines of echoed output are already in the server log
+    fn rotation(&self, output: &GameOutput) -> (u64, usize) {
+        (self.max_bytes, if output.echo { 0 } else { self.files })
+    }
+}
+
+/// Files a game writes its stdout and stderr to. The game holds them open itself, so its output and the last line
+/// of its stdout outlive a restart of the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameOutput {
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    /// Without `GAME_LOG_DIR` the files are in the runtime dir, their lines are printed with the server log and
+    /// they are removed once the game is gone
+    pub echo: bool,
+}
+
+impl GameOutput {
+    /// Opens both files for the game to append to
+    fn open(&self) -> Result<(File, File), std::io::Error> {
+        let open = |path: &Path| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            OpenOptions::new().create(true).append(true).open(path)
+        };
+        Ok((open(&self.stdout)?, open(&self.stderr)?))
+    }
+    /// The last non-empty line the game printed on stdout, looking into the last rotated file when the current
+    /// one was just emptied
+    pub fn last_stdout_line(&self) -> Option<String> {
+        last_line(&self.stdout).or_else(|| last_line(&rotated(&self.stdout, 1)))
+    }
+    /// `path` as listed by `/admin/processes`, unless it is only echoed
+    fn log_path(&self, path: &Path) -> Option<String> {
+        (!self.echo).then(|| path.display().to_string())
+    }
+    /// Removes the files of a game that is gone, unless they are its logs
+    pub fn clean_up(&self) {
+        if !self.echo {
+            return;
+        }
+        for path in [&self.stdout, &self.stderr] {
+            if let Err(e) = fs::remove_file(path).or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) }) {
+                println!("Failed to remove game output {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// `path` with `.n` appended, where its `n`th rotated copy is kept
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+}
+
+/// The last non-empty line of the end of a file
+fn last_line(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(LAST_LINE_MAX_BYTES))).ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+    String::from_utf8_lossy(&tail)
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .map(str::to_string)
+}
+
+/// Copies a log the game still writes to aside as `.1`, moving older copies up and keeping `files` of them, then
+/// empties it. The game appends, so it carries on at the start of the emptied file
+fn copy_truncate(path: &Path, files: usize) -> Result<(), std::io::Error> {
+    if files > 0 {
+        for n in (1..files).rev() {
+            let from = rotated(path, n);
+            if from.exists() {
+                fs::rename(&from, rotated(path, n + 1))?;
+            }
+        }
+        fs::copy(path, rotated(path, 1))?;
+    }
+    OpenOptions::new().write(true).open(path)?.set_len(0)
+}
+
+/// Reads what a game appends to one of its output files
+struct OutputFollower {
+    path: PathBuf,
+    stream: &'static str,
+    offset: u64,
+    /// The start of a line the game has not finished yet
+    partial: Vec<u8>,
+}
+
+impl OutputFollower {
+    /// Starts at the current end of the file, what is already in it was written before
+    fn new(path: &Path, stream: &'static str) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            stream,
+            offset: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            partial: Vec::new(),
+        }
+    }
+    /// The lines completed since the last call, and the size of the file
+    fn read_lines(&mut self) -> Result<(Vec<String>, u64), std::io::Error> {
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            // emptied by a rotation
+            self.offset = 0;
+            self.partial.clear();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut read = Vec::new();
+        file.read_to_end(&mut read)?;
+        self.offset += read.len() as u64;
+        self.partial.extend_from_slice(&read);
+        let mut lines = Vec::new();
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+        Ok((lines, self.offset))
+    }
+}
+
+/// Follows a game's output file until the sender of `done` is dropped, printing its lines with the server log when `echo`,
+/// notifying `ready` on a `READY` line of stdout and rotating the file once it reaches `max_bytes`
+fn follow(
+    mut follower: OutputFollower,
+    match_id: u32,
+    echo: bool,
+    (max_bytes, files): (u64, usize),
+    ready: Arc<Notify>,
+    mut done: watch::Receiver<()>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let last = tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(OUTPUT_POLL_MILLIS)) => false,
+                _ = done.changed() => true,
+            };
+            match follower.read_lines() {
+                Ok((lines, size)) => {
+                    for line in lines {
+                        if echo {
+                            println!("Game {} {}: {}", match_id, follower.stream, line);
+                        }
+                        if follower.stream == "stdout" && line.trim() == READY_LINE {
+                            ready.notify_one();
+                        }
+                    }
+                    // the last line of stdout is read back from the file once the game is gone
+                    if !last && size >= max_bytes {
+                        if let Err(e) = copy_truncate(&follower.path, files) {
+                            println!("Failed to rotate game log {}: {}", follower.path.display(), e);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => println!("Failed to read game output {}: {}", follower.path.display(), e),
+            }
+            if last {
+                break;
+            }
+        }
+    })
+}
+
+/// Resolves once the game accepts connections according to `probe`
+async fn wait_until_ready(probe: ReadyProbe, port: u32, ready_line: &Notify) {
+    match probe {
+        ReadyProbe::Tcp => {
+            while TcpStream::connect(("127.0.0.1", port as u16)).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+            }
+        }
+        ReadyProbe::Stdout => ready_line.notified().await,
+        ReadyProbe::None => {}
+    }
+}
+
+/// Asks the child to stop with SIGTERM and kills it once `grace` has passed
+async fn terminate(child: &mut Child, grace: Duration) -> Result<ExitStatus, std::io::Error> {
+    if let Some(pid) = child.id() {
+        // SAFETY: the pid belongs to a child that has not been waited on, so it cannot have been reused
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+    match tokio::time::timeout(grace, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            println!("Game process {:?} ignored SIGTERM, killing it", child.id());
+            child.kill().await?;
+            child.wait().await
+        }
+    }
+}
+
+/// Asks a process the server did not start to stop with SIGTERM and kills it once `grace` has passed
+async fn terminate_orphan(record: &ProcessRecord, grace: Duration) {
+    record.signal(libc::SIGTERM);
+    let deadline = tokio::time::Instant::now() + grace;
+    let mut killed = false;
+    while record.is_alive() {
+        if !killed && tokio::time::Instant::now() >= deadline {
+            println!("Game process {} ignored SIGTERM, killing it", record.pid);
+            record.signal(libc::SIGKILL);
+            killed = true;
+        }
+        tokio::time::sleep(Duration::from_millis(ORPHAN_POLL_MILLIS)).await;
+    }
+}
+
+/// Every game process the server started, keyed by match id. Processes are started by `run`, which waits on
+/// them, enforces the game's startup and maximum runtime limits and captures their output. Each one is recorded
+/// in `records` until its match is settled, so the processes left by a previous run can be `adopt`ed
+pub struct Supervisor {
+    processes: Mutex<HashMap<u32, ProcessStatus>>,
+    /// The games in each cgroup of the catalogue's `limits`
+    cgroups: CgroupTenants,
+    logs: GameLogConfig,
+    records: ProcessRecords,
+    sandbox: Sandbox,
+}
+pub type SharedSupervisor = Arc<Supervisor>;
+
+/// What ended a phase of a supervised process
+enum Phase {
+    Exited(ExitStatus),
+    Ready,
+    Stop(&'static str),
+}
+
+impl Supervisor {
+    pub fn new(logs: GameLogConfig, records: ProcessRecords, sandbox: Sandbox) -> Self {
+        Self {
+            processes: Mutex::new(HashMap::new()),
+            cgroups: CgroupTenants::default(),
+            logs,
+            records,
+            sandbox,
+        }
+    }
+    pub fn from_env(runtime_dir: &Path) -> Self {
+        Self::new(GameLogConfig::from_env(), ProcessRecords::from_env(), Sandbox::from_env(runtime_dir))
+    }
+    pub fn records(&self) -> &ProcessRecords {
+        &self.records
+    }
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+    /// Status of every running process and of the ones that exited recently
+    pub fn statuses(&self) -> Vec<ProcessStatus> {
+        let now = now_secs();
+        let mut processes = self.processes.lock().unwrap();
+        processes.retain(|_, p| p.ended_at.is_none_or(|ended| ended + EXITED_RETENTION_SECS > now));
+        let mut statuses: Vec<ProcessStatus> = processes
+            .values()
+            .map(|p| ProcessStatus {
+                uptime_secs: p.ended_at.unwrap_or(now).saturating_sub(p.started_at),
+                ..p.clone()
+            })
+            .collect();
+        statuses.sort_by_key(|p| p.started_at);
+        statuses
+    }
+    fn update(&self, match_id: u32, f: impl FnOnce(&mut ProcessStatus)) {
+        if let Some(status) = self.processes.lock().unwrap().get_mut(&match_id) {
+            f(status);
+        }
+    }
+    /// Runs the game of `doc` until it exits. `on_ready` is awaited once the game accepts connections, a game that
+    /// doesn't within `startup_secs` is stopped. A running game is stopped after `max_runtime_secs` or when
+    /// `kill` is notified: SIGTERM first, SIGKILL after `kill_grace_secs`. The process is recorded with the match's
+    /// `stakes` once started, the caller removes the record after settling the match
+    pub async fn run<F, Fut>(
+        &self,
+        doc: &LaunchDocument,
+        config: &LaunchConfig,
+        game: &GameDefinition,
+        stakes: Vec<Stake>,
+        kill: &Notify,
+        on_ready: F,
+    ) -> Result<GameExit, std::io::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let (mut command, launch_file) = build_command(doc, config, game)?;
+        let player_names: Vec<&str> = doc.players.iter().map(|p| p.username.as_str()).collect();
+        println!(
+            "Starting {} game at port {} for players {} ({:?} launch)",
+            doc.game_type,
+            doc.port,
+            player_names.join(", "),
+            config.mode
+        );
+        // the game writes to the files itself rather than to pipes that would close with the server
+        let output = self.logs.output(doc.match_id, &config.runtime_dir);
+        let sandboxed = output
+            .open()
+            .and_then(|(stdout, stderr)| {
+                command.stdout(stdout).stderr(stderr).kill_on_drop(true);
+                // the limits go first, the cgroup can't be joined once the sandbox made every mount read-only
+                game.limits.apply(&mut command)
+            })
+            .and_then(|()| match (&game.sandbox, &doc.scratch_dir) {
+                (Some(config), Some(scratch)) => self.sandbox.apply(config, &mut command, Path::new(scratch)),
+                (Some(_), None) => Err(std::io::Error::other("sandboxed game has no scratch dir")),
+                (None, _) => Ok(false),
+            });
+        let result = match sandboxed {
+            Ok(sandboxed) => {
+                // completed with the pid once the game is spawned
+                let record = ProcessRecord {
+                    pid: 0,
+                    start_ticks: None,
+                    started_at: 0,
+                    sandboxed,
+                    launch_file: launch_file.clone(),
+                    output: output.clone(),
+                    doc: doc.clone(),
+                    stakes,
+                };
+                self.supervise(command, record, &output, game, kill, on_ready).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Some(path) = launch_file {
+            remove_launch_file(&path);
+        }
+        remove_scratch_dir(doc);
+        result
+    }
+    /// Lists the game process from before it is spawned until it is gone, as `Exited` however that happened
+    async fn supervise<F, Fut>(
+        &self,
+        command: tokio::process::Command,
+        mut record: ProcessRecord,
+        output: &GameOutput,
+        game: &GameDefinition,
+        kill: &Notify,
+        on_ready: F,
+    ) -> Result<GameExit, std::io::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let match_id = record.doc.match_id;
+        let followers = [(&output.stdout, "stdout"), (&output.stderr, "stderr")].map(|(path, stream)| OutputFollower::new(path, stream));
+        self.processes.lock().unwrap().insert(
+            match_id,
+            ProcessStatus {
+                match_id,
+                game_type: record.doc.game_type.clone(),
+                pid: None,
+                state: ProcessState::Starting,
+                started_at: now_secs(),
+                uptime_secs: 0,
+                ended_at: None,
+                exit_code: None,
+                signal: None,
+                limit_exceeded: None,
+                stdout_log: output.log_path(&output.stdout),
+                stderr_log: output.log_path(&output.stderr),
+                adopted: false,
+                sandboxed: record.sandboxed,
+            },
+        );
+        let ready_line = Arc::new(Notify::new());
+        // dropping the sender stops the followers
+        let (stop_following, following) = watch::channel(());
+        let followers = followers.map(|follower| {
+            follow(
+                follower,
+                match_id,
+                output.echo,
+                self.logs.rotation(output),
+                ready_line.clone(),
+                following.clone(),
+            )
+        });
+        let ended = self.wait_for_exit(command, &mut record, game, kill, on_ready, &ready_line).await;
+        self.update(match_id, |p| {
+            p.state = ProcessState::Exited;
+            p.ended_at = Some(now_secs());
+            if let Ok((status, limit_exceeded, _)) = &ended {
+                p.exit_code = status.code();
+                p.signal = status.signal();
+                p.limit_exceeded = *limit_exceeded;
+            }
+        });
+        drop(stop_following);
+        for follower in followers {
+            let _ = follower.await;
+        }
+        let last_line = output.last_stdout_line();
+        output.clean_up();
+        let (status, limit_exceeded, started) = ended?;
+        Ok(GameExit {
+            exit_code: status.code().unwrap_or(1000),
+            report: read_report(&record.doc, last_line.as_deref()),
+            started,
+            limit_exceeded,
+        })
+    }
+    /// Spawns the game and waits until it exits or is stopped. Returns how it ended, the limit it went over if any
+    /// and whether it accepted connections. The process is killed when this returns early with an error, as
+    /// dropping it does
+    async fn wait_for_exit<F, Fut>(
+        &self,
+        mut command: tokio::process::Command,
+        record: &mut ProcessRecord,
+        game: &GameDefinition,
+        kill: &Notify,
+        on_ready: F,
+        ready_line: &Notify,
+    ) -> Result<(ExitStatus, Option<LimitExceeded>, bool), std::io::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut limits = game.limits.watch(&self.cgroups);
+        let mut child = command.spawn()?;
+        if let Some(pid) = child.id() {
+            limits.follow(pid);
+            record.pid = pid;
+            record.start_ticks = process_start_ticks(pid);
+            record.started_at = now_secs();
+            self.records.insert(record.clone());
+        }
+        let doc = &record.doc;
+        self.update(doc.match_id, |p| p.pid = child.id());
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(serde_json::to_string(doc)?.as_bytes()).await?;
+            // dropping stdin closes it so the game sees the end of the document
+        }
+
+        let timeouts = &game.timeouts;
+        let grace = Duration::from_secs(timeouts.kill_grace_secs);
+        let runtime_limit = tokio::time::Instant::now() + Duration::from_secs(timeouts.max_runtime_secs);
+        let startup = tokio::select! {
+            status = child.wait() => Phase::Exited(status?),
+            _ = kill.notified() => Phase::Stop("killed"),
+            _ = tokio::time::sleep(Duration::from_secs(timeouts.startup_secs)) => Phase::Stop("did not start in time"),
+            _ = wait_until_ready(game.ready_probe, doc.port, ready_line) => Phase::Ready,
+        };
+        let started = matches!(startup, Phase::Ready);
+        let end = match startup {
+            Phase::Ready => {
+                println!("Game for match {} accepts connections", doc.match_id);
+                self.update(doc.match_id, |p| p.state = ProcessState::Running);
+                on_ready().await;
+                tokio::select! {
+                    status = child.wait() => Phase::Exited(status?),
+                    _ = kill.notified() => Phase::Stop("killed"),
+                    _ = tokio::time::sleep_until(runtime_limit) => Phase::Stop("ran out of time"),
+                }
+            }
+            other => other,
+        };
+        let (status, limit_exceeded) = match end {
+            // a signal the supervisor sent is not a limit
+            Phase::Exited(status) => (status, limits.exceeded(status)),
+            Phase::Stop(reason) => {
+                println!("Stopping game for match {}: {}", doc.match_id, reason);
+                (terminate(&mut child, grace).await?, None)
+            }
+            Phase::Ready => unreachable!("a running game only exits or is stopped"),
+        };
+        if let Some(limit) = limit_exceeded {
+            println!("Game for match {} went over its {:?} limit", doc.match_id, limit);
+        }
+        Ok((status, limit_exceeded, started))
+    }
+    /// Watches a game process a previous run of the server started until it exits, stopping it after
+    /// `max_runtime_secs` from its start or when `kill` is notified. Its output is followed from where it is now
+    pub async fn adopt(&self, record: &ProcessRecord, game: &GameDefinition, kill: &Notify) {
+        let match_id = record.doc.match_id;
+        // its OOM kills would count for the other games in its cgroup
+        let _limits = game.limits.watch(&self.cgroups);
+        let (stop_following, following) = watch::channel(());
+        let output = &record.output;
+        let followers: Vec<JoinHandle<()>> = [(&output.stdout, "stdout"), (&output.stderr, "stderr")]
+            .into_iter()
+            .map(|(path, stream)| {
+                let follower = OutputFollower::new(path, stream);
+                follow(
+                    follower,
+                    match_id,
+                    output.echo,
+                    self.logs.rotation(output),
+                    Arc::new(Notify::new()),
+                    following.clone(),
+                )
+            })
+            .collect();
+        self.processes.lock().unwrap().insert(
+            match_id,
+            ProcessStatus {
+                match_id,
+                game_type: record.doc.game_type.clone(),
+                pid: Some(record.pid),
+                state: ProcessState::Running,
+                started_at: record.started_at,
+                uptime_secs: 0,
+                ended_at: None,
+                exit_code: None,
+                signal: None,
+                limit_exceeded: None,
+                stdout_log: output.log_path(&output.stdout),
+                stderr_log: output.log_path(&output.stderr),
+                adopted: true,
+                sandboxed: record.sandboxed,
+            },
+        );
+        let timeouts = &game.timeouts;
+        let remaining = (record.started_at + timeouts.max_runtime_secs).saturating_sub(now_secs());
+        let runtime_limit = tokio::time::Instant::now() + Duration::from_secs(remaining);
+        let mut interval = tokio::time::interval(Duration::from_millis(ORPHAN_POLL_MILLIS));
+        let stop = loop {
+            tokio::select! {
+                _ = interval.tick() => if !record.is_alive() {
+                    break None;
+                },
+                _ = kill.notified() => break Some("killed"),
+                _ = tokio::time::sleep_until(runtime_limit) => break Some("ran out of time"),
+            }
+        };
+        if let Some(reason) = stop {
+            println!("Stopping game for match {}: {}", match_id, reason);
+            terminate_orphan(record, Duration::from_secs(timeouts.kill_grace_secs)).await;
+        }
+        self.update(match_id, |p| {
+            p.state = ProcessState::Exited;
+            p.ended_at = Some(now_secs());
+        });
+        drop(stop_following);
+        for follower in followers {
+            let _ = follower.await;
+        }
+    }
+    /// Stops a game process a previous run of the server started, see `OrphanPolicy::Kill`
+    pub async fn stop_orphan(&self, record: &ProcessRecord, kill_grace_secs: u64) {
+        terminate_orphan(record, Duration::from_secs(kill_grace_secs)).await;
+    }
+}
+
+#[cfg(test)]
+impl Supervisor {
+    /// A supervisor echoing game output to the console and keeping no process records
+    pub fn for_tests() -> SharedSupervisor {
+        let logs = GameLogConfig {
+            dir: None,
+            max_bytes: DEFAULT_GAME_LOG_MAX_BYTES,
+            files: DEFAULT_GAME_LOG_FILES,
+        };
+        Arc::new(Self::new(logs, ProcessRecords::new(None), Sandbox::from_env(&env::temp_dir())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::utils::random_token;
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("supervisor-test-{}", random_token()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn append(path: &Path, contents: &str) {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn output_goes_to_the_log_dir_or_is_echoed_from_the_runtime_dir() {
+        let logs = GameLogConfig {
+            dir: Some(PathBuf::from("/var/log/games")),
+            max_bytes: 1024,
+            files: 3,
+        };
+        let output = logs.output(42, Path::new("/run/games"));
+        assert_eq!(output.stdout, Path::new("/var/log/games/match-42.stdout.log"));
+        assert_eq!(output.stderr, Path::new("/var/log/games/match-42.stderr.log"));
+        assert!(!output.echo);
+        assert_eq!(logs.rotation(&output), (1024, 3));
+        assert_eq!(output.log_path(&output.stdout).as_deref(), Some("/var/log/games/match-42.stdout.log"));
+
+        let echoed = GameLogConfig { dir: None, ..logs }.output(42, Path::new("/run/games"));
+        assert_eq!(echoed.stdout, Path::new("/run/games/match-42.stdout.log"));
+        assert!(echoed.echo);
+        assert_eq!(echoed.log_path(&echoed.stdout), None);
+    }
+
+    #[test]
+    fn copy_truncate_keeps_the_newest_copies() {
+        let dir = temp_dir();
+        let log = dir.join("match-1.stdout.log");
+        for contents in ["first\n", "second\n", "third\n"] {
+            fs::write(&log, contents).unwrap();
+            copy_truncate(&log, 2).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&log).unwrap(), "");
+        assert_eq!(fs::read_to_string(rotated(&log, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated(&log, 2)).unwrap(), "second\n");
+        assert!(!rotated(&log, 3).exists());
+
+        // echoed output isn't kept
+        fs::write(&log, "fourth\n").unwrap();
+        copy_truncate(&log, 0).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "");
+        assert_eq!(fs::read_to_string(rotated(&log, 1)).unwrap(), "third\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn last_stdout_line_survives_a_rotation() {
+        let dir = temp_dir();
+        let output = GameOutput {
+            stdout: dir.join("match-1.stdout.log"),
+            stderr: dir.join("match-1.stderr.log"),
+            echo: true,
+        };
+        assert_eq!(output.last_stdout_line(), None);
+        output.open().unwrap();
+        append(&output.stdout, "READY\nsig {\"match_id\":1}\n\n");
+        assert_eq!(output.last_stdout_line().as_deref(), Some("sig {\"match_id\":1}"));
+        copy_truncate(&output.stdout, 1).unwrap();
+        assert_eq!(output.last_stdout_line().as_deref(), Some("sig {\"match_id\":1}"));
+
+        output.clean_up();
+        assert!(!output.stdout.exists() && !output.stderr.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn output_follower_reads_new_complete_lines() {
+        let dir = temp_dir();
+        let log = dir.join("match-1.stdout.log");
+        append(&log, "before the follower\n");
+        let mut follower = OutputFollower::new(&log, "stdout");
+        append(&log, "one\ntw");
+        assert_eq!(follower.read_lines().unwrap().0, vec!["one"]);
+        append(&log, "o\r\nthree\n");
+        assert_eq!(follower.read_lines().unwrap().0, vec!["two", "three"]);
+        assert!(follower.read_lines().unwrap().0.is_empty());
+
+        copy_truncate(&log, 1).unwrap();
+        append(&log, "after the rotation\n");
+        let (lines, size) = follower.read_lines().unwrap();
+        assert_eq!(lines, vec!["after the rotation"]);
+        assert_eq!(size, "after the rotation\n".len() as u64);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Runs `script` as the game of match `match_id` with `fields` added to its catalogue entry, returning how it
+    /// ended, whether it was reported ready and how `/admin/processes` lists it afterwards
+    async fn run_script(match_id: u32, script: &str, fields: &str) -> (Result<GameExit, std::io::Error>, bool, ProcessStatus) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir();
+        let executable = dir.join("game.sh");
+        fs::write(&executable, script).unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o700)).unwrap();
+        let game = GameDefinition::for_tests(&format!(r#""executable": "{}", {}"#, executable.display(), fields));
+        let doc = serde_json::json!({
+            "match_id": match_id,
+            "game_type": "test",
+            "port": 0,
+            "players": [{ "user_id": 1, "username": "alice", "token": "token-alice", "team": 0 }],
+            "teams": 1,
+            "ports": {},
+            "settings": null,
+            "result_url": "http://127.0.0.1:8080/end_match",
+            "result_secret": "secret",
+            "result_file": dir.join("result.json"),
+        });
+        let config = LaunchConfig {
+            mode: crate::launch::LaunchMode::Argv,
+            result_url: String::from("http://127.0.0.1:8080/end_match"),
+            runtime_dir: dir.clone(),
+        };
+        let supervisor = Supervisor::for_tests();
+        let ready = std::sync::atomic::AtomicBool::new(false);
+        let exit = supervisor
+            .run(
+                &serde_json::from_value(doc).unwrap(),
+                &config,
+                &game,
+                Vec::new(),
+                &Notify::new(),
+                || async {
+                    ready.store(true, std::sync::atomic::Ordering::SeqCst);
+                },
+            )
+            .await;
+        let status = supervisor.statuses().into_iter().find(|p| p.match_id == match_id).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (exit, ready.into_inner(), status)
+    }
+
+    #[tokio::test]
+    async fn games_that_do_not_start_in_time_are_stopped() {
+        let fields = r#""ready_probe": "stdout", "timeouts": { "startup_secs": 1, "kill_grace_secs": 1 }"#;
+        let started = tokio::time::Instant::now();
+        let (exit, ready, status) = run_script(1, "#!/bin/sh\necho booting\nexec sleep 30\n", fields).await;
+        let exit = exit.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!exit.started && !ready);
+        assert_eq!((status.state, status.signal), (ProcessState::Exited, Some(libc::SIGTERM)));
+        assert!(status.ended_at.is_some() && status.pid.is_some());
+    }
+
+    #[tokio::test]
+    async fn games_are_ready_once_they_print_ready() {
+        let fields = r#""ready_probe": "stdout", "timeouts": { "startup_secs": 5 }"#;
+        let (exit, ready, status) = run_script(2, "#!/bin/sh\necho booting\necho READY\nsleep 1\nexit 3\n", fields).await;
+        let exit = exit.unwrap();
+        assert!(exit.started && ready);
+        assert_eq!(exit.exit_code, 3);
+        assert_eq!((status.state, status.exit_code), (ProcessState::Exited, Some(3)));
+        // a line merely containing it does not count
+        let fields = r#""ready_probe": "stdout", "timeouts": { "startup_secs": 1, "kill_grace_secs": 1 }"#;
+        let (exit, ready, _) = run_script(3, "#!/bin/sh\necho NOT READY\nexec sleep 30\n", fields).await;
+        assert!(!exit.unwrap().started && !ready);
+    }
+
+    #[tokio::test]
+    async fn games_that_fail_to_spawn_are_listed_as_exited() {
+        let (exit, ready, status) = run_script(4, "#!/nonexistent/interpreter\n", r#""ready_probe": "none""#).await;
+        assert!(exit.is_err() && !ready);
+        assert_eq!(status.state, ProcessState::Exited);
+        assert!(status.ended_at.is_some() && status.pid.is_none() && status.exit_code.is_none());
+    }
+}
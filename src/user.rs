@@ -1,48 +1,58 @@
-use warp::{Filter, Rejection, Reply};
+use serde::Serialize;
+use warp::{Filter, Rejection};
 
+use std::sync::Arc;
+
+use crate::auth::SharedAuthProvider;
 use crate::error::UnauthorizedError;
+use crate::utils::constant_time_eq;
 
 pub struct User {
     pub id: u64,
     pub username: String,
+    pub display_name: String,
     pub auth_token: String,
+}
+
+/// Public view of an account, never includes the token or password hash. The balance comes from the wallet
+#[derive(Serialize)]
+pub struct Profile {
+    pub id: u64,
+    pub username: String,
+    pub display_name: String,
     pub balance: u64,
 }
-pub fn with_user() -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+pub fn with_user(auth: SharedAuthProvider) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::header::optional("Authorization")
+        .and_then(move |auth_header: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                let auth_header = auth_header.ok_or_else(|| warp::reject::custom(UnauthorizedError::new("missing authorization header")))?;
+                let token = auth_header
+                    .strip_prefix("Bearer ")
+                    .ok_or_else(|| warp::reject::custom(UnauthorizedError::new("authorization header is not a bearer token")))?;
+                auth.authenticate(token).map_err(warp::reject::custom)
+            }
+        })
+        .boxed()
+}
+/// Guards operator routes with the `ADMIN_TOKEN` bearer token. Everything is rejected when no token is configured
+pub fn with_admin(admin_token: Option<Arc<str>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
     warp::header::optional("Authorization")
-        .and_then(|auth_header: Option<String>| async move {
-            if let Some(auth) = auth_header {
-                if auth.starts_with("Bearer ") {
-                    // TODO: Add actual auth
-                    // Extract the token by removing "Bearer " prefix
-                    let token = auth.strip_prefix("Bearer ").unwrap().to_string();
-                    let user = User {
-                        id: 1,
-                        username: token.clone(),
-                        balance: 1000,
-                        auth_token: token,
-                    };
-                    Ok(user)
-                    // add back auth here
-                    // if token == "valid_token" {
-                    //     let user = User {
-                    //         id: 1,
-                    //         username: "Alice".to_string(),
-                    //         balance: 1000,
-                    //         auth_token: token,
-                    //     };
-                    //     Ok(user)
-                    // } else {
-                    //     Err(warp::reject::custom(UnauthorizedError)) // Custom error if token is invalid
-                    // }
-                } else {
-                    println!("Invalid auth");
-                    Err(warp::reject::custom(UnauthorizedError))
+        .and_then(move |auth_header: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                let admin_token = admin_token.ok_or_else(|| warp::reject::custom(UnauthorizedError::new("admin routes are disabled")))?;
+                let token = auth_header
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .ok_or_else(|| warp::reject::custom(UnauthorizedError::new("missing admin token")))?;
+                if !constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+                    return Err(warp::reject::custom(UnauthorizedError::new("invalid admin token")));
                 }
-            } else {
-                println!("No auth");
-                Err(warp::reject::custom(UnauthorizedError))
+                Ok::<(), Rejection>(())
             }
         })
+        .untuple_one()
         .boxed()
 }
@@ -1,28 +1,17 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use futures::lock::Mutex;
-
-pub fn game_type_to_path(game_type: &String) -> String {
-    if game_type == "knockout" {
-        String::from("knockout/KnockoutGame.x86_64")
-    } else {
-        return String::from("");
-    }
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
-pub struct NumberPool {
-    available: VecDeque<u32>,
+/// Random hex token for sessions and other bearer secrets
+pub fn random_token() -> String {
+    let mut buffer = [0u8; 32];
+    getrandom::fill(&mut buffer).expect("Failed to generate random token");
+    buffer.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-impl NumberPool {
-    pub fn new(range: std::ops::Range<u32>) -> Self {
-        Self { available: range.collect() }
-    }
-    pub fn get(&mut self) -> Option<u32> {
-        return self.available.pop_front();
-    }
-    pub fn release(&mut self, num: u32) {
-        self.available.push_back(num);
-    }
+/// Compares secrets without leaking how much of them matched through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
-pub type SharedNumberPool = Arc<Mutex<NumberPool>>;
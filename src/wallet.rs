@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::utils::{now_secs, random_token};
+
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 10;
+const HTTP_CONNECT_TIMEOUT_SECS: u64 = 3;
+
+#[derive(Debug)]
+pub enum WalletError {
+    InsufficientFunds,
+    UnknownReservation,
+    Backend(String),
+}
+
+/// Balance operations used for escrow and payouts. Reserved amounts are held out of the spendable
+/// balance until they are either captured (spent for good) or released (made spendable again)
+#[async_trait]
+pub trait WalletBackend: Send + Sync {
+    /// Spendable balance, not counting open reservations
+    async fn balance(&self, user_id: u64) -> Result<u64, WalletError>;
+    async fn reserve(&self, user_id: u64, amount: u64, reference: &str) -> Result<String, WalletError>;
+    async fn capture(&self, reservation_id: &str) -> Result<(), WalletError>;
+    async fn release(&self, reservation_id: &str) -> Result<(), WalletError>;
+    async fn credit(&self, user_id: u64, amount: u64, reference: &str) -> Result<(), WalletError>;
+}
+pub type SharedWallet = Arc<dyn WalletBackend>;
+
+struct Reservation {
+    user_id: u64,
+    amount: u64,
+}
+
+struct MemoryState {
+    balances: HashMap<u64, u64>,
+    reservations: HashMap<String, Reservation>,
+}
+
+/// Balances kept in process memory, every user starts with `starting_balance`
+pub struct InMemoryWallet {
+    state: Mutex<MemoryState>,
+    starting_balance: u64,
+}
+
+impl InMemoryWallet {
+    pub fn new(starting_balance: u64) -> Self {
+        Self {
+            state: Mutex::new(MemoryState {
+                balances: HashMap::new(),
+                reservations: HashMap::new(),
+            }),
+            starting_balance,
+        }
+    }
+}
+
+#[async_trait]
+impl WalletBackend for InMemoryWallet {
+    async fn balance(&self, user_id: u64) -> Result<u64, WalletError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(*state.balances.entry(user_id).or_insert(self.starting_balance))
+    }
+    async fn reserve(&self, user_id: u64, amount: u64, _reference: &str) -> Result<String, WalletError> {
+        let mut state = self.state.lock().unwrap();
+        let balance = state.balances.entry(user_id).or_insert(self.starting_balance);
+        *balance = balance.checked_sub(amount).ok_or(WalletError::InsufficientFunds)?;
+        let id = random_token();
+        state.reservations.insert(id.clone(), Reservation { user_id, amount });
+        Ok(id)
+    }
+    async fn capture(&self, reservation_id: &str) -> Result<(), WalletError> {
+        let mut state = self.state.lock().unwrap();
+        state.reservations.remove(reservation_id).ok_or(WalletError::UnknownReservation)?;
+        Ok(())
+    }
+    async fn release(&self, reservation_id: &str) -> Result<(), WalletError> {
+        let mut state = self.state.lock().unwrap();
+        let reservation = state.reservations.remove(reservation_id).ok_or(WalletError::UnknownReservation)?;
+        *state.balances.entry(reservation.user_id).or_insert(self.starting_balance) += reservation.amount;
+        Ok(())
+    }
+    async fn credit(&self, user_id: u64, amount: u64, _reference: &str) -> Result<(), WalletError> {
+        let mut state = self.state.lock().unwrap();
+        *state.balances.entry(user_id).or_insert(self.starting_balance) += amount;
+        Ok(())
+    }
+}
+
+/// Balances and open reservations stored in a local SQLite database. Queries run on the blocking pool
+pub struct SqliteWallet {
+    conn: Arc<Mutex<Connection>>,
+    starting_balance: u64,
+}
+
+impl SqliteWallet {
+    pub fn open(path: &str, starting_balance: u64) -> Result<Self, WalletError> {
+        let conn = Connection::open(path).map_err(sqlite_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS balances (
+                user_id INTEGER PRIMARY KEY,
+                balance INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS reservations (
+                id TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                amount INTEGER NOT NULL,
+                reference TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );",
+        )
+        .map_err(sqlite_error)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            starting_balance,
+        })
+    }
+    /// Runs `f` on the connection off the async runtime, rusqlite blocks on the database file
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, WalletError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, WalletError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| WalletError::Backend(format!("sqlite: {}", e)))?
+    }
+}
+
+fn ensure_account(conn: &Connection, user_id: u64, starting_balance: u64) -> Result<(), WalletError> {
+    conn.execute(
+        "INSERT OR IGNORE INTO balances (user_id, balance) VALUES (?1, ?2)",
+        params![user_id as i64, starting_balance as i64],
+    )
+    .map_err(sqlite_error)?;
+    Ok(())
+}
+
+fn sqlite_error(e: rusqlite::Error) -> WalletError {
+    WalletError::Backend(format!("sqlite: {}", e))
+}
+
+#[async_trait]
+impl WalletBackend for SqliteWallet {
+    async fn balance(&self, user_id: u64) -> Result<u64, WalletError> {
+        let starting_balance = self.starting_balance;
+        self.with_conn(move |conn| {
+            ensure_account(conn, user_id, starting_balance)?;
+            let balance: i64 = conn
+                .query_row("SELECT balance FROM balances WHERE user_id = ?1", params![user_id as i64], |row| {
+                    row.get(0)
+                })
+                .map_err(sqlite_error)?;
+            Ok(balance as u64)
+        })
+        .await
+    }
+    async fn reserve(&self, user_id: u64, amount: u64, reference: &str) -> Result<String, WalletError> {
+        let starting_balance = self.starting_balance;
+        let reference = reference.to_string();
+        self.with_conn(move |conn| {
+            ensure_account(conn, user_id, starting_balance)?;
+            let tx = conn.transaction().map_err(sqlite_error)?;
+            let debited = tx
+                .execute(
+                    "UPDATE balances SET balance = balance - ?2 WHERE user_id = ?1 AND balance >= ?2",
+                    params![user_id as i64, amount as i64],
+                )
+                .map_err(sqlite_error)?;
+            if debited == 0 {
+                return Err(WalletError::InsufficientFunds);
+            }
+            let id = random_token();
+            tx.execute(
+                "INSERT INTO reservations (id, user_id, amount, reference, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, user_id as i64, amount as i64, reference, now_secs() as i64],
+            )
+            .map_err(sqlite_error)?;
+            tx.commit().map_err(sqlite_error)?;
+            Ok(id)
+        })
+        .await
+    }
+    async fn capture(&self, reservation_id: &str) -> Result<(), WalletError> {
+        let reservation_id = reservation_id.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn
+                .execute("DELETE FROM reservations WHERE id = ?1", params![reservation_id])
+                .map_err(sqlite_error)?;
+            if deleted == 0 {
+                return Err(WalletError::UnknownReservation);
+            }
+            Ok(())
+        })
+        .await
+    }
+    async fn release(&self, reservation_id: &str) -> Result<(), WalletError> {
+        let reservation_id = reservation_id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(sqlite_error)?;
+            let reservation: Option<(i64, i64)> = tx
+                .query_row("SELECT user_id, amount FROM reservations WHERE id = ?1", params![reservation_id], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .optional()
+                .map_err(sqlite_error)?;
+            let (user_id, amount) = reservation.ok_or(WalletError::UnknownReservation)?;
+            tx.execute("DELETE FROM reservations WHERE id = ?1", params![reservation_id])
+                .map_err(sqlite_error)?;
+            tx.execute("UPDATE balances SET balance = balance + ?2 WHERE user_id = ?1", params![user_id, amount])
+                .map_err(sqlite_error)?;
+            tx.commit().map_err(sqlite_error)
+        })
+        .await
+    }
+    async fn credit(&self, user_id: u64, amount: u64, _reference: &str) -> Result<(), WalletError> {
+        let starting_balance = self.starting_balance;
+        self.with_conn(move |conn| {
+            ensure_account(conn, user_id, starting_balance)?;
+            conn.execute(
+                "UPDATE balances SET balance = balance + ?2 WHERE user_id = ?1",
+                params![user_id as i64, amount as i64],
+            )
+            .map_err(sqlite_error)?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[derive(Serialize)]
+struct AmountBody<'a> {
+    user_id: u64,
+    amount: u64,
+    reference: &'a str,
+}
+
+#[derive(Serialize)]
+struct ReservationBody<'a> {
+    reservation_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct BalanceResponse {
+    balance: u64,
+}
+
+#[derive(Deserialize)]
+struct ReserveResponse {
+    reservation_id: String,
+}
+
+/// Client for the external wallet service.
+///
+/// - `GET /balance/{user_id}` -> `{ "balance": u64 }`
+/// - `POST /reserve` `{ user_id, amount, reference }` -> `{ "reservation_id": string }`, 402 when funds are insufficient
+/// - `POST /capture` and `POST /release` `{ reservation_id }`, 404 when the reservation is unknown
+/// - `POST /credit` `{ user_id, amount, reference }`
+///
+/// Calls that take longer than `timeout` fail with `WalletError::Backend`
+pub struct HttpWallet {
+    client: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl HttpWallet {
+    pub fn new(base_url: &str, token: Option<String>, timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout.min(Duration::from_secs(HTTP_CONNECT_TIMEOUT_SECS)))
+            .build()
+            .expect("Failed to build wallet http client");
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let builder = self.client.request(method, format!("{}{}", self.base_url, path));
+        match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+    async fn post<T: Serialize + ?Sized>(&self, path: &str, body: &T) -> Result<reqwest::Response, WalletError> {
+        let response = self
+            .request(reqwest::Method::POST, path)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| WalletError::Backend(format!("http: {}", e)))?;
+        match response.status() {
+            StatusCode::PAYMENT_REQUIRED => Err(WalletError::InsufficientFunds),
+            StatusCode::NOT_FOUND => Err(WalletError::UnknownReservation),
+            status if !status.is_success() => Err(WalletError::Backend(format!("http: {} returned {}", path, status))),
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait]
+impl WalletBackend for HttpWallet {
+    async fn balance(&self, user_id: u64) -> Result<u64, WalletError> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/balance/{}", user_id))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| WalletError::Backend(format!("http: {}", e)))?;
+        let body: BalanceResponse = response.json().await.map_err(|e| WalletError::Backend(format!("http: {}", e)))?;
+        Ok(body.balance)
+    }
+    async fn reserve(&self, user_id: u64, amount: u64, reference: &str) -> Result<String, WalletError> {
+        let response = self.post("/reserve", &AmountBody { user_id, amount, reference }).await?;
+        let body: ReserveResponse = response.json().await.map_err(|e| WalletError::Backend(format!("http: {}", e)))?;
+        Ok(body.reservation_id)
+    }
+    async fn capture(&self, reservation_id: &str) -> Result<(), WalletError> {
+        self.post("/capture", &ReservationBody { reservation_id }).await.map(|_| ())
+    }
+    async fn release(&self, reservation_id: &str) -> Result<(), WalletError> {
+        self.post("/release", &ReservationBody { reservation_id }).await.map(|_| ())
+    }
+    async fn credit(&self, user_id: u64, amount: u64, reference: &str) -> Result<(), WalletError> {
+        self.post("/credit", &AmountBody { user_id, amount, reference }).await.map(|_| ())
+    }
+}
+
+pub fn wallet_from_env() -> SharedWallet {
+    let starting_balance = env::var("STARTING_BALANCE")
+        .map(|v| v.parse().expect("Invalid STARTING_BALANCE"))
+        .unwrap_or(0);
+    match env::var("WALLET_BACKEND").unwrap_or_else(|_| "memory".to_string()).as_str() {
+        "memory" => Arc::new(InMemoryWallet::new(starting_balance)),
+        "sqlite" => {
+            let path = env::var("WALLET_SQLITE_PATH").unwrap_or_else(|_| "wallet.db".to_string());
+            Arc::new(SqliteWallet::open(&path, starting_balance).expect("Failed to open wallet database"))
+        }
+        "http" => {
+            let url = env::var("WALLET_HTTP_URL").expect("WALLET_HTTP_URL must be set for the http wallet");
+            let token = env::var("WALLET_HTTP_TOKEN").ok().filter(|t| !t.is_empty());
+            let timeout = env::var("WALLET_HTTP_TIMEOUT_SECS")
+                .map(|v| v.parse().expect("Invalid WALLET_HTTP_TIMEOUT_SECS"))
+                .unwrap_or(DEFAULT_HTTP_TIMEOUT_SECS);
+            Arc::new(HttpWallet::new(&url, token, Duration::from_secs(timeout)))
+        }
+        other => panic!("Unknown WALLET_BACKEND {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use warp::http::StatusCode;
+    use warp::{Filter, Reply};
+
+    use super::*;
+
+    fn sqlite_path() -> String {
+        env::temp_dir()
+            .join(format!("wallet-test-{}.db", random_token()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn sqlite_reservations_move_balances_and_survive_a_reopen() {
+        let path = sqlite_path();
+        let wallet = SqliteWallet::open(&path, 100).unwrap();
+        assert_eq!(wallet.balance(1).await.unwrap(), 100);
+        let captured = wallet.reserve(1, 30, "match:1").await.unwrap();
+        let released = wallet.reserve(1, 20, "match:2").await.unwrap();
+        assert_eq!(wallet.balance(1).await.unwrap(), 50);
+        wallet.capture(&captured).await.unwrap();
+        wallet.credit(2, 30, "match:1").await.unwrap();
+        assert_eq!(wallet.balance(2).await.unwrap(), 130);
+        drop(wallet);
+
+        // the open reservation is still held after a restart
+        let wallet = SqliteWallet::open(&path, 100).unwrap();
+        assert_eq!(wallet.balance(1).await.unwrap(), 50);
+        wallet.release(&released).await.unwrap();
+        assert_eq!(wallet.balance(1).await.unwrap(), 70);
+        assert!(matches!(wallet.release(&released).await, Err(WalletError::UnknownReservation)));
+        assert!(matches!(wallet.capture(&captured).await, Err(WalletError::UnknownReservation)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sqlite_reservations_need_the_funds() {
+        let path = sqlite_path();
+        let wallet = SqliteWallet::open(&path, 10).unwrap();
+        assert!(matches!(wallet.reserve(1, 11, "match:1").await, Err(WalletError::InsufficientFunds)));
+        assert_eq!(wallet.balance(1).await.unwrap(), 10);
+        wallet.reserve(1, 10, "match:1").await.unwrap();
+        assert!(matches!(wallet.reserve(1, 1, "match:2").await, Err(WalletError::InsufficientFunds)));
+        assert_eq!(wallet.balance(1).await.unwrap(), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_memory_reservations_need_the_funds() {
+        let wallet = InMemoryWallet::new(10);
+        assert!(matches!(wallet.reserve(1, 11, "match:1").await, Err(WalletError::InsufficientFunds)));
+        let reservation = wallet.reserve(1, 10, "match:1").await.unwrap();
+        wallet.release(&reservation).await.unwrap();
+        assert_eq!(wallet.balance(1).await.unwrap(), 10);
+    }
+
+    /// A wallet service that only knows reservation `r1`, refuses amounts over 10 and takes 2 seconds to credit
+    async fn wallet_service() -> String {
+        let balance = warp::get()
+            .and(warp::path!("balance" / u64))
+            .map(|_| warp::reply::json(&serde_json::json!({ "balance": 42 })).into_response());
+        let reserve = warp::post()
+            .and(warp::path("reserve"))
+            .and(warp::body::json())
+            .map(|body: serde_json::Value| {
+                let status = if body["amount"].as_u64() > Some(10) {
+                    StatusCode::PAYMENT_REQUIRED
+                } else {
+                    StatusCode::OK
+                };
+                warp::reply::with_status(warp::reply::json(&serde_json::json!({ "reservation_id": "r1" })), status).into_response()
+            });
+        let capture = warp::post()
+            .and(warp::path("capture"))
+            .and(warp::body::json())
+            .map(|body: serde_json::Value| {
+                let status = if body["reservation_id"] == "r1" {
+                    StatusCode::OK
+                } else {
+                    StatusCode::NOT_FOUND
+                };
+                warp::reply::with_status(warp::reply(), status).into_response()
+            });
+        let credit = warp::post().and(warp::path("credit")).then(|| async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            warp::reply().into_response()
+        });
+        let routes =
+            warp::header::exact("authorization", "Bearer wallet-token").and(balance.or(reserve).unify().or(capture).unify().or(credit).unify());
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn http_wallet_maps_service_errors() {
+        let url = wallet_service().await;
+        let wallet = HttpWallet::new(&url, Some(String::from("wallet-token")), Duration::from_secs(1));
+        assert_eq!(wallet.balance(1).await.unwrap(), 42);
+        assert_eq!(wallet.reserve(1, 10, "match:1").await.unwrap(), "r1");
+        assert!(matches!(wallet.reserve(1, 11, "match:1").await, Err(WalletError::InsufficientFunds)));
+        wallet.capture("r1").await.unwrap();
+        assert!(matches!(wallet.capture("r2").await, Err(WalletError::UnknownReservation)));
+        assert!(matches!(wallet.credit(1, 5, "match:1").await, Err(WalletError::Backend(_))));
+
+        let unauthorized = HttpWallet::new(&url, None, Duration::from_secs(1));
+        assert!(matches!(unauthorized.balance(1).await, Err(WalletError::Backend(_))));
+    }
+}
@@ -1,5 +1,5 @@
 import axios from "axios";
-import { Match } from "./types";
+import { DrainEvent, GameListing, Match, MatchResult, MatchUpdate, Profile, Transaction } from "./types";
 import { EventSource } from "eventsource"
 import assert, { deepEqual } from "assert";
 import dotenv from "dotenv";
@@ -8,26 +8,58 @@ dotenv.config();
 axios.defaults.validateStatus = (status) => {
     return true;
 }
-const letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZabdefghijklmnopqrstuvwxyz"
-function generateRandomToken(): string {
-    return Array.from({ length: 20 }).map((_) => letters[Math.floor(Math.random() * letters.length)]).join("");
+const URL = `http://${process.env.HOST}:${process.env.PORT}`
+const PASSWORD = "correct horse battery staple"
+function randomUsername(): string {
+    return "player_" + Math.random().toString(36).slice(2, 10);
 }
-const PRIZES: number[] = [2, 5, 10, 25, 50]
-const GAME_TYPES: string[] = ["soccer", "knockout"]
+// game types and prizes come from the server, see fetchGames
+let GAMES: GameListing[] = []
 function pickRandom<T>(arr: T[]): T {
     return arr[Math.floor(Math.random() * arr.length)];
 }
 class Client {
     token: string
+    username: string
     url: string;
-    constructor() {
-        this.token = generateRandomToken();
-        this.url = `http://${process.env.HOST}:${process.env.PORT}`
+    constructor(credentials: { token: string, username: string }) {
+        this.token = credentials.token;
+        this.username = credentials.username;
+        this.url = URL
         console.log(this.url);
     }
+    // registers a fresh account (AUTH_PROVIDER=session) and logs it in
+    static async register(): Promise<Client> {
+        const username = randomUsername();
+        const registered = await axios.post(`${URL}/register`, { username, password: PASSWORD });
+        assert(registered.status === 201, "Invalid register status");
+        const duplicate = await axios.post(`${URL}/register`, { username, password: PASSWORD });
+        assert(duplicate.status === 409, "Duplicate username should conflict");
+        const badLogin = await axios.post(`${URL}/login`, { username, password: "wrong password" });
+        assert(badLogin.status === 401, "Wrong password should be unauthorized");
+        const login = await axios.post(`${URL}/login`, { username, password: PASSWORD });
+        assert(login.status === 200, "Invalid login status");
+        const client = new Client({ token: login.data.token, username });
+        const me = await client.me();
+        assert(me.username === username && me.id === registered.data.id, "Session does not resolve to the registered user");
+        return client;
+    }
+    async me(): Promise<Profile> {
+        const response = await axios.get(`${this.url}/me`, { headers: { Authorization: `Bearer ${this.token}` } });
+        assert(response.status === 200, "Invalid response status");
+        return response.data as Profile;
+    }
+    async logout(): Promise<void> {
+        const response = await axios.post(`${this.url}/logout`, null, { headers: { Authorization: `Bearer ${this.token}` } });
+        assert(response.status === 200, "Invalid response status");
+        const after = await axios.get(`${this.url}/me`, { headers: { Authorization: `Bearer ${this.token}` } });
+        assert(after.status === 401, "Session should be revoked after logout");
+    }
     async createGame(): Promise<Match> {
-        const prize = pickRandom(PRIZES)
-        const game_type = pickRandom(GAME_TYPES)
+        const balanceBefore = (await this.me()).balance;
+        const game = pickRandom(GAMES.filter((g) => g.accepting))
+        const prize = pickRandom(game.prizes)
+        const game_type = game.game_type
         const response = await axios.post(`${this.url}/create`,
             { prize, game_type },
             {
@@ -40,27 +72,55 @@ class Client {
         )
         assert(response.status === 200, "Invalid response status");
         const { id, players, prize: gamePrize, game_type: gameGame_type, expiry_time } = response.data as Match;
-        assert(players.includes(this.token), "Players does not include token");
+        assert(players.includes(this.username), "Players does not include username");
         assert(Number(gamePrize) === prize, "Incorrect prize value");
         assert(gameGame_type === game_type, "Invalid game type");
+        assert((await this.me()).balance === balanceBefore - prize, "Stake was not escrowed");
+        const transactions = await this.getTransactions();
+        assert(transactions.some((t) => t.match_id === id && t.kind === "ESCROW" && t.amount === prize), "Escrow transaction missing");
         return response.data as Match;
     }
     async openEventSource(id: number): Promise<EventSource> {
         return new Promise<EventSource>((resolve, reject) => {
             const es = new EventSource(`${this.url}/updates?id=${id}`)
-            es.onmessage = (event: any) => {
-                const eventData = JSON.parse(event.data);
+            es.onmessage = async (event: any) => {
+                const eventData = JSON.parse(event.data) as MatchUpdate;
                 console.log(eventData);
-                const state = eventData[0]
-                const port = eventData[3];
+                const { state, port, expiry_time, kicked } = eventData;
+                if (state === "READYING") {
+                    console.log(`Ready check ends in ${expiry_time - Math.floor(Date.now() / 1000)}s`);
+                }
+                if (kicked.length > 0) {
+                    console.log(`Kicked by the ready check: ${kicked.join(", ")}`);
+                }
                 if (state === "PLAYING") {
-                    const wsUrl = `http://localhost:${port}`;
+                    const { game_token } = await this.getGame(id);
+                    assert(game_token, "Missing game token");
+                    const verified = await axios.post(`${this.url}/game/verify`, { match_id: id, token: game_token });
+                    assert(verified.status === 200 && verified.data.username === this.username, "Game token does not verify");
+                    const forged = await axios.post(`${this.url}/end_match`,
+                        { match_id: id, winner: this.username, duration_secs: 1, reason: "completed" },
+                        { headers: { "X-Signature": "00" } }
+                    );
+                    assert(forged.status === 401, "Unsigned result should be rejected");
+                    const wsUrl = `http://localhost:${port}?token=${game_token}`;
                     console.log(`Connecting to game on ${wsUrl}`);
                     const websocket = new WebSocket(wsUrl);
                     websocket.onopen = (event) => {
                         console.log("Websocket opened with url: " + wsUrl);
                         console.log("WS Data: " + String(event));
                     };
+                    websocket.onmessage = (message) => {
+                        // game-simulation greets every connection with how it was launched
+                        const hello = JSON.parse(String(message.data));
+                        if (hello.launch === undefined) {
+                            return;
+                        }
+                        const mode = process.env.LAUNCH_MODE || "argv";
+                        assert(hello.launch === mode, `Game was launched with ${hello.launch}, expected ${mode}`);
+                        assert(hello.match_id === id, "Game was launched with the wrong match id");
+                        console.log(`Game launched with ${hello.launch} for match ${hello.match_id}`);
+                    };
                     websocket.onerror = (event) => {
                         console.log(`Websocket errored with error: ${event.error}, message: ${event.message}, target: ${JSON.stringify(event.target)}, type: ${event.type}`);
                     }
@@ -91,6 +151,11 @@ class Client {
         assert(game_id === id, "Invalid game id");
         return response.data as Match
     }
+    async getTransactions(): Promise<Transaction[]> {
+        const response = await axios.get(`${this.url}/transactions`, { headers: { Authorization: `Bearer ${this.token}` } });
+        assert(response.status === 200, "Invalid response status");
+        return response.data as Transaction[];
+    }
     async getGame(id: number): Promise<Match> {
         const response = await axios.get(`${this.url}/match?id=${id}`,
             {
@@ -103,6 +168,17 @@ class Client {
         assert(response.status === 200, "Invalid response status");
         return response.data
     }
+    // polls the match until it is settled, games exit within a minute of starting
+    async waitForResult(id: number): Promise<MatchResult> {
+        for (let i = 0; i < 120; i++) {
+            const { result } = await this.getGame(id);
+            if (result) {
+                return result;
+            }
+            await new Promise((resolve) => setTimeout(resolve, 1000));
+        }
+        throw new Error(`Match ${id} was not settled`);
+    }
     async getGames(): Promise<Match[]> {
         const response = await axios.get(`${this.url}/matches`,
             {
@@ -128,14 +204,93 @@ class Client {
         return true;
     }
 }
+async function fetchGames(): Promise<GameListing[]> {
+    const response = await axios.get(`${URL}/games`);
+    assert(response.status === 200, "Invalid response status");
+    const games = response.data as GameListing[];
+    assert(games.some((g) => g.accepting), "No game type is accepting matches");
+    return games;
+}
+async function testUnauthorized() {
+    const missing = await axios.get(`${URL}/matches`);
+    assert(missing.status === 401, "Missing token should be unauthorized");
+    const unknown = await axios.get(`${URL}/matches`, { headers: { Authorization: "Bearer not-a-real-token" } });
+    assert(unknown.status === 401, "Unknown token should be unauthorized");
+    const notBearer = await axios.get(`${URL}/matches`, { headers: { Authorization: "Basic abc" } });
+    assert(notBearer.status === 401, "Non bearer auth should be unauthorized");
+}
+// with SIMULATION_RESULT set in .env the game only reports through that channel, the match must be settled from it
+async function testResultSource(client: Client, id: number) {
+    const channel = process.env.SIMULATION_RESULT;
+    if (!channel) {
+        return;
+    }
+    const sources: Record<string, MatchResult["source"]> = { callback: "callback", file: "result_file", stdout: "stdout", exit_code: "exit_code" };
+    const result = await client.waitForResult(id);
+    assert(result.source === sources[channel], `Match was settled from ${result.source}, expected ${sources[channel]}`);
+    if (channel === "file" || channel === "stdout") {
+        assert(result.report && result.report.match_id === id, "Settled without the signed report");
+    }
+    console.log(`Match ${id} settled from ${result.source}`);
+}
+// drains the server, which refuses matches until it is restarted, so it only runs with TEST_DRAIN and ADMIN_TOKEN set
+async function testDrain() {
+    const adminToken = process.env.ADMIN_TOKEN;
+    if (!process.env.TEST_DRAIN || !adminToken) {
+        return;
+    }
+    const client = await Client.register();
+    const balanceBefore = (await client.me()).balance;
+    const match = await client.createGame();
+    const es = new EventSource(`${URL}/updates?id=${match.id}`);
+    const draining = new Promise<DrainEvent>((resolve, reject) => {
+        es.addEventListener("draining", (event: any) => resolve(JSON.parse(event.data) as DrainEvent));
+        es.onerror = () => reject(new Error("Update stream closed before the draining event"));
+    });
+    await new Promise((resolve) => es.addEventListener("open", resolve));
+
+    const unauthorized = await axios.post(`${URL}/admin/drain`);
+    assert(unauthorized.status === 401, "Drain without the admin token should be unauthorized");
+    const drain = await axios.post(`${URL}/admin/drain`, null, { headers: { Authorization: `Bearer ${adminToken}` } });
+    assert(drain.status === 200 && drain.data.drain.state === "draining", "Drain did not start");
+    const event = await draining;
+    es.close();
+    assert(event.state === "draining" && event.deadline === drain.data.drain.deadline, "Draining event does not match the drain");
+
+    const health = await axios.get(`${URL}/health`);
+    assert(health.status === 503, "Health check should fail while draining");
+    const game = pickRandom(GAMES.filter((g) => g.accepting));
+    const create = await axios.post(`${URL}/create`,
+        { prize: game.prizes[0], game_type: game.game_type },
+        { headers: { Authorization: `Bearer ${client.token}` } }
+    );
+    assert(create.status === 503, "Create should be refused while draining");
+
+    // the drain cancels open matches right after it starts
+    let refunded = false;
+    for (let i = 0; i < 10 && !refunded; i++) {
+        await new Promise((resolve) => setTimeout(resolve, 500));
+        refunded = (await client.getTransactions()).some((t) => t.match_id === match.id && t.kind === "REFUND");
+    }
+    assert(refunded, "Refund transaction missing");
+    const { state } = await client.getGame(match.id);
+    assert(state === "CANCELLED", `Open match should be cancelled by the drain, got ${state}`);
+    assert((await client.me()).balance === balanceBefore, "Stake of the cancelled match was not refunded");
+    console.log(`Drain cancelled match ${match.id}, running games have until ${event.deadline}`);
+}
 async function main() {
-    const client1 = new Client();
-    const client2 = new Client();
+    await testUnauthorized();
+    GAMES = await fetchGames();
+    const client1 = await Client.register();
+    const client2 = await Client.register();
+    await (await Client.register()).logout();
 
     const match = await client1.createGame();
     const es = await client1.openEventSource(match.id);
     await client2.joinGame(match.id);
     const es2 = await client2.openEventSource(match.id);
     await Promise.all([client1.readyUp(match.id), client2.readyUp(match.id)]);
+    await testResultSource(client1, match.id);
+    await testDrain();
 }
 main().then(() => console.log("DONE"));
\ No newline at end of file
@@ -1,7 +1,73 @@
+export type Profile = {
+    id: number,
+    username: string,
+    display_name: string,
+    balance: number
+}
+export type Transaction = {
+    id: number,
+    match_id: number,
+    user_id: number,
+    kind: "ESCROW" | "RELEASE" | "CAPTURE" | "PAYOUT" | "REFUND" | "FORFEIT",
+    amount: number,
+    created_at: number
+}
+export type GameListing = {
+    game_type: string,
+    min_players: number,
+    max_players: number,
+    prizes: number[],
+    teams: number,
+    ready_check_secs: number,
+    accepting: boolean,
+    open_matches: number,
+    playing_matches: number,
+    running_games: number,
+    max_running_games: number | null,
+    queued_matches: number,
+    can_launch: boolean
+}
+// data of the `draining` event sent to /updates subscribers when the server shuts down
+export type DrainEvent = { state: "draining", deadline: number }
+export type MatchState = "OPEN" | "READYING" | "QUEUED" | "STARTING" | "PLAYING" | "FINISHED" | "CANCELLED" | "EXPIRED" | "ABORTED"
+// sent to /updates subscribers whenever the match changes. expiry_time is the ready check deadline while READYING,
+// kicked the players kicked by the last ready check and queue_position is null unless QUEUED
+export type MatchUpdate = {
+    state: MatchState,
+    ready: boolean[],
+    players: string[],
+    port: number,
+    expiry_time: number,
+    kicked: string[],
+    ports: Record<string, number>,
+    queue_position: number | null
+}
 export type Match = {
     id: number,
     prize: number,
     game_type: string,
     expiry_time: number,
-    players: string[]
+    players: string[],
+    port: number,
+    ports: Record<string, number>,
+    teams: number[],
+    state: MatchState,
+    kicked: string[],
+    queue_position: number | null,
+    game_token?: string,
+    result: MatchResult | null
+}
+export type MatchResult = {
+    outcome: "winner" | "team_winner" | "draw" | "refunded",
+    player?: number,
+    team?: number,
+    source: "callback" | "result_file" | "stdout" | "exit_code" | "launch_failure" | "startup_failure" | "cpu_limit" | "memory_limit" | "expired" | "drained" | "orphaned",
+    report: {
+        match_id: number,
+        winner: string | null,
+        winning_team?: number | null,
+        scores: Record<string, number>,
+        duration_secs: number,
+        reason: "completed" | "forfeit" | "disconnect" | "timeout" | "draw" | "error"
+    } | null
 }
\ No newline at end of file
@@ -0,0 +1,71 @@
+// Minimal stand-in for the wallet service used by WALLET_BACKEND=http (see HttpWallet in src/wallet.rs)
+import http from "http";
+import { randomBytes } from "crypto";
+import dotenv from "dotenv";
+dotenv.config();
+
+const STARTING_BALANCE = Number(process.env.STARTING_BALANCE ?? 1000);
+const PORT = Number(process.env.WALLET_STUB_PORT ?? 8090);
+
+const balances = new Map<number, number>();
+const reservations = new Map<string, { user_id: number, amount: number }>();
+
+function balanceOf(userId: number): number {
+    if (!balances.has(userId)) {
+        balances.set(userId, STARTING_BALANCE);
+    }
+    return balances.get(userId)!;
+}
+function send(res: http.ServerResponse, status: number, body?: object) {
+    res.writeHead(status, { "Content-Type": "application/json" });
+    res.end(body ? JSON.stringify(body) : "");
+}
+
+const server = http.createServer((req, res) => {
+    let raw = "";
+    req.on("data", (chunk) => raw += chunk);
+    req.on("end", () => {
+        const body = raw ? JSON.parse(raw) : {};
+        const balanceMatch = req.url?.match(/^\/balance\/(\d+)$/);
+        if (req.method === "GET" && balanceMatch) {
+            return send(res, 200, { balance: balanceOf(Number(balanceMatch[1])) });
+        }
+        if (req.method !== "POST") {
+            return send(res, 404);
+        }
+        switch (req.url) {
+            case "/reserve": {
+                const balance = balanceOf(body.user_id);
+                if (balance < body.amount) {
+                    return send(res, 402);
+                }
+                balances.set(body.user_id, balance - body.amount);
+                const reservation_id = randomBytes(16).toString("hex");
+                reservations.set(reservation_id, { user_id: body.user_id, amount: body.amount });
+                return send(res, 200, { reservation_id });
+            }
+            case "/capture": {
+                if (!reservations.delete(body.reservation_id)) {
+                    return send(res, 404);
+                }
+                return send(res, 200);
+            }
+            case "/release": {
+                const reservation = reservations.get(body.reservation_id);
+                if (!reservation) {
+                    return send(res, 404);
+                }
+                reservations.delete(body.reservation_id);
+                balances.set(reservation.user_id, balanceOf(reservation.user_id) + reservation.amount);
+                return send(res, 200);
+            }
+            case "/credit": {
+                balances.set(body.user_id, balanceOf(body.user_id) + body.amount);
+                return send(res, 200);
+            }
+            default:
+                return send(res, 404);
+        }
+    });
+});
+server.listen(PORT, () => console.log(`Wallet stub listening on ${PORT}`));